@@ -1,13 +1,84 @@
+use core::{mem, ptr, slice};
 use acpi::{self, AcpiHandler, Acpi, AcpiError};
-use crate::memory::physical_mapping::{self, PhysicalMapping};
+use spin::Once;
+use crate::memory::{self, physical_mapping::{self, PhysicalMapping}};
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// The length of the RSDP in revision 0, which is what its first checksum covers
+const RSDP_V1_LENGTH: usize = 20;
+/// Where the real mode segment of the extended BIOS data area is stored
+const EBDA_SEGMENT_ADDR: usize = 0x40E;
+/// The main BIOS area, which is searched for the RSDP after the first KiB of the EBDA
+const BIOS_AREA_START: usize = 0xE0000;
+const BIOS_AREA_END: usize = 0x100000;
+/// No real table comes close to this. It bounds how much is mapped for a corrupt header.
+const MAX_TABLE_LENGTH: usize = 1024 * 1024;
+
+/// The RSDP found in `acpi_init`
+static RSDP: Once<Rsdp> = Once::new();
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // Only present from revision 2 onwards
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// The header common to all system description tables
+#[derive(Debug, Copy, Clone)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// A system description table which is mapped in full
+pub struct AcpiTable {
+    mapping: PhysicalMapping<u8>,
+    length: usize,
+}
+
+impl AcpiTable {
+    /// The bytes of the table, including its header
+    pub fn data(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.mapping.virtual_address() as *const u8, self.length) }
+    }
+
+    /// The bytes of the table after its header
+    pub fn body(&self) -> &[u8] {
+        &self.data()[mem::size_of::<SdtHeader>()..]
+    }
+}
 
 pub fn acpi_init() -> Result<Acpi, AcpiError> {
     info!("acpi: initializing");
     let mut handler = FlowerAcpiHandler;
+
     // We're BIOS. We'd have crashed by now if we weren't.
-    let search_result = unsafe { acpi::search_for_rsdp_bios(&mut handler) };
+    let result = match find_rsdp() {
+        Some((address, rsdp)) => {
+            RSDP.call_once(|| rsdp);
+            acpi::parse_rsdp(&mut handler, address)
+        }
+        None => Err(AcpiError::NoValidRsdp),
+    };
 
-    match search_result {
+    match result {
         Ok(acpi) => {
             info!("acpi: init successful");
             Ok(acpi)
@@ -38,6 +109,11 @@ impl AcpiHandler for FlowerAcpiHandler {
     fn unmap_physical_region<T>(&mut self, region: acpi::PhysicalMapping<T>) {
         let obj_addr = region.virtual_start.as_ptr() as *mut T as usize;
 
+        // Regions in the direct map were never allocated on the heap
+        if memory::in_direct_map(obj_addr) {
+            return;
+        }
+
         // Clear lower page offset bits
         let page_begin = obj_addr & !0xFFF;
 
@@ -49,3 +125,117 @@ impl AcpiHandler for FlowerAcpiHandler {
         }
     }
 }
+
+/// Finds and maps the table with the given signature, e.g `b"SRAT"`. The `acpi` crate only keeps
+/// what it parses out of the tables it knows about, so other tables are found by walking the RSDT
+/// or XSDT of the RSDP which was found in `acpi_init`. Tables with a bad checksum are skipped.
+pub fn find_table(signature: &[u8; 4]) -> Option<AcpiTable> {
+    let rsdp = *RSDP.r#try()?;
+
+    let use_xsdt = rsdp.revision >= 2 && rsdp.xsdt_address != 0;
+
+    let (root_address, root_signature, entry_size) = if use_xsdt {
+        (rsdp.xsdt_address as usize, b"XSDT", mem::size_of::<u64>())
+    } else {
+        (rsdp.rsdt_address as usize, b"RSDT", mem::size_of::<u32>())
+    };
+
+    let root = map_table(root_address, root_signature)?;
+    let entries = root.body();
+
+    for entry in entries.chunks(entry_size).filter(|entry| entry.len() == entry_size) {
+        let address = unsafe {
+            if entry_size == mem::size_of::<u64>() {
+                ptr::read_unaligned(entry.as_ptr() as *const u64) as usize
+            } else {
+                ptr::read_unaligned(entry.as_ptr() as *const u32) as usize
+            }
+        };
+
+        if let Some(table) = map_table(address, signature) {
+            return Some(table);
+        }
+    }
+
+    None
+}
+
+/// Searches the first KiB of the EBDA and then the main BIOS area for the RSDP, returning the first
+/// one with a valid signature and checksums along with its physical address
+fn find_rsdp() -> Option<(usize, Rsdp)> {
+    let ebda_start = unsafe {
+        (*physical_mapping::map_physical_type::<u16>(EBDA_SEGMENT_ADDR, false) as usize) << 4
+    };
+
+    let mut areas = [(ebda_start, 1024), (BIOS_AREA_START, BIOS_AREA_END - BIOS_AREA_START)];
+
+    // The EBDA is not always present
+    if ebda_start == 0 {
+        areas[0].1 = 0;
+    }
+
+    for &(start, length) in areas.iter().filter(|&&(_, length)| length > 0) {
+        let mapping = unsafe { physical_mapping::map_physical_region::<u8>(start, length, false) };
+        let area = unsafe {
+            slice::from_raw_parts(mapping.virtual_address() as *const u8, length)
+        };
+
+        // The RSDP is always 16 byte aligned
+        for offset in (0..=length - mem::size_of::<Rsdp>()).step_by(16) {
+            let candidate = &area[offset..offset + mem::size_of::<Rsdp>()];
+
+            if let Some(rsdp) = validate_rsdp(candidate) {
+                return Some((start + offset, rsdp));
+            }
+        }
+    }
+
+    None
+}
+
+/// Reads an RSDP out of the bytes if its signature and checksums are valid
+fn validate_rsdp(bytes: &[u8]) -> Option<Rsdp> {
+    if &bytes[..RSDP_SIGNATURE.len()] != RSDP_SIGNATURE || checksum(&bytes[..RSDP_V1_LENGTH]) != 0 {
+        return None;
+    }
+
+    let rsdp = unsafe { ptr::read_unaligned(bytes.as_ptr() as *const Rsdp) };
+
+    // Revision 2 adds the extended checksum, which covers the whole structure
+    if rsdp.revision >= 2 && checksum(bytes) != 0 {
+        return None;
+    }
+
+    Some(rsdp)
+}
+
+/// Maps a whole table, given the physical address of its header. Returns `None` if it does not
+/// have the given signature, or if its length or checksum is bad.
+fn map_table(address: usize, signature: &[u8; 4]) -> Option<AcpiTable> {
+    let header = unsafe { *physical_mapping::map_physical_type::<SdtHeader>(address, false) };
+    let length = header.length as usize;
+
+    if header.signature != *signature {
+        return None;
+    }
+
+    if length < mem::size_of::<SdtHeader>() || length > MAX_TABLE_LENGTH {
+        error!("acpi: table at 0x{:x} has a bad length of {}", address, length);
+        return None;
+    }
+
+    let mapping = unsafe { physical_mapping::map_physical_region(address, length, false) };
+    let table = AcpiTable { mapping, length };
+
+    if checksum(table.data()) != 0 {
+        error!("acpi: table at 0x{:x} has a bad checksum", address);
+        return None;
+    }
+
+    Some(table)
+}
+
+/// The sum of the bytes, which is 0 for a valid table
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
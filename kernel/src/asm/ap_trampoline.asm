@@ -0,0 +1,114 @@
+; Application processor trampoline
+; APs start in real mode at the address given in the startup IPI. This code is copied to
+; AP_TRAMPOLINE_ADDR at runtime, so all addresses within it must be calculated relative to there.
+; The BSP fills in `ap_trampoline_data` before sending the startup IPI.
+
+%define AP_TRAMPOLINE_ADDR 0x8000
+%define REL(label) ((label) - ap_trampoline_start + AP_TRAMPOLINE_ADDR)
+
+global ap_trampoline_start
+global ap_trampoline_end
+global ap_trampoline_data
+
+section .text.ap_trampoline
+bits 16
+
+ap_trampoline_start:
+    cli
+    cld
+
+    xor ax, ax
+    mov ds, ax
+
+    lgdt [REL(ap_gdt32.pointer)] ; Load 32 bit gdt
+
+    ; Enable protected mode
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+
+    jmp dword ap_gdt32.code:REL(ap_protected_mode)
+
+bits 32
+ap_protected_mode:
+    mov ax, ap_gdt32.data
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    ; Enable Physical Address Extension
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+
+    ; Use the kernel's page tables. The BSP makes sure these are below 4GiB.
+    mov eax, [REL(ap_trampoline_data.cr3)]
+    mov cr3, eax
+
+    ; Set long mode and nxe bits
+    mov ecx, 0xc0000080
+    rdmsr
+    or eax, 1 << 8 ; long mode
+    or eax, 1 << 11 ; nxe bit
+    wrmsr
+
+    ; Enable paging and write protection
+    mov eax, cr0
+    or eax, (1 << 31) | (1 << 16)
+    mov cr0, eax
+
+    lgdt [REL(ap_gdt64.pointer)] ; Load 64 bit gdt
+
+    jmp ap_gdt64.code:REL(ap_long_mode)
+
+bits 64
+ap_long_mode:
+    mov ax, ap_gdt64.data
+    mov ss, ax
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+
+    mov rsp, [REL(ap_trampoline_data.stack_top)]
+    mov rdi, [REL(ap_trampoline_data.argument)] ; Pass the argument to the entry point
+    mov rax, [REL(ap_trampoline_data.entry)]
+    xor rbp, rbp ; Terminate the chain of saved frame pointers which backtraces follow
+    call rax
+
+    ; The entry point should never return
+.hang:
+    cli
+    hlt
+    jmp .hang
+
+align 8
+ap_gdt32:
+    dq 0
+.code: equ $ - ap_gdt32 ; offset from gdt
+    dq 0x00cf9a000000ffff ; 4GiB flat 32 bit code
+.data: equ $ - ap_gdt32 ; offset from gdt
+    dq 0x00cf92000000ffff ; 4GiB flat data
+.pointer:
+    dw $ - ap_gdt32 - 1 ; length
+    dd REL(ap_gdt32) ; address of table
+
+align 8
+ap_gdt64:
+    dq 0
+.code: equ $ - ap_gdt64 ; offset from gdt
+    dq (1<<44) | (1<<47) | (1<<41) | (1<<43) | (1<<53)
+.data: equ $ - ap_gdt64 ; offset from gdt
+    dq (1<<44) | (1<<47) | (1<<41)
+.pointer:
+    dw $ - ap_gdt64 - 1 ; length
+    dd REL(ap_gdt64) ; address of table
+
+align 8
+ap_trampoline_data:
+.cr3: dq 0
+.stack_top: dq 0
+.entry: dq 0
+.argument: dq 0
+
+ap_trampoline_end:
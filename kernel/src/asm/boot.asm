@@ -227,7 +227,8 @@ check_long_mode:
     call error_print
     hlt
     
-section .bss
+; Only used until the kernel is remapped, after which this section is reclaimed
+section .boot_bss nobits alloc noexec write align=4096
 align 4096
 p4_table:
     resb 4096
@@ -307,6 +308,9 @@ long_mode_start:
     ; Pass guard page address to kmain through rsi
     mov rsi, guard_page_begin
 
+    ; Terminate the chain of saved frame pointers which backtraces follow
+    xor rbp, rbp
+
     call kmain + KERNEL_MAPPING_BEGIN
 
 ; Set up higher half by mapping the kernel to %KERNEL_MAPPING_BEGIN
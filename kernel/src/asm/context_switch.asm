@@ -0,0 +1,31 @@
+; Kernel thread context switching
+; Only the callee-saved registers need to be preserved, since `switch_context` is called like any
+; other function. Everything else has already been saved by the caller (or by the interrupt handler
+; if the switch was caused by preemption).
+
+global switch_context
+
+section .text
+bits 64
+
+; Saves the current context on the stack and switches to the stack of another context
+; Args (System V): pointer to save the current stack pointer to (rdi), new stack pointer (rsi)
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+
+    mov [rdi], rsp ; save old stack pointer
+    mov rsp, rsi ; load new stack pointer
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+
+    ret ; return into the new context
@@ -0,0 +1,96 @@
+; Common CPU exception entry
+; Every exception vector has a stub which pushes a dummy error code if the CPU does not push one,
+; followed by the vector number. The common entry then saves all general purpose registers on top of
+; them, which together make up an `interrupts::context::ExceptionContext`, and passes a pointer to
+; it to `exception_dispatch`. The registers are restored from the context when it returns, so a
+; handler can resume the interrupted code with modified state.
+;
+; Exceptions from user mode swap the GS base to the per-CPU block on entry and back on return (see
+; `smp::percpu::PerCpu`). Whether the exception came from user mode is told by the RPL of the CS
+; saved in the interrupt frame.
+
+extern exception_dispatch
+global exception_stubs
+global exception_entry_end
+
+; The offset of the saved CS from the stack pointer at `exception_common`, above the vector, error
+; code and RIP
+%define SAVED_CS 24
+
+; Whether the CPU pushes an error code for the vector
+%define HAS_ERROR_CODE(v) ((v) == 8 || ((v) >= 10 && (v) <= 14) || (v) == 17 || (v) == 21 || (v) == 29 || (v) == 30)
+
+section .text
+bits 64
+
+%assign vector 0
+%rep 32
+exception_stub_%+vector:
+%if !HAS_ERROR_CODE(vector)
+    push qword 0 ; dummy error code
+%endif
+    push qword vector
+    jmp exception_common
+%assign vector vector + 1
+%endrep
+
+exception_common:
+    test qword [rsp + SAVED_CS], 3
+    jz .from_kernel
+    swapgs
+.from_kernel:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    ; The CPU aligns the stack to 16 bytes before pushing the interrupt frame, and 22 qwords have
+    ; been pushed since, so it is aligned for the call
+    mov rdi, rsp ; exception_dispatch(&mut context)
+    cld
+    call exception_dispatch
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+
+    test qword [rsp + SAVED_CS], 3
+    jz .to_kernel
+    swapgs
+.to_kernel:
+    add rsp, 16 ; vector and error code
+    iretq
+exception_entry_end: ; the stubs and the common entry lie between `exception_stub_0` and here
+
+section .rodata
+
+; The addresses of the stubs, indexed by vector
+exception_stubs:
+%assign vector 0
+%rep 32
+    dq exception_stub_%+vector
+%assign vector vector + 1
+%endrep
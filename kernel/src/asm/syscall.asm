@@ -0,0 +1,49 @@
+; System call entry
+; `syscall` does not switch stacks, so the kernel stack of the current thread is loaded from the
+; per-CPU block (see `smp::percpu::PerCpu`), which `swapgs` points GS at on entry and away from
+; again before returning to user mode. Interrupts are masked on entry by SFMASK until the user state
+; has been saved on the kernel stack.
+;
+; Calling convention: the syscall number is passed in rax and up to five arguments in rdi, rsi, rdx,
+; r10 and r8. The result is returned in rax. rcx and r11 are clobbered by `syscall` itself, and the
+; other caller-saved registers of the System V ABI are clobbered by the handler.
+
+extern syscall_dispatch
+global syscall_entry
+
+%define PERCPU_SYSCALL_STACK 8
+%define PERCPU_SYSCALL_USER_STACK 16
+
+section .text
+bits 64
+
+syscall_entry:
+    swapgs ; GS base to the per-CPU block
+    mov [gs:PERCPU_SYSCALL_USER_STACK], rsp ; save user stack pointer
+    mov rsp, [gs:PERCPU_SYSCALL_STACK] ; load kernel stack pointer
+
+    ; Save the user state on the kernel stack so that the thread can be preempted
+    push qword [gs:PERCPU_SYSCALL_USER_STACK]
+    push rcx ; user instruction pointer
+    push r11 ; user rflags
+    sub rsp, 8 ; align the stack for the call
+
+    ; syscall_dispatch(number, arg0, arg1, arg2, arg3, arg4)
+    mov r9, r8
+    mov r8, r10
+    mov rcx, rdx
+    mov rdx, rsi
+    mov rsi, rdi
+    mov rdi, rax
+
+    sti
+    call syscall_dispatch
+    cli
+
+    add rsp, 8
+    pop r11
+    pop rcx
+    pop rsp ; back onto the user stack
+
+    swapgs ; GS base back to the user's
+    o64 sysret
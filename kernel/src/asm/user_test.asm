@@ -0,0 +1,26 @@
+; A tiny position independent ring 3 program, copied into a user page by `usermode::test_program`
+; with the `user_test` feature to check that user mode and system calls work.
+
+global user_test_start
+global user_test_end
+
+%define SYSCALL_WRITE 0
+%define SYSCALL_EXIT 1
+
+section .rodata
+bits 64
+
+user_test_start:
+    mov rax, SYSCALL_WRITE
+    lea rdi, [rel .message]
+    mov rsi, .message_end - .message
+    syscall
+
+    mov rax, SYSCALL_EXIT
+    xor rdi, rdi
+    syscall
+
+.message: db "Hello from ring 3!", 10
+.message_end:
+
+user_test_end:
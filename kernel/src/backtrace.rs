@@ -0,0 +1,377 @@
+//! Stack backtraces. The kernel is built with frame pointers, so the return addresses of the
+//! current call stack can be found by following the chain of saved `rbp`s. They are resolved into
+//! function names with the kernel's ELF symbol table, which the bootloader loads along with the
+//! kernel.
+
+use core::{fmt, mem, ptr, slice, str};
+use core::fmt::Write;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use arrayvec::ArrayVec;
+use multiboot2::{BootInformation, ElfSectionType};
+use spin::Once;
+use crate::memory::{self, paging::PhysicalAddress};
+use crate::interrupts::context;
+
+/// Largest distance between two consecutive stack frames which is taken as valid
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+/// Maximum number of frames in a backtrace
+pub const MAX_FRAMES: usize = 32;
+const SYMBOL_TYPE_FUNCTION: u8 = 2;
+
+static SYMBOL_TABLE: Once<SymbolTable> = Once::new();
+/// The instruction pointer of the code interrupted by a fatal CPU exception, or 0 if none happened
+static EXCEPTION_RIP: AtomicUsize = AtomicUsize::new(0);
+/// The frame pointer of the code interrupted by a fatal CPU exception, or 0 for user code
+static EXCEPTION_RBP: AtomicUsize = AtomicUsize::new(0);
+
+/// The physical location of the kernel's symbol table and the string table with the names of its
+/// symbols. Neither is mapped with the kernel's sections, so they are read through the direct map.
+#[derive(Debug, Clone)]
+pub struct SymbolTable {
+    pub symbols: Range<usize>,
+    pub names: Range<usize>,
+}
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+struct Symbol {
+    name: u32,
+    info: u8,
+    other: u8,
+    section_index: u16,
+    value: u64,
+    size: u64,
+}
+
+/// Finds the kernel's symbol table in the multiboot2 ELF sections tag and keeps it for resolving
+/// backtraces. Returns its location, which must be kept from being handed out by the physical
+/// allocator.
+pub fn init_symbols(mb_info: &BootInformation) -> Option<&'static SymbolTable> {
+    let elf_sections = mb_info.elf_sections_tag()?;
+
+    let area = |section_type, name| {
+        elf_sections.sections()
+            .find(|section| section.section_type() == section_type && section.name() == name)
+            .map(|section| section.start_address() as usize..section.end_address() as usize)
+    };
+
+    let symbols = area(ElfSectionType::LinkerSymbolTable, ".symtab");
+    let names = area(ElfSectionType::StringTable, ".strtab");
+
+    match (symbols, names) {
+        (Some(symbols), Some(names)) => {
+            Some(SYMBOL_TABLE.call_once(|| SymbolTable { symbols, names }))
+        },
+        _ => {
+            warn!("backtrace: no kernel symbol table, backtraces will not be symbolized");
+            None
+        },
+    }
+}
+
+/// The location of the kernel's symbol table, if it was found
+pub fn symbol_table() -> Option<&'static SymbolTable> {
+    SYMBOL_TABLE.r#try()
+}
+
+/// Reads the frame pointer of the calling function
+#[inline(always)]
+pub fn current_rbp() -> usize {
+    let rbp: usize;
+    unsafe { asm!("mov %rbp, $0" : "=r"(rbp)); }
+    rbp
+}
+
+/// The return addresses of a call stack, found by walking its frame pointer chain. The walk stops
+/// at anything that does not look like a frame on the same stack, and at frames which are not
+/// mapped (or cannot be told to be, as the page tables are locked), so that walking a corrupt chain
+/// while panicking cannot fault again.
+pub struct Frames {
+    rbp: usize,
+}
+
+impl Frames {
+    /// Walks the call stack starting from the frame with the given frame pointer. The frame pointer
+    /// should be 0 or point to a frame on a stack, as reading other mapped memory, such as that of
+    /// a device, may have side effects.
+    pub unsafe fn new(rbp: usize) -> Self {
+        Frames { rbp }
+    }
+}
+
+impl Iterator for Frames {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let rbp = self.rbp;
+
+        if rbp == 0 || rbp % mem::align_of::<usize>() != 0 || rbp.checked_add(8).is_none() {
+            return None;
+        }
+
+        // The saved frame pointer and return address may be on different pages
+        let mapped = |address| context::is_mapped(address) == Some(true);
+        if !mapped(rbp) || !mapped(rbp + 8) {
+            self.rbp = 0;
+            return None;
+        }
+
+        let (next_rbp, return_address) = unsafe {
+            (ptr::read(rbp as *const usize), ptr::read((rbp + 8) as *const usize))
+        };
+
+        // The stack grows down, so callers' frames are above
+        self.rbp = if next_rbp <= rbp || next_rbp - rbp > MAX_FRAME_SIZE {
+            0
+        } else {
+            next_rbp
+        };
+
+        if return_address == 0 {
+            None
+        } else {
+            Some(return_address)
+        }
+    }
+}
+
+/// Records where a fatal CPU exception happened, so that a backtrace captured while panicking
+/// starts at the faulting instruction rather than in the exception handler. Only kernel code's
+/// frame pointer can be trusted to point at a frame on a mapped stack, so `rbp` must be 0 if the
+/// exception happened in user mode.
+pub fn set_exception_frame(rip: usize, rbp: usize) {
+    EXCEPTION_RBP.store(rbp, Ordering::SeqCst);
+    EXCEPTION_RIP.store(rip, Ordering::SeqCst);
+}
+
+/// A captured call stack
+pub struct Backtrace {
+    frames: ArrayVec<[usize; MAX_FRAMES]>,
+    /// Whether the first frame is the instruction which caused an exception, rather than a return
+    /// address
+    from_exception: bool,
+}
+
+impl Backtrace {
+    /// Captures the call stack of the caller, or that of the code interrupted by a fatal CPU
+    /// exception if one was recorded.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut frames = ArrayVec::new();
+        let exception_rip = EXCEPTION_RIP.swap(0, Ordering::SeqCst);
+
+        if exception_rip != 0 {
+            let rbp = EXCEPTION_RBP.load(Ordering::SeqCst);
+            frames.push(exception_rip);
+            frames.extend(unsafe { Frames::new(rbp) }.take(MAX_FRAMES - 1));
+        } else {
+            frames.extend(unsafe { Frames::new(current_rbp()) }.take(MAX_FRAMES));
+        }
+
+        Backtrace { frames, from_exception: exception_rip != 0 }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Backtrace:")?;
+
+        for (i, &address) in self.frames.iter().enumerate() {
+            write!(f, "  {:>2}: 0x{:016x}", i, address)?;
+
+            // A return address is just past the call, which may be the end of the calling function
+            let call_address = if i == 0 && self.from_exception { address } else { address - 1 };
+
+            match resolve(call_address) {
+                Some((name, start)) => {
+                    writeln!(f, " - {}+0x{:x}", Demangle(name), address - start)?
+                },
+                None => writeln!(f)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Finds the function containing the address, returning its name and start address. Returns
+/// `None` if there is no symbol table or it is not direct mapped yet.
+pub fn resolve(address: usize) -> Option<(&'static str, usize)> {
+    let table = SYMBOL_TABLE.r#try()?;
+
+    if !memory::is_direct_mapped(&table.symbols) || !memory::is_direct_mapped(&table.names) {
+        return None;
+    }
+
+    let (symbols, names) = unsafe { (direct_mapped(&table.symbols), direct_mapped(&table.names)) };
+
+    let symbol = symbols.chunks_exact(mem::size_of::<Symbol>())
+        // The table's alignment is up to the bootloader
+        .map(|entry| unsafe { ptr::read_unaligned(entry.as_ptr() as *const Symbol) })
+        .filter(|symbol| symbol.info & 0xF == SYMBOL_TYPE_FUNCTION)
+        .filter(|symbol| {
+            let start = symbol.value as usize;
+            start <= address && address < start + (symbol.size as usize).max(1)
+        })
+        .max_by_key(|symbol| symbol.value)?;
+
+    let name = names.get(symbol.name as usize..)?;
+    let name = &name[..name.iter().position(|&byte| byte == 0)?];
+
+    str::from_utf8(name).ok().map(|name| (name, symbol.value as usize))
+}
+
+unsafe fn direct_mapped(range: &Range<usize>) -> &'static [u8] {
+    let start = memory::phys_to_virt(PhysicalAddress(range.start)).0 as *const u8;
+    slice::from_raw_parts(start, range.end - range.start)
+}
+
+/// Displays a symbol name, demangled if it uses Rust's legacy mangling scheme (e.g
+/// `_ZN4core9panicking5panic17h0123456789abcdefE` is shown as `core::panicking::panic`). Other
+/// names are shown as they are.
+pub struct Demangle<'a>(pub &'a str);
+
+impl<'a> fmt::Display for Demangle<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mangled = self.0;
+
+        if !mangled.starts_with("_ZN") || !mangled.ends_with('E') || mangled.len() < 4 {
+            return f.write_str(mangled);
+        }
+
+        let body = &mangled[3..mangled.len() - 1];
+
+        // Check that the whole name is well formed before writing anything
+        let mut rest = body;
+        while !rest.is_empty() {
+            match split_component(rest) {
+                Some((_, remaining)) => rest = remaining,
+                None => return f.write_str(mangled),
+            }
+        }
+
+        let mut rest = body;
+        let mut first = true;
+
+        while let Some((component, remaining)) = split_component(rest) {
+            rest = remaining;
+
+            if rest.is_empty() && is_hash(component) {
+                break;
+            }
+
+            if !first {
+                f.write_str("::")?;
+            }
+
+            write_component(f, component)?;
+            first = false;
+        }
+
+        Ok(())
+    }
+}
+
+/// Splits a length prefixed component off the start of a mangled name
+fn split_component(mangled: &str) -> Option<(&str, &str)> {
+    let digits = mangled.bytes().take_while(|byte| byte.is_ascii_digit()).count();
+    let length: usize = mangled[..digits].parse().ok()?;
+    let rest = &mangled[digits..];
+
+    if length == 0 || length > rest.len() || !rest.is_char_boundary(length) {
+        return None;
+    }
+
+    Some((&rest[..length], &rest[length..]))
+}
+
+/// Whether a component is the hash which ends a legacy mangled name
+fn is_hash(component: &str) -> bool {
+    component.len() == 17 &&
+        component.starts_with('h') &&
+        component[1..].bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+fn write_component(f: &mut fmt::Formatter, component: &str) -> fmt::Result {
+    // Components which would start with a `$` escape begin with an underscore
+    let mut rest = if component.starts_with("_$") { &component[1..] } else { component };
+
+    while let Some(c) = rest.chars().next() {
+        if rest.starts_with("..") {
+            f.write_str("::")?;
+            rest = &rest[2..];
+            continue;
+        }
+
+        if c == '$' {
+            let decoded = rest[1..].find('$')
+                .and_then(|end| unescape(&rest[1..end + 1]).map(|c| (c, end + 2)));
+
+            if let Some((decoded, length)) = decoded {
+                f.write_char(decoded)?;
+                rest = &rest[length..];
+                continue;
+            }
+        }
+
+        f.write_char(c)?;
+        rest = &rest[c.len_utf8()..];
+    }
+
+    Ok(())
+}
+
+fn unescape(escape: &str) -> Option<char> {
+    let c = match escape {
+        "SP" => '@',
+        "BP" => '*',
+        "RF" => '&',
+        "LT" => '<',
+        "GT" => '>',
+        "LP" => '(',
+        "RP" => ')',
+        "C" => ',',
+        _ if escape.starts_with('u') => {
+            return u32::from_str_radix(&escape[1..], 16).ok().and_then(core::char::from_u32);
+        },
+        _ => return None,
+    };
+
+    Some(c)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_demangle() {
+        let demangle = |mangled| format!("{}", Demangle(mangled));
+
+        assert_eq!(
+            demangle("_ZN4core9panicking5panic17h0123456789abcdefE"),
+            "core::panicking::panic",
+        );
+        assert_eq!(
+            demangle(
+                "_ZN78_$LT$flower_kernel..memory..heap..Heap$u20$as$u20$core..alloc..GlobalAlloc$GT$\
+                5alloc17hfedcba9876543210E"
+            ),
+            "<flower_kernel::memory::heap::Heap as core::alloc::GlobalAlloc>::alloc",
+        );
+        assert_eq!(
+            demangle("_ZN13flower_kernel5kmain28_$u7b$$u7b$closure$u7d$$u7d$17h0123456789abcdefE"),
+            "flower_kernel::kmain::{{closure}}",
+        );
+    }
+
+    #[test]
+    fn test_demangle_not_mangled() {
+        let demangle = |mangled| format!("{}", Demangle(mangled));
+
+        assert_eq!(demangle("kmain"), "kmain");
+        assert_eq!(demangle("_ZN4core9pan"), "_ZN4core9pan");
+        assert_eq!(demangle("_ZN99coreE"), "_ZN99coreE");
+    }
+}
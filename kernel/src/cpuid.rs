@@ -1,6 +1,8 @@
 use core::arch::x86_64::__cpuid;
 
 const CPUID_GET_FEATURES: u32 = 0x1;
+const CPUID_MAX_EXTENDED: u32 = 0x8000_0000;
+const CPUID_GET_EXTENDED_FEATURES: u32 = 0x8000_0001;
 
 bitflags! {
     /// Represents the collection of flags returned from `CPUID_GET_FEATURES`. This is combined into
@@ -61,3 +63,33 @@ pub fn features() -> Features {
     let result = unsafe { __cpuid(CPUID_GET_FEATURES) };
     Features::from_bits_truncate(result.edx as u64 | (result.ecx as u64) << 32)
 }
+
+/// Returns the initial APIC ID of the current CPU
+pub fn initial_apic_id() -> u8 {
+    let result = unsafe { __cpuid(CPUID_GET_FEATURES) };
+    (result.ebx >> 24) as u8
+}
+
+bitflags! {
+    /// Represents the EDX flags returned from `CPUID_GET_EXTENDED_FEATURES`.
+    pub struct ExtendedFeatures: u32 {
+        const SYSCALL_INSTRUCTION = 1 << 11;
+        const NO_EXECUTE = 1 << 20;
+        const GIB_PAGES = 1 << 26;
+        const RDTSCP_INSTRUCTION = 1 << 27;
+        const LONG_MODE = 1 << 29;
+    }
+}
+
+/// Requests extended CPUID features and returns a set of flags. Empty if the CPU does not support
+/// the request.
+pub fn extended_features() -> ExtendedFeatures {
+    let max = unsafe { __cpuid(CPUID_MAX_EXTENDED) }.eax;
+
+    if max < CPUID_GET_EXTENDED_FEATURES {
+        return ExtendedFeatures::empty();
+    }
+
+    let result = unsafe { __cpuid(CPUID_GET_EXTENDED_FEATURES) };
+    ExtendedFeatures::from_bits_truncate(result.edx)
+}
@@ -0,0 +1,155 @@
+//! Parsing of static ELF64 executables, just enough to load them.
+
+use core::{mem, ptr};
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3E;
+const SEGMENT_LOAD: u32 = 1;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ElfError {
+    TooSmall,
+    NotElf,
+    Not64Bit,
+    NotLittleEndian,
+    NotExecutable,
+    WrongMachine,
+    /// A program header or segment lies outside of the file
+    Truncated,
+}
+
+bitflags! {
+    pub struct SegmentFlags: u32 {
+        const EXECUTABLE = 1 << 0;
+        const WRITABLE = 1 << 1;
+        const READABLE = 1 << 2;
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+struct FileHeader {
+    ident: [u8; 16],
+    elf_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    program_header_offset: u64,
+    section_header_offset: u64,
+    flags: u32,
+    header_size: u16,
+    program_header_entry_size: u16,
+    program_header_count: u16,
+    section_header_entry_size: u16,
+    section_header_count: u16,
+    section_name_index: u16,
+}
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+struct ProgramHeader {
+    segment_type: u32,
+    flags: u32,
+    offset: u64,
+    virtual_address: u64,
+    physical_address: u64,
+    file_size: u64,
+    memory_size: u64,
+    align: u64,
+}
+
+/// A loadable segment of an executable
+#[derive(Debug, Copy, Clone)]
+pub struct Segment<'a> {
+    pub virtual_address: usize,
+    pub memory_size: usize,
+    /// The contents of the segment. The rest of the segment up to `memory_size` is zeroed.
+    pub data: &'a [u8],
+    pub flags: SegmentFlags,
+}
+
+/// A validated static ELF64 executable for x86_64
+pub struct Elf<'a> {
+    data: &'a [u8],
+    header: FileHeader,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        let header: FileHeader = read(data, 0).ok_or(ElfError::TooSmall)?;
+
+        if header.ident[0..4] != ELF_MAGIC {
+            return Err(ElfError::NotElf);
+        } else if header.ident[4] != CLASS_64 {
+            return Err(ElfError::Not64Bit);
+        } else if header.ident[5] != DATA_LITTLE_ENDIAN {
+            return Err(ElfError::NotLittleEndian);
+        } else if header.elf_type != TYPE_EXECUTABLE {
+            return Err(ElfError::NotExecutable);
+        } else if header.machine != MACHINE_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+
+        let elf = Elf { data, header };
+
+        // Check all segments up front so that iterating them cannot fail
+        for i in 0..header.program_header_count as usize {
+            let program_header = elf.program_header(i).ok_or(ElfError::Truncated)?;
+            let end = program_header.offset.checked_add(program_header.file_size);
+
+            match end {
+                Some(end) if end <= data.len() as u64 => (),
+                _ => return Err(ElfError::Truncated),
+            }
+        }
+
+        Ok(elf)
+    }
+
+    pub fn entry_point(&self) -> usize {
+        self.header.entry as usize
+    }
+
+    /// The segments which need to be loaded into memory
+    pub fn segments(&'a self) -> impl Iterator<Item = Segment<'a>> + 'a {
+        (0..self.header.program_header_count as usize)
+            .filter_map(move |i| self.program_header(i))
+            .filter(|header| header.segment_type == SEGMENT_LOAD)
+            .map(move |header| {
+                let start = header.offset as usize;
+
+                Segment {
+                    virtual_address: header.virtual_address as usize,
+                    memory_size: header.memory_size as usize,
+                    data: &self.data[start..start + header.file_size as usize],
+                    flags: SegmentFlags::from_bits_truncate(header.flags),
+                }
+            })
+    }
+
+    fn program_header(&self, index: usize) -> Option<ProgramHeader> {
+        let entry_size = self.header.program_header_entry_size as usize;
+        if entry_size < mem::size_of::<ProgramHeader>() {
+            return None;
+        }
+
+        let offset = (self.header.program_header_offset as usize)
+            .checked_add(index.checked_mul(entry_size)?)?;
+
+        read(self.data, offset)
+    }
+}
+
+/// Reads a structure from the data at the given offset, if it fits
+fn read<T: Copy>(data: &[u8], offset: usize) -> Option<T> {
+    let end = offset.checked_add(mem::size_of::<T>())?;
+
+    if end <= data.len() {
+        Some(unsafe { ptr::read_unaligned(data[offset..].as_ptr() as *const T) })
+    } else {
+        None
+    }
+}
@@ -1,28 +1,19 @@
-use spin::Once;
+use x86_64::{VirtAddr, PrivilegeLevel};
 use x86_64::structures::tss::TaskStateSegment;
+use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
+use crate::memory;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 pub const PANICKING_EXCEPTION_IST_INDEX: u16 = 1;
 pub const IRQ_IST_INDEX: u16 = 2;
+pub const PAGE_FAULT_IST_INDEX: u16 = 3;
 
-pub static TSS: Once<TaskStateSegment> = Once::new();
-
-use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
-
-lazy_static! {
-    static ref GDT: Gdt = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let data_selector = gdt.add_entry(Descriptor::UserSegment((1<<44) | (1<<47) | (1<<41) | (1<<53)));
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(TSS.wait().unwrap()));
-        Gdt {
-            table: gdt,
-            selectors: Selectors { code_selector, tss_selector, data_selector },
-        }
-    };
-}
-
-struct Gdt {
+/// A GDT containing kernel code and data segments, user data and code segments and a TSS. Every CPU
+/// has its own, since every CPU needs its own TSS.
+///
+/// The order of the segments matters: `sysret` expects the user data segment and then the user code
+/// segment to directly follow the kernel data segment.
+pub struct Gdt {
     table: GlobalDescriptorTable,
     selectors: Selectors,
 }
@@ -31,27 +22,76 @@ struct Selectors {
     code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
     data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
 }
 
-pub fn init() {
-    use x86_64::instructions::segmentation::*;
-    use x86_64::instructions::tables::load_tss;
+impl Gdt {
+    pub fn new(tss: &'static TaskStateSegment) -> Gdt {
+        let mut table = GlobalDescriptorTable::new();
+        let code_selector = table.add_entry(Descriptor::kernel_code_segment());
+        let data_selector = table.add_entry(Descriptor::UserSegment((1<<44) | (1<<47) | (1<<41) | (1<<53)));
+        let user_data_selector = table.add_entry(Descriptor::UserSegment((1<<44) | (1<<47) | (1<<41) | (3<<45)));
+        let user_code_selector = table.add_entry(Descriptor::UserSegment((1<<44) | (1<<47) | (1<<41) | (1<<43) | (1<<53) | (3<<45)));
+        let tss_selector = table.add_entry(Descriptor::tss_segment(tss));
+
+        Gdt {
+            table,
+            selectors: Selectors {
+                code_selector,
+                tss_selector,
+                data_selector,
+                user_code_selector: SegmentSelector::new(user_code_selector.index(), PrivilegeLevel::Ring3),
+                user_data_selector: SegmentSelector::new(user_data_selector.index(), PrivilegeLevel::Ring3),
+            },
+        }
+    }
+
+    pub fn user_code_selector(&self) -> SegmentSelector {
+        self.selectors.user_code_selector
+    }
+
+    pub fn user_data_selector(&self) -> SegmentSelector {
+        self.selectors.user_data_selector
+    }
+
+    /// The value of the STAR MSR: the kernel code segment for `syscall` and the base that `sysret`
+    /// calculates the user segments from.
+    pub fn syscall_star(&self) -> u64 {
+        let sysret_base = self.selectors.data_selector.0 as u64;
+        (sysret_base << 48) | ((self.selectors.code_selector.0 as u64) << 32)
+    }
+
+    /// Loads the GDT and TSS on the current CPU and reloads all segment registers. Note that this
+    /// clears the GS base.
+    pub fn load(&'static self) {
+        use x86_64::instructions::segmentation::*;
+        use x86_64::instructions::tables::load_tss;
+
+        self.table.load();
 
-    debug!("gdt: initialising rust gdt");
+        unsafe {
+            set_cs(self.selectors.code_selector);
+            load_tss(self.selectors.tss_selector);
 
-    GDT.table.load();
+            // Reload selector registers
+            load_ss(self.selectors.data_selector);
+            load_ds(self.selectors.data_selector);
+            load_es(self.selectors.data_selector);
+            load_fs(self.selectors.data_selector);
+            load_gs(self.selectors.data_selector);
+        }
+    }
+}
 
-    unsafe {
-        set_cs(GDT.selectors.code_selector);
-        load_tss(GDT.selectors.tss_selector);
+/// Creates a TSS with a fresh set of IST stacks
+pub fn new_tss() -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    let stacks = memory::alloc_ist_stacks();
 
-        // Reload selector registers
-        load_ss(GDT.selectors.data_selector);
-        load_ds(GDT.selectors.data_selector);
-        load_es(GDT.selectors.data_selector);
-        load_fs(GDT.selectors.data_selector);
-        load_gs(GDT.selectors.data_selector);
+    for i in 0..stacks.len() { // Packed struct; cannot safely borrow fields
+        tss.interrupt_stack_table[i] = VirtAddr::new(stacks[i] as u64);
     }
 
-    debug!("gdt: initialised");
-}
\ No newline at end of file
+    tss
+}
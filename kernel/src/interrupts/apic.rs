@@ -0,0 +1,334 @@
+//! Local APIC and I/O APIC drivers. When the ACPI MADT describes them, these replace the 8259 PICs
+//! for IRQ routing.
+//!
+//! Thanks to the OSDev wiki's [APIC](https://wiki.osdev.org/APIC) and
+//! [IOAPIC](https://wiki.osdev.org/IOAPIC) pages.
+
+use core::ptr;
+use alloc::vec::Vec;
+use spin::{Mutex, Once};
+use acpi::{self, Polarity, TriggerMode};
+use x86_64::structures::idt::ExceptionStackFrame;
+use crate::memory::physical_mapping::{self, PhysicalMapping};
+use crate::util;
+
+pub static APIC: Once<Apic> = Once::new();
+
+/// The vector that spurious interrupts from the local APIC are delivered on
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+/// The vector that ISA IRQ 0 is delivered on. Kept the same as the PIC offset so that the IDT
+/// does not need to change.
+const ISA_IRQ_OFFSET: u8 = 32;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+// Local APIC register offsets
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_END_OF_INTERRUPT: usize = 0xB0;
+const LAPIC_SPURIOUS_INTERRUPT_VECTOR: usize = 0xF0;
+const LAPIC_INTERRUPT_COMMAND_LOW: usize = 0x300;
+const LAPIC_INTERRUPT_COMMAND_HIGH: usize = 0x310;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+// Interrupt command register flags
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+// I/O APIC register offsets
+const IOAPIC_REGISTER_SELECT: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_VERSION: u8 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u8 = 0x10;
+
+bitflags! {
+    /// Flags of an I/O APIC redirection table entry. Delivery mode is always fixed and destination
+    /// mode is always physical, which are both represented by zero.
+    struct RedirectionFlags: u64 {
+        const ACTIVE_LOW = 1 << 13;
+        const LEVEL_TRIGGERED = 1 << 15;
+        const MASKED = 1 << 16;
+    }
+}
+
+/// The local APIC of this CPU and all I/O APICs in the system
+pub struct Apic {
+    pub local: LocalApic,
+    io_apics: Mutex<Vec<IoApic>>,
+    overrides: Vec<IsaOverride>,
+}
+
+impl Apic {
+    /// Enables the local APIC and maps all I/O APICs described by the MADT. All I/O APIC
+    /// redirection entries are left masked.
+    ///
+    /// # Unsafety
+    ///
+    /// Unsafe as the addresses must be those of the local APIC and I/O APICs.
+    pub unsafe fn new(
+        local_apic_address: usize,
+        io_apics: &[acpi::IoApic],
+        overrides: &[acpi::InterruptSourceOverride],
+    ) -> Self {
+        let local = LocalApic::new(local_apic_address);
+        local.enable();
+
+        let io_apics = io_apics.iter()
+            .map(|io_apic| {
+                IoApic::new(
+                    io_apic.id,
+                    io_apic.address as usize,
+                    io_apic.global_system_interrupt_base,
+                )
+            })
+            .collect();
+
+        let overrides = overrides.iter()
+            .map(|source_override| IsaOverride {
+                isa_source: source_override.isa_source,
+                global_system_interrupt: source_override.global_system_interrupt,
+                active_low: source_override.polarity == Polarity::ActiveLow,
+                level_triggered: source_override.trigger_mode == TriggerMode::Level,
+            })
+            .collect();
+
+        Apic { local, io_apics: Mutex::new(io_apics), overrides }
+    }
+
+    /// Finds where an ISA IRQ is wired to, taking into account interrupt source overrides. ISA
+    /// IRQs without an override are identity mapped, edge triggered and active high.
+    fn isa_route(&self, irq: u8) -> IsaOverride {
+        self.overrides.iter()
+            .find(|source_override| source_override.isa_source == irq)
+            .cloned()
+            .unwrap_or(IsaOverride {
+                isa_source: irq,
+                global_system_interrupt: irq as u32,
+                active_low: false,
+                level_triggered: false,
+            })
+    }
+
+    /// Routes an ISA IRQ to this CPU at the same vector the PIC would have delivered it at
+    pub fn route_isa_irq(&self, irq: u8, enabled: bool) {
+        let route = self.isa_route(irq);
+        let mut flags = RedirectionFlags::empty();
+
+        if route.active_low {
+            flags |= RedirectionFlags::ACTIVE_LOW;
+        }
+
+        if route.level_triggered {
+            flags |= RedirectionFlags::LEVEL_TRIGGERED;
+        }
+
+        if !enabled {
+            flags |= RedirectionFlags::MASKED;
+        }
+
+        let entry = (ISA_IRQ_OFFSET + irq) as u64 |
+            flags.bits() |
+            ((self.local.id() as u64) << 56);
+
+        self.with_io_apic(route.global_system_interrupt, |io_apic, index| {
+            io_apic.set_redirection_entry(index, entry);
+        });
+    }
+
+    pub fn set_isa_irq_masked(&self, irq: u8, masked: bool) {
+        let gsi = self.isa_route(irq).global_system_interrupt;
+
+        self.with_io_apic(gsi, |io_apic, index| {
+            let mut entry = io_apic.redirection_entry(index);
+            entry.set_masked(masked);
+            io_apic.set_redirection_entry(index, entry.0);
+        });
+    }
+
+    /// Runs the closure on the I/O APIC which handles the given global system interrupt, passing
+    /// it the index of the interrupt's redirection entry.
+    fn with_io_apic<F: FnOnce(&mut IoApic, u32)>(&self, gsi: u32, f: F) {
+        let mut io_apics = self.io_apics.lock();
+        let io_apic = io_apics.iter_mut()
+            .find(|io_apic| io_apic.handles(gsi));
+
+        match io_apic {
+            Some(io_apic) => {
+                let index = gsi - io_apic.gsi_base;
+                f(io_apic, index)
+            },
+            None => warn!("apic: no io apic handles gsi {}", gsi),
+        }
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.local.end_of_interrupt();
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct IsaOverride {
+    isa_source: u8,
+    global_system_interrupt: u32,
+    active_low: bool,
+    level_triggered: bool,
+}
+
+pub struct LocalApic {
+    mapping: PhysicalMapping<u32>,
+}
+
+// The mapping is never handed out, and all accesses to the registers are volatile
+unsafe impl Send for LocalApic {}
+unsafe impl Sync for LocalApic {}
+
+impl LocalApic {
+    unsafe fn new(physical_address: usize) -> Self {
+        LocalApic {
+            mapping: physical_mapping::map_mmio_region(physical_address, 4096),
+        }
+    }
+
+    fn read(&self, register: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.mapping.virtual_address() + register) as *const u32) }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.mapping.virtual_address() + register) as *mut u32, value)
+        }
+    }
+
+    /// Enables the local APIC of the current CPU. All CPUs' local APICs are at the same address,
+    /// so this is also used to enable the APs' local APICs.
+    pub unsafe fn enable(&self) {
+        util::wrmsr(IA32_APIC_BASE_MSR, util::rdmsr(IA32_APIC_BASE_MSR) | APIC_GLOBAL_ENABLE);
+
+        // Accept all interrupts
+        self.write(LAPIC_TASK_PRIORITY, 0);
+        self.write(
+            LAPIC_SPURIOUS_INTERRUPT_VECTOR,
+            SPURIOUS_VECTOR as u32 | LAPIC_SOFTWARE_ENABLE,
+        );
+    }
+
+    pub fn id(&self) -> u8 {
+        (self.read(LAPIC_ID) >> 24) as u8
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.write(LAPIC_END_OF_INTERRUPT, 0);
+    }
+
+    /// Sends an INIT IPI to the CPU with the given APIC ID, resetting it
+    pub fn send_init_ipi(&self, apic_id: u8) {
+        self.send_ipi(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+    }
+
+    /// Sends a startup IPI to the CPU with the given APIC ID. It will start executing in real mode
+    /// at `vector * 4096`.
+    pub fn send_startup_ipi(&self, apic_id: u8, vector: u8) {
+        self.send_ipi(apic_id, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | vector as u32);
+    }
+
+    fn send_ipi(&self, apic_id: u8, command: u32) {
+        self.write(LAPIC_INTERRUPT_COMMAND_HIGH, (apic_id as u32) << 24);
+        self.write(LAPIC_INTERRUPT_COMMAND_LOW, command); // Writing the low half sends the IPI
+
+        while self.read(LAPIC_INTERRUPT_COMMAND_LOW) & ICR_DELIVERY_PENDING != 0 {}
+    }
+}
+
+struct IoApic {
+    id: u8,
+    gsi_base: u32,
+    redirection_entries: u32,
+    mapping: PhysicalMapping<u32>,
+}
+
+// Only accessed behind the `io_apics` mutex
+unsafe impl Send for IoApic {}
+
+impl IoApic {
+    unsafe fn new(id: u8, physical_address: usize, gsi_base: u32) -> Self {
+        let mut io_apic = IoApic {
+            id,
+            gsi_base,
+            redirection_entries: 0,
+            mapping: physical_mapping::map_mmio_region(physical_address, 4096),
+        };
+
+        io_apic.redirection_entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+
+        // Start with everything masked
+        for index in 0..io_apic.redirection_entries {
+            io_apic.set_redirection_entry(index, RedirectionFlags::MASKED.bits());
+        }
+
+        debug!(
+            "apic: io apic {} handles gsis {} to {}",
+            io_apic.id,
+            gsi_base,
+            gsi_base + io_apic.redirection_entries - 1,
+        );
+
+        io_apic
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.redirection_entries
+    }
+
+    fn read(&mut self, register: u8) -> u32 {
+        let base = self.mapping.virtual_address();
+
+        unsafe {
+            ptr::write_volatile((base + IOAPIC_REGISTER_SELECT) as *mut u32, register as u32);
+            ptr::read_volatile((base + IOAPIC_WINDOW) as *const u32)
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u32) {
+        let base = self.mapping.virtual_address();
+
+        unsafe {
+            ptr::write_volatile((base + IOAPIC_REGISTER_SELECT) as *mut u32, register as u32);
+            ptr::write_volatile((base + IOAPIC_WINDOW) as *mut u32, value);
+        }
+    }
+
+    fn redirection_entry(&mut self, index: u32) -> RedirectionEntry {
+        let register = IOAPIC_REDIRECTION_TABLE + (index * 2) as u8;
+        let low = self.read(register) as u64;
+        let high = self.read(register + 1) as u64;
+
+        RedirectionEntry((high << 32) | low)
+    }
+
+    fn set_redirection_entry(&mut self, index: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + (index * 2) as u8;
+
+        // Write the high half first so the entry is never unmasked with a stale destination
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct RedirectionEntry(u64);
+
+impl RedirectionEntry {
+    fn set_masked(&mut self, masked: bool) {
+        let mut flags = RedirectionFlags::from_bits_truncate(self.0);
+        flags.set(RedirectionFlags::MASKED, masked);
+
+        self.0 = (self.0 & !RedirectionFlags::all().bits()) | flags.bits();
+    }
+}
+
+/// Spurious interrupts must not be acknowledged, so this does nothing. As it does not touch GS, it
+/// does not need to `swapgs` when entered from user mode.
+pub extern "x86-interrupt" fn spurious(_stack_frame: &mut ExceptionStackFrame) {}
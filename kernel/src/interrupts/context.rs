@@ -0,0 +1,229 @@
+//! The state saved by the common exception entry in `asm/exceptions.asm`, and dumping it along with
+//! the control registers and the memory around the faulting code when an exception is fatal.
+
+use core::{fmt, ptr};
+use x86_64::structures::idt::PageFaultErrorCode;
+use crate::memory::paging::{Page, PageSize, PAGE_TABLES};
+use crate::util;
+
+/// Bytes shown on each line of a hexdump
+const HEXDUMP_LINE: usize = 16;
+/// Lines of code shown before and after the line containing the faulting instruction
+const CODE_CONTEXT_LINES: usize = 1;
+/// Lines shown from the top of the stack
+const STACK_LINES: usize = 4;
+
+/// The registers of the interrupted code, as pushed by the CPU and the exception entry. Changes to
+/// them take effect when the handler returns.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct ExceptionContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// The error code pushed by the CPU, or 0 for exceptions without one
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl ExceptionContext {
+    /// Whether the exception happened in ring 0
+    pub fn is_kernel(&self) -> bool {
+        self.cs & 0b11 == 0
+    }
+
+    /// Displays the registers, the control registers and the memory at the instruction pointer and
+    /// the top of the stack
+    pub fn dump(&self) -> Dump {
+        Dump(self)
+    }
+}
+
+pub struct Dump<'a>(&'a ExceptionContext);
+
+impl<'a> fmt::Display for Dump<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let c = self.0;
+
+        let registers = [
+            ("RAX", c.rax), ("RBX", c.rbx), ("RCX", c.rcx),
+            ("RDX", c.rdx), ("RSI", c.rsi), ("RDI", c.rdi),
+            ("RBP", c.rbp), ("RSP", c.rsp), ("R8 ", c.r8),
+            ("R9 ", c.r9), ("R10", c.r10), ("R11", c.r11),
+            ("R12", c.r12), ("R13", c.r13), ("R14", c.r14),
+            ("R15", c.r15), ("RIP", c.rip), ("RFL", c.rflags),
+        ];
+
+        for line in registers.chunks(3) {
+            for (name, value) in line {
+                write!(f, "{}={:016x} ", name, value)?;
+            }
+            writeln!(f)?;
+        }
+
+        writeln!(
+            f,
+            "CS={:04x} SS={:04x} vector={} error code=0x{:x}",
+            c.cs,
+            c.ss,
+            c.vector,
+            c.error_code,
+        )?;
+
+        let efer = unsafe { util::rdmsr(util::IA32_EFER_MSR) };
+        writeln!(f, "CR0={:016x} CR2={:016x} CR3={:016x}", util::cr0(), util::cr2(), util::cr3())?;
+        writeln!(f, "CR4={:016x} EFER={:016x}", util::cr4(), efer)?;
+
+        let code = Hexdump::new(
+            (c.rip as usize & !(HEXDUMP_LINE - 1)).wrapping_sub(CODE_CONTEXT_LINES * HEXDUMP_LINE),
+            (CODE_CONTEXT_LINES * 2 + 1) * HEXDUMP_LINE,
+        );
+        writeln!(f, "Code at RIP:\n{}", code)?;
+
+        let stack = Hexdump::new(c.rsp as usize, STACK_LINES * HEXDUMP_LINE);
+        write!(f, "Top of stack:\n{}", stack)
+    }
+}
+
+/// Displays memory as lines of hex bytes. The contents of lines which are not mapped are left out.
+pub struct Hexdump {
+    start: usize,
+    lines: usize,
+}
+
+impl Hexdump {
+    /// Covers at least the given number of bytes from the address, in whole lines
+    pub fn new(address: usize, bytes: usize) -> Self {
+        let start = address & !(HEXDUMP_LINE - 1);
+        let end = address.wrapping_add(bytes);
+        let lines = (end.wrapping_sub(start) + HEXDUMP_LINE - 1) / HEXDUMP_LINE;
+
+        Hexdump { start, lines }
+    }
+}
+
+impl fmt::Display for Hexdump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        hexdump(f, self.start, self.lines)
+    }
+}
+
+fn hexdump(f: &mut fmt::Formatter, start: usize, lines: usize) -> fmt::Result {
+    for line in 0..lines {
+        let address = start.wrapping_add(line * HEXDUMP_LINE);
+        write!(f, "{:016x}:", address)?;
+
+        // A line never crosses a page boundary, so checking its start is enough
+        match is_mapped(address) {
+            Some(true) => {
+                for offset in 0..HEXDUMP_LINE {
+                    let byte = unsafe { ptr::read_volatile((address + offset) as *const u8) };
+                    write!(f, " {:02x}", byte)?;
+                }
+                writeln!(f)?;
+            },
+            Some(false) => writeln!(f, " not mapped")?,
+            None => writeln!(f, " page tables locked")?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the address is mapped in the current address space, or `None` if this cannot be told
+/// because the page tables are locked (e.g by the code which faulted)
+pub fn is_mapped(address: usize) -> Option<bool> {
+    if !is_canonical(address) {
+        return Some(false);
+    }
+
+    let tables = PAGE_TABLES.try_lock()?;
+    Some(tables.translate(address).is_some())
+}
+
+/// Whether bits 48 to 63 of the address are copies of bit 47, without which it cannot be mapped
+fn is_canonical(address: usize) -> bool {
+    let upper = address >> 47;
+    upper == 0 || upper == 0x1FFFF
+}
+
+/// Describes a page fault's error code, e.g "user write to a present page"
+pub struct PageFaultDescription(pub PageFaultErrorCode);
+
+impl fmt::Display for PageFaultDescription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+
+        let mode = if code.contains(PageFaultErrorCode::USER_MODE) { "user" } else { "kernel" };
+
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch from"
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write to"
+        } else {
+            "read from"
+        };
+
+        let page = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "a present page"
+        } else {
+            "a non-present page"
+        };
+
+        write!(f, "{} {} {}", mode, access, page)?;
+
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, " (reserved bit set in a page table)")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Describes how an address is mapped in the current address space, as found by walking the page
+/// tables
+pub struct PageWalk(pub usize);
+
+impl fmt::Display for PageWalk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let address = self.0;
+
+        if !is_canonical(address) {
+            return write!(f, "0x{:x}: not canonical", address);
+        }
+
+        let tables = match PAGE_TABLES.try_lock() {
+            Some(tables) => tables,
+            None => return write!(f, "0x{:x}: page tables locked", address),
+        };
+
+        match tables.walk_page_table(Page::containing_address(address, PageSize::Kib4)) {
+            Some((entry, size)) => write!(
+                f,
+                "0x{:x}: {:?} page at 0x{:x}, flags {:?}",
+                address,
+                size,
+                entry.physical_address().map(|physical| physical.0).unwrap_or(0),
+                entry.flags(),
+            ),
+            None => write!(f, "0x{:x}: not mapped", address),
+        }
+    }
+}
@@ -0,0 +1,623 @@
+//! An interactive kernel debugger, entered on breakpoint (`int3`) and debug exceptions. It reads
+//! commands from serial port 1 and the PS/2 keyboard, writes to both serial and the VGA terminal,
+//! and resumes the interrupted code when told to continue or step.
+//!
+//! Software breakpoints replace the first byte of an instruction with `int3`. They are taken out
+//! for the duration of a debugging session, so that the debugger does not hit them itself. When one
+//! is hit, it is left out while its instruction is single stepped with RFLAGS.TF, and inserted
+//! again afterwards.
+//!
+//! Breakpoints cannot be set in the code which runs before a session takes the breakpoints out or
+//! after it puts them back (the exception entry, this module, `exceptions`, `util`, `cpuid` and
+//! `spin`), as hitting one there would enter the debugger again on a CPU which already holds it.
+//! Symbols are not a complete guard, as inlining moves code between functions, so a breakpoint
+//! which is hit on the CPU in the debugger anyway is taken out, and stays out until the next
+//! session.
+
+use core::{fmt, mem, ptr, str};
+use core::cell::UnsafeCell;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use arrayvec::ArrayVec;
+use spin::{Mutex, RwLockWriteGuard};
+use crate::drivers::keyboard::keymap::{self, codes};
+use crate::drivers::ps2::io as ps2_io;
+use crate::drivers::serial::{self, SerialPort};
+use crate::drivers::vga;
+use crate::terminal::{self, Stdout, TerminalOutput};
+use crate::backtrace::{self, Demangle};
+use crate::cpuid;
+use crate::memory;
+use crate::util;
+use super::context::{self, ExceptionContext, Hexdump, PageWalk};
+
+const MAX_BREAKPOINTS: usize = 16;
+const MAX_LINE: usize = 80;
+/// Largest number of bytes shown by `read`
+const MAX_READ: usize = 1024;
+const INT3: u8 = 0xCC;
+/// The trap flag in RFLAGS, which raises a debug exception after the next instruction
+const RFLAGS_TRAP: u64 = 1 << 8;
+/// The bit in DR6 which is set when a debug exception was caused by the trap flag
+const DR6_SINGLE_STEP: u64 = 1 << 14;
+/// The bit in CR0 which stops the kernel from writing to read only pages
+const CR0_WRITE_PROTECT: u64 = 1 << 16;
+
+const HELP: &str = "\
+Numbers are hexadecimal.
+  regs                     show registers and the memory at RIP and RSP
+  read <address> [bytes]   dump memory
+  write <address> <byte>.. write bytes to memory
+  walk <address>           walk the page tables for an address
+  break [address]          set a breakpoint, or list them
+  delete <address>         remove a breakpoint
+  stats                    show memory usage
+  leaks                    show live allocations, with the alloc_tracking feature
+  step                     execute one instruction
+  continue                 resume execution
+";
+
+/// The modules whose functions run on the way into and out of a session (see the module
+/// documentation)
+const EXCEPTION_PATH_MODULES: [&str; 6] = [
+    "flower_kernel::interrupts::debugger",
+    "flower_kernel::interrupts::exceptions",
+    "flower_kernel::util",
+    "flower_kernel::cpuid",
+    "spin",
+    "core::sync::atomic",
+];
+/// Symbols on the way into a session which are not in a Rust module
+const EXCEPTION_PATH_SYMBOLS: [&str; 1] = ["exception_dispatch"];
+/// The value of `SESSION_CPU` when no CPU is in the debugger
+const NO_CPU: usize = usize::max_value();
+
+/// Held for a whole debugging session, so other CPUs which hit a breakpoint wait for it to end
+static DEBUGGER: Mutex<Debugger> = Mutex::new(Debugger::new());
+/// The initial APIC ID of the CPU which holds `DEBUGGER`, from just after it is locked until just
+/// after it is unlocked, or `NO_CPU`. The next CPU to lock it may set it before it is cleared, so
+/// it is only cleared if it is still the same CPU.
+static SESSION_CPU: AtomicUsize = AtomicUsize::new(NO_CPU);
+/// The breakpoints as they were last inserted, which can be read without locking `DEBUGGER`
+static INSERTED: InsertedBreakpoints =
+    InsertedBreakpoints(UnsafeCell::new([None; MAX_BREAKPOINTS]));
+
+extern "C" {
+    /// The end of the exception entry in `asm/exceptions.asm`, which starts with the stubs
+    static exception_entry_end: u8;
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Breakpoint {
+    address: usize,
+    /// The byte which `int3` replaced
+    original: u8,
+}
+
+/// Only written by the CPU which holds `DEBUGGER` while the breakpoints are out, and only read by
+/// that CPU when it hits one of them before it unlocks `DEBUGGER`
+struct InsertedBreakpoints(UnsafeCell<[Option<Breakpoint>; MAX_BREAKPOINTS]>);
+
+unsafe impl Sync for InsertedBreakpoints {}
+
+struct Debugger {
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// A breakpoint which was taken out to run its instruction, and is to be put back after it
+    lifted: Option<usize>,
+    /// Whether the user asked to single step, as opposed to stepping over a lifted breakpoint
+    stepping: bool,
+}
+
+enum Resume {
+    Continue,
+    Step,
+}
+
+/// Handles a breakpoint exception, which leaves RIP just after the `int3`
+pub fn breakpoint(context: &mut ExceptionContext) {
+    let address = context.rip as usize - 1;
+    let cpu = cpuid::initial_apic_id() as usize;
+
+    if SESSION_CPU.load(Ordering::SeqCst) == cpu {
+        resume_reentered(context, address);
+        return;
+    }
+
+    let mut debugger = DEBUGGER.lock();
+    SESSION_CPU.store(cpu, Ordering::SeqCst);
+
+    if debugger.find(address).is_some() {
+        debugger.lifted = Some(address);
+        context.rip = address as u64;
+    }
+
+    debugger.session(context, "breakpoint");
+
+    mem::drop(debugger);
+    SESSION_CPU.compare_and_swap(cpu, NO_CPU, Ordering::SeqCst);
+}
+
+/// Handles a debug exception, which is raised after an instruction was single stepped
+pub fn debug(context: &mut ExceptionContext) {
+    let dr6 = util::dr6();
+    unsafe { util::dr6_write(0); }
+
+    let cpu = cpuid::initial_apic_id() as usize;
+    let mut debugger = DEBUGGER.lock();
+    SESSION_CPU.store(cpu, Ordering::SeqCst);
+
+    if let Some(address) = debugger.lifted.take() {
+        if debugger.find(address).is_some() {
+            unsafe { poke(address, INT3); }
+        }
+    }
+
+    if dr6 & DR6_SINGLE_STEP != 0 && !debugger.stepping {
+        context.rflags &= !RFLAGS_TRAP;
+    } else {
+        debugger.session(context, "debug exception");
+    }
+
+    mem::drop(debugger);
+    SESSION_CPU.compare_and_swap(cpu, NO_CPU, Ordering::SeqCst);
+}
+
+/// Handles a breakpoint which was hit on the way into or out of a session, on the CPU which holds
+/// `DEBUGGER`. Entering a session there would deadlock, so the breakpoint is taken out and the
+/// instruction it replaced is run. It is inserted again with the others when the next session
+/// ends.
+fn resume_reentered(context: &mut ExceptionContext, address: usize) {
+    let inserted = unsafe { ptr::read_volatile(INSERTED.0.get()) };
+    let breakpoint = inserted.iter()
+        .filter_map(|breakpoint| *breakpoint)
+        .find(|breakpoint| breakpoint.address == address);
+
+    // Otherwise the `int3` is not one of ours, and execution just carries on after it
+    if let Some(breakpoint) = breakpoint {
+        unsafe { poke(address, breakpoint.original); }
+        context.rip = address as u64;
+    }
+}
+
+impl Debugger {
+    const fn new() -> Self {
+        Debugger { breakpoints: [None; MAX_BREAKPOINTS], lifted: None, stepping: false }
+    }
+
+    fn find(&self, address: usize) -> Option<Breakpoint> {
+        self.breakpoints.iter()
+            .filter_map(|breakpoint| *breakpoint)
+            .find(|breakpoint| breakpoint.address == address)
+    }
+
+    /// Takes commands until told to resume, then sets the trap flag if there is an instruction to
+    /// step. The breakpoints are taken out meanwhile.
+    fn session(&mut self, context: &mut ExceptionContext, reason: &str) {
+        self.set_inserted(false);
+        let mut console = Console::new();
+
+        let _ = writeln!(console, "debugger: {} at {}", reason, Location(context.rip as usize));
+
+        let resume = loop {
+            let _ = write!(console, "kdb> ");
+            let line = console.read_line();
+            let line = str::from_utf8(&line).unwrap_or("");
+
+            if let Some(resume) = self.execute(&mut console, context, line) {
+                break resume;
+            }
+        };
+
+        self.stepping = match resume {
+            Resume::Continue => false,
+            Resume::Step => true,
+        };
+
+        if self.stepping || self.lifted.is_some() {
+            context.rflags |= RFLAGS_TRAP;
+        } else {
+            context.rflags &= !RFLAGS_TRAP;
+        }
+
+        self.set_inserted(true);
+    }
+
+    /// Inserts or takes out every breakpoint, except a lifted one which stays out either way
+    fn set_inserted(&self, inserted: bool) {
+        if inserted {
+            // The breakpoints are all out, so this CPU cannot be reading the copy meanwhile
+            unsafe { ptr::write_volatile(INSERTED.0.get(), self.breakpoints); }
+        }
+
+        let breakpoints = self.breakpoints.iter()
+            .filter_map(|breakpoint| *breakpoint)
+            .filter(|breakpoint| self.lifted != Some(breakpoint.address));
+
+        for breakpoint in breakpoints {
+            let byte = if inserted { INT3 } else { breakpoint.original };
+            unsafe { poke(breakpoint.address, byte); }
+        }
+    }
+
+    /// Runs a command, returning how to resume if the command resumes execution
+    fn execute(
+        &mut self,
+        console: &mut Console,
+        context: &mut ExceptionContext,
+        line: &str,
+    ) -> Option<Resume> {
+        let mut words = line.split_whitespace();
+        let command = words.next()?;
+        let arguments: ArrayVec<[Option<usize>; MAX_LINE / 2]> = words.map(parse_number).collect();
+
+        // Errors writing to the console could not be shown anywhere
+        let _ = match (command, arguments.as_slice()) {
+            ("continue", []) | ("c", []) => return Some(Resume::Continue),
+            ("step", []) | ("s", []) => return Some(Resume::Step),
+            ("help", []) => console.write_str(HELP),
+            ("regs", []) => write!(console, "{}", context.dump()),
+            ("read", [Some(address)]) => write!(console, "{}", Hexdump::new(*address, 64)),
+            ("read", [Some(address), Some(bytes)]) => {
+                write!(console, "{}", Hexdump::new(*address, (*bytes).min(MAX_READ)))
+            },
+            ("write", arguments) if arguments.len() >= 2 => self.write(console, arguments),
+            ("walk", [Some(address)]) => writeln!(console, "{}", PageWalk(*address)),
+            ("break", []) => self.list_breakpoints(console),
+            ("break", [Some(address)]) => self.set_breakpoint(console, *address),
+            ("delete", [Some(address)]) => self.delete_breakpoint(console, *address),
+            ("stats", []) => match memory::stats::try_memory_stats() {
+                Some(stats) => write!(console, "{}", stats),
+                None => writeln!(console, "An allocator is locked, so its usage cannot be read"),
+            },
+            ("leaks", []) => leaks(console),
+            _ => writeln!(console, "Unknown command or bad arguments, see `help`"),
+        };
+
+        None
+    }
+
+    /// Writes the bytes following the address in the arguments
+    fn write(&mut self, console: &mut Console, arguments: &[Option<usize>]) -> fmt::Result {
+        let address = match arguments[0] {
+            Some(address) => address,
+            None => return writeln!(console, "Bad address"),
+        };
+
+        let mut bytes: ArrayVec<[u8; MAX_LINE / 2]> = ArrayVec::new();
+        for argument in &arguments[1..] {
+            match argument {
+                Some(byte) if *byte <= 0xFF => bytes.push(*byte as u8),
+                _ => return writeln!(console, "Bytes must be between 0 and ff"),
+            }
+        }
+
+        let end = address.wrapping_add(bytes.len() - 1);
+        if context::is_mapped(address) != Some(true) || context::is_mapped(end) != Some(true) {
+            return writeln!(console, "0x{:x} to 0x{:x} is not mapped", address, end);
+        }
+
+        for (target, &byte) in (address..).zip(bytes.iter()) {
+            // Breakpoints are out during the session, so only the byte they put back changes
+            let breakpoint = self.breakpoints.iter_mut()
+                .filter_map(|slot| slot.as_mut())
+                .find(|breakpoint| breakpoint.address == target);
+
+            if let Some(breakpoint) = breakpoint {
+                breakpoint.original = byte;
+            }
+
+            unsafe { poke(target, byte); }
+        }
+
+        Ok(())
+    }
+
+    fn list_breakpoints(&self, console: &mut Console) -> fmt::Result {
+        for breakpoint in self.breakpoints.iter().filter_map(|breakpoint| *breakpoint) {
+            writeln!(console, "  {}", Location(breakpoint.address))?;
+        }
+
+        Ok(())
+    }
+
+    fn set_breakpoint(&mut self, console: &mut Console, address: usize) -> fmt::Result {
+        if self.find(address).is_some() {
+            return writeln!(console, "There is already a breakpoint at 0x{:x}", address);
+        }
+
+        if context::is_mapped(address) != Some(true) {
+            return writeln!(console, "0x{:x} is not mapped", address);
+        }
+
+        if is_exception_path(address) {
+            return writeln!(
+                console,
+                "{} is on the way into the debugger, so cannot have a breakpoint",
+                Location(address),
+            );
+        }
+
+        let slot = match self.breakpoints.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => slot,
+            None => return writeln!(console, "No more than {} breakpoints", MAX_BREAKPOINTS),
+        };
+
+        // The breakpoint is inserted with the others when the session ends
+        let original = unsafe { ptr::read_volatile(address as *const u8) };
+        *slot = Some(Breakpoint { address, original });
+        writeln!(console, "Breakpoint at {}", Location(address))
+    }
+
+    fn delete_breakpoint(&mut self, console: &mut Console, address: usize) -> fmt::Result {
+        let slot = self.breakpoints.iter_mut()
+            .find(|slot| slot.map(|breakpoint| breakpoint.address) == Some(address));
+
+        // The breakpoint is already out, as the session is in progress
+        match slot.and_then(|slot| slot.take()) {
+            Some(_) => Ok(()),
+            None => writeln!(console, "There is no breakpoint at 0x{:x}", address),
+        }
+    }
+}
+
+/// Shows the live allocations which are tracked with the `alloc_tracking` feature
+fn leaks(console: &mut Console) -> fmt::Result {
+    #[cfg(feature = "alloc_tracking")]
+    {
+        match memory::alloc_tracking::try_report() {
+            Some(report) => write!(console, "{}", report),
+            None => writeln!(console, "The allocation table is locked, so it cannot be read"),
+        }
+    }
+
+    #[cfg(not(feature = "alloc_tracking"))]
+    {
+        writeln!(console, "Allocation tracking needs the alloc_tracking feature")
+    }
+}
+
+/// Parses a hexadecimal number, with or without a `0x` prefix
+fn parse_number(word: &str) -> Option<usize> {
+    let digits = if word.starts_with("0x") { &word[2..] } else { word };
+    usize::from_str_radix(digits, 16).ok()
+}
+
+/// Whether the address is in code which runs on the way into or out of a session
+fn is_exception_path(address: usize) -> bool {
+    let entry_start = unsafe { super::exception_stubs[0] };
+    let entry_end = unsafe { &exception_entry_end as *const u8 as usize };
+
+    if (entry_start..entry_end).contains(&address) {
+        return true;
+    }
+
+    match backtrace::resolve(address) {
+        Some((name, _)) => {
+            EXCEPTION_PATH_SYMBOLS.contains(&name) ||
+                EXCEPTION_PATH_MODULES.iter().any(|module| in_module(name, module))
+        }
+        None => false,
+    }
+}
+
+/// Whether a mangled symbol is a function in the module, or in an impl for a type in the module.
+/// Trait impls start with the type, e.g `_$LT$spin..mutex..MutexGuard$LT$T$GT$$u20$as$u20$..`.
+fn in_module(name: &str, module: &str) -> bool {
+    if !name.starts_with("_ZN") {
+        return false;
+    }
+
+    let mut rest = &name[3..];
+    let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    let mut components = module.split("::");
+
+    if rest[digits..].starts_with("_$LT$") {
+        let path = rest[digits + "_$LT$".len()..].trim_start_matches("$RF$");
+        let path = path.trim_start_matches("mut$u20$");
+        let path = &path[..path.find('$').unwrap_or(path.len())];
+
+        let mut path_components = path.split("..");
+        return components.all(|component| path_components.next() == Some(component));
+    }
+
+    components.all(|component| {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let length = match rest[..digits].parse::<usize>() {
+            Ok(length) if digits + length <= rest.len() => length,
+            _ => return false,
+        };
+
+        let matches = &rest[digits..digits + length] == component;
+        rest = &rest[digits + length..];
+        matches
+    })
+}
+
+/// Writes a byte even if its page is read only (e.g kernel code), by clearing CR0.WP meanwhile
+unsafe fn poke(address: usize, byte: u8) {
+    let cr0 = util::cr0();
+    util::cr0_write(cr0 & !CR0_WRITE_PROTECT);
+    ptr::write_volatile(address as *mut u8, byte);
+    util::cr0_write(cr0);
+}
+
+/// Displays an address along with the function it is in, if known
+struct Location(usize);
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:x}", self.0)?;
+
+        match backtrace::resolve(self.0) {
+            Some((name, start)) => write!(f, " ({}+0x{:x})", Demangle(name), self.0 - start),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Input from serial port 1 and the PS/2 keyboard, and output to serial port 1 and the VGA
+/// terminal, or only the latter two if the serial port cannot be initialized. Neither goes through
+/// the locks of the usual drivers, which the interrupted code may hold.
+struct Console {
+    /// Serial port 1, unless it could not be initialized
+    serial: Option<SerialPort>,
+    keyboard: PolledKeyboard,
+}
+
+impl Console {
+    fn new() -> Self {
+        let mut serial = unsafe { SerialPort::new(serial::PORT_1_ADDR) };
+        let serial = serial.init(serial::MAX_BAUD, false).ok().map(|_| serial);
+
+        Console { serial, keyboard: PolledKeyboard::new() }
+    }
+
+    /// Reads a line, echoing it as it is typed
+    fn read_line(&mut self) -> ArrayVec<[u8; MAX_LINE]> {
+        let mut line = ArrayVec::new();
+
+        loop {
+            match self.read_byte() {
+                b'\r' | b'\n' => {
+                    let _ = self.write_str("\n");
+                    return line;
+                },
+                0x08 | 0x7F => {
+                    if line.pop().is_some() {
+                        if let Some(serial) = &mut self.serial {
+                            let _ = serial.write_str("\x08 \x08");
+                        }
+
+                        if let Some(mut stdout) = vga_terminal() {
+                            let _ = stdout.backspace();
+                        }
+                    }
+                },
+                byte if byte.is_ascii_graphic() || byte == b' ' => {
+                    if line.try_push(byte).is_ok() {
+                        let _ = self.write_char(byte as char);
+                    }
+                },
+                _ => (),
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.serial.as_mut().and_then(|serial| serial.try_read()) {
+                return byte;
+            }
+
+            if let Some(byte) = self.keyboard.read_byte() {
+                return byte;
+            }
+        }
+    }
+}
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(serial) = &mut self.serial {
+            serial.write_str(s)?;
+        }
+
+        if let Some(mut stdout) = vga_terminal() {
+            stdout.write_str(s)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The VGA terminal, unless the interrupted code is using it
+fn vga_terminal() -> Option<RwLockWriteGuard<'static, Stdout<'static>>> {
+    let stdout = terminal::STDOUT.try_write()?;
+
+    // `Stdout` locks the writer for each operation, so it is only checked to be free here
+    drop(vga::WRITER.try_write()?);
+
+    Some(stdout)
+}
+
+/// Reads characters from the PS/2 keyboard by polling the controller, as the keyboard driver may
+/// be in use by the interrupted code
+struct PolledKeyboard {
+    shift: bool,
+    extended: bool,
+    release: bool,
+}
+
+impl PolledKeyboard {
+    fn new() -> Self {
+        PolledKeyboard { shift: false, extended: false, release: false }
+    }
+
+    /// Returns the ASCII character typed, if a key was pressed
+    fn read_byte(&mut self) -> Option<u8> {
+        if !ps2_io::can_read().ok()? || !ps2_io::can_read_keyboard().ok()? {
+            return None;
+        }
+
+        let scancode = ps2_io::DATA_PORT.read();
+
+        match scancode {
+            0xE0 => self.extended = true,
+            0xF0 => self.release = true,
+            _ => {
+                let release = mem::replace(&mut self.release, false);
+                let keycode = if mem::replace(&mut self.extended, false) {
+                    keymap::get_extended_code_ps2_set_2(scancode)
+                } else {
+                    keymap::get_code_ps2_set_2(scancode)
+                }?;
+
+                if keycode == codes::LEFT_SHIFT || keycode == codes::RIGHT_SHIFT {
+                    self.shift = !release;
+                } else if !release {
+                    let (lower, upper) = keymap::get_us_qwerty_char(keycode)?;
+                    let character = if self.shift { upper } else { lower };
+                    return Some(character as u8);
+                }
+            },
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_in_module() {
+        let debugger = "flower_kernel::interrupts::debugger";
+
+        assert!(in_module("_ZN13flower_kernel10interrupts8debugger4poke17h01234567E", debugger));
+        assert!(!in_module("_ZN13flower_kernel10interrupts10exceptions4page17h0123E", debugger));
+        assert!(!in_module("_ZN13flower_kernel10interrupts17h0123456789abcdefE", debugger));
+        assert!(in_module("_ZN4spin5mutex14Mutex$LT$T$GT$4lock17h0123456789abcdefE", "spin"));
+        assert!(!in_module("_ZN8spinning4lock17h0123456789abcdefE", "spin"));
+        assert!(!in_module("exception_dispatch", "spin"));
+
+        assert!(in_module(
+            concat!(
+                "_ZN79_$LT$spin..mutex..MutexGuard$LT$T$GT$$u20$as$u20$",
+                "core..ops..drop..Drop$GT$4drop17h0123456789abcdefE",
+            ),
+            "spin",
+        ));
+        assert!(in_module(
+            concat!(
+                "_ZN84_$LT$$RF$flower_kernel..interrupts..debugger..Location$u20$as$u20$",
+                "core..fmt..Display$GT$3fmt17h0123456789abcdefE",
+            ),
+            debugger,
+        ));
+        assert!(!in_module(
+            "_ZN65_$LT$spinning..Lock$u20$as$u20$core..ops..drop..Drop$GT$4drop17h01234567E",
+            "spin",
+        ));
+    }
+}
@@ -1,109 +1,162 @@
-//! Exception handlers
-
-use x86_64::structures::idt::{ExceptionStackFrame, PageFaultErrorCode};
-
-pub extern "x86-interrupt" fn divide_by_zero(stack_frame: &mut ExceptionStackFrame) {
-    panic!("cpuex: divide by zero\n{:#?}", stack_frame);
-}
-
-pub extern "x86-interrupt" fn debug(stack_frame: &mut ExceptionStackFrame) {
-    panic!("cpuex: debug\n{:#?}", stack_frame);
-}
-
-pub extern "x86-interrupt" fn nmi(stack_frame: &mut ExceptionStackFrame) {
-    panic!("cpuex: nmi\n{:#?}", stack_frame);
+//! Exception handlers. Every exception goes through the common entry in `asm/exceptions.asm`,
+//! which calls [exception_dispatch] with the interrupted code's registers. Breakpoint and debug
+//! exceptions enter the debugger and resume, while the rest panic unless they can be resolved.
+
+use x86_64::structures::idt::PageFaultErrorCode;
+use crate::memory::{self, address_space, demand_paging};
+use crate::sched::{self, thread};
+use crate::smp::percpu;
+use crate::{backtrace, gdt, util};
+use super::context::{ExceptionContext, PageFaultDescription, PageWalk};
+use super::debugger;
+
+const DEBUG_VECTOR: u64 = 1;
+const BREAKPOINT_VECTOR: u64 = 3;
+const PAGE_FAULT_VECTOR: u64 = 14;
+
+/// The names of the exception vectors, by vector
+const EXCEPTION_NAMES: [&str; 32] = [
+    "divide by zero",
+    "debug",
+    "nmi",
+    "breakpoint",
+    "overflow",
+    "out of bounds",
+    "invalid opcode",
+    "device not available",
+    "double fault",
+    "coprocessor segment overrun",
+    "invalid tss",
+    "segment not present",
+    "stack segment fault",
+    "general protection fault",
+    "page fault",
+    "reserved exception 15",
+    "x87 floating point",
+    "alignment check",
+    "machine check",
+    "simd floating point",
+    "virtualization",
+    "control protection",
+    "reserved exception 22",
+    "reserved exception 23",
+    "reserved exception 24",
+    "reserved exception 25",
+    "reserved exception 26",
+    "reserved exception 27",
+    "hypervisor injection",
+    "vmm communication",
+    "security exception",
+    "reserved exception 31",
+];
+
+/// Panics with the given message followed by a dump of the exception context, recording where the
+/// exception happened so that the backtrace starts at the faulting instruction rather than in the
+/// handler
+macro_rules! exception_panic {
+    ($context:expr, $($arg:tt)*) => {{
+        let context: &ExceptionContext = $context;
+        let rbp = if context.is_kernel() { context.rbp as usize } else { 0 };
+        backtrace::set_exception_frame(context.rip as usize, rbp);
+
+        panic!("{}\n{}", format_args!($($arg)*), context.dump());
+    }};
+}
+
+/// Called by the common exception entry with the state it saved, which is restored when this
+/// returns
+#[no_mangle]
+pub extern "C" fn exception_dispatch(context: &mut ExceptionContext) {
+    match context.vector {
+        DEBUG_VECTOR => debugger::debug(context),
+        BREAKPOINT_VECTOR => debugger::breakpoint(context),
+        PAGE_FAULT_VECTOR => page_fault(context),
+        vector if has_error_code(vector) => exception_panic!(
+            context,
+            "cpuex: {} 0x{:x}",
+            EXCEPTION_NAMES[vector as usize],
+            context.error_code,
+        ),
+        vector => exception_panic!(context, "cpuex: {}", EXCEPTION_NAMES[vector as usize]),
+    }
 }
 
-pub extern "x86-interrupt" fn breakpoint(stack_frame: &mut ExceptionStackFrame) {
-    panic!("cpuex: breakpoint\n{:#?}", stack_frame);
+/// Whether the CPU pushes an error code for the vector. Must match `HAS_ERROR_CODE` in
+/// `asm/exceptions.asm`.
+fn has_error_code(vector: u64) -> bool {
+    match vector {
+        8 | 10..=14 | 17 | 21 | 29 | 30 => true,
+        _ => false,
+    }
 }
 
-pub extern "x86-interrupt" fn overflow(stack_frame: &mut ExceptionStackFrame) {
-    panic!("cpuex: overflow\n{:#?}", stack_frame);
-}
+/// Resolves a page fault if it is on a guard page, a lazily mapped heap page or a copy on write
+/// page, and reports it otherwise. Runs on the page fault IST, which is only used for resolving.
+fn page_fault(context: &mut ExceptionContext) {
+    let error_code = PageFaultErrorCode::from_bits_truncate(context.error_code);
+    let cr2 = util::cr2() as usize;
 
-pub extern "x86-interrupt" fn out_of_bounds(stack_frame: &mut ExceptionStackFrame) {
-    panic!("cpuex: out of bounds\n{:#?}", stack_frame);
-}
+    if memory::is_boot_stack_guard_page(cr2) || thread::is_stack_guard_page(cr2) {
+        fatal_page_fault(FatalPageFault { context: context.clone(), cr2, stack_overflow: true });
+    }
 
-pub extern "x86-interrupt" fn invalid_opcode(stack_frame: &mut ExceptionStackFrame) {
-    panic!(
-        "cpuex: invalid opcode \n{:#?}\n => note: qword at {:?} is 0x{:x}",
-        stack_frame,
-        stack_frame.instruction_pointer,
-        unsafe { *(stack_frame.instruction_pointer.as_ptr::<u64>()) },
+    let kernel_not_present = !error_code.intersects(
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::USER_MODE
     );
-}
 
-pub extern "x86-interrupt" fn device_not_available(stack_frame: &mut ExceptionStackFrame) {
-    panic!("cpuex: device not available\n{:#?}", stack_frame);
-}
+    if kernel_not_present && demand_paging::handle_fault(cr2) {
+        return;
+    }
 
-pub extern "x86-interrupt" fn double_fault(stack_frame: &mut ExceptionStackFrame, code: u64) {
-    panic!("cpuex: double fault 0x{:x}\n{:#?}", code, stack_frame);
-}
+    let write_to_present = error_code.contains(
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE
+    );
 
-pub extern "x86-interrupt" fn invalid_tss(stack_frame: &mut ExceptionStackFrame, code: u64) {
-    panic!("cpuex: invalid tss 0x{:x}\n{:#?}", code, stack_frame);
-}
+    if write_to_present && address_space::handle_cow_fault(cr2) {
+        return;
+    }
 
-pub extern "x86-interrupt" fn segment_not_present(stack_frame: &mut ExceptionStackFrame, code: u64) {
-    panic!("cpuex: segment not present 0x{:x}\n{:#?}", code, stack_frame);
+    fatal_page_fault(FatalPageFault { context: context.clone(), cr2, stack_overflow: false });
 }
 
-pub extern "x86-interrupt" fn stack_segment_fault(stack_frame: &mut ExceptionStackFrame, code: u64) {
-    panic!("cpuex: stack segment fault 0x{:x}\n{:#?}", code, stack_frame);
+/// A page fault which cannot be resolved
+struct FatalPageFault {
+    context: ExceptionContext,
+    cr2: usize,
+    /// Whether the fault was on a kernel stack's guard page
+    stack_overflow: bool,
 }
 
-pub extern "x86-interrupt" fn general_protection_fault(stack_frame: &mut ExceptionStackFrame, code: u64) {
-    panic!("cpuex: general protection fault 0x{:x}\n{:#?}", code, stack_frame);
+/// Panics for a page fault which cannot be resolved. The page fault IST is small, and a fault while
+/// reporting would start over at its top and overwrite the report, so the panic happens on the
+/// panicking exception stack instead.
+fn fatal_page_fault(fault: FatalPageFault) -> ! {
+    let top = percpu::current().ist_top(gdt::PANICKING_EXCEPTION_IST_INDEX);
+    unsafe { util::call_on_stack(top, report_page_fault, &fault as *const FatalPageFault as usize) }
 }
 
-pub extern "x86-interrupt" fn page_fault(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
-    // WORKAROUND: https://github.com/rust-lang/rust/issues/57270
-    // Workaround code taken from https://github.com/phil-opp/blog_os/issues/513
-    unsafe{
-        asm!("sub rsp, 8
-              sub rbp, 8"::::"intel", "volatile");
-    }
+extern "C" fn report_page_fault(fault: usize) -> ! {
+    let fault = unsafe { &*(fault as *const FatalPageFault) };
 
-    let error_code = PageFaultErrorCode::from_bits_truncate(error_code).clone();
+    // Copy the fault off the page fault IST before anything can fault again
+    let (context, cr2) = (fault.context.clone(), fault.cr2);
+    let error_code = PageFaultErrorCode::from_bits_truncate(context.error_code);
 
-    unsafe{
-        asm!("add rsp, 8
-              add rbp, 8"::::"intel", "volatile");
+    if fault.stack_overflow {
+        exception_panic!(
+            &context,
+            "cpuex: kernel stack overflow in {}\n => note: CR2 = 0x{:x}",
+            sched::current_thread_name().unwrap_or("<unknown thread>"),
+            cr2,
+        );
     }
 
-    let cr2: u64;
-    unsafe { asm!("mov %cr2, $0" : "=r" (cr2)); }
-
-    panic!(
-        "cpuex: page fault (flags: {:?})\n{:#?}\n => note: CR2 = 0x{:x}\
+    exception_panic!(
+        &context,
+        "cpuex: page fault: {} (flags: {:?})\n => note: CR2 = {}\
     \n Check that this address is mapped correctly",
-        error_code, stack_frame, cr2
+        PageFaultDescription(error_code),
+        error_code,
+        PageWalk(cr2),
     );
 }
-
-pub extern "x86-interrupt" fn x87_floating_point(stack_frame: &mut ExceptionStackFrame) {
-    panic!("cpuex: x87 floating point\n{:#?}", stack_frame);
-}
-
-pub extern "x86-interrupt" fn alignment_check(stack_frame: &mut ExceptionStackFrame, code: u64) {
-    panic!("cpuex: alignment check 0x{:x}\n{:#?}", code, stack_frame);
-}
-
-pub extern "x86-interrupt" fn machine_check(stack_frame: &mut ExceptionStackFrame) {
-    panic!("cpuex: machine check\n{:#?}", stack_frame);
-}
-
-pub extern "x86-interrupt" fn simd_floating_point(stack_frame: &mut ExceptionStackFrame) {
-    panic!("cpuex: simd floating point\n{:#?}", stack_frame);
-}
-
-pub extern "x86-interrupt" fn virtualization(stack_frame: &mut ExceptionStackFrame) {
-    panic!("cpuex: virtualization\n{:#?}", stack_frame);
-}
-
-pub extern "x86-interrupt" fn security_exception(stack_frame: &mut ExceptionStackFrame, code: u64) {
-    panic!("cpuex: security exception 0x{:x}\n{:#?}", code, stack_frame);
-}
@@ -1,15 +1,24 @@
 //! Module for interrupt handling/IDT
 
-use x86_64::structures::idt::{InterruptDescriptorTable, ExceptionStackFrame, PageFaultErrorCode};
-use crate::interrupts::exceptions::page_fault;
+use core::mem;
+use x86_64::structures::idt::{InterruptDescriptorTable, ExceptionStackFrame};
 use crate::gdt;
 
 use alloc::vec::Vec;
 use spin::RwLock;
+use acpi::{Acpi, InterruptModel};
 use array_init;
 
 mod pic;
+pub mod apic;
+pub mod context;
 mod exceptions;
+mod debugger;
+
+extern "C" {
+    /// The entry stubs of the exception vectors, defined in `asm/exceptions.asm`
+    static exception_stubs: [usize; 32];
+}
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
@@ -37,6 +46,17 @@ pub fn dispatch_irq(irq: u8) {
     }
 }
 
+/// Dispatches an IRQ and acknowledges it on whichever interrupt controller is in use
+fn irq_received(irq: u8) {
+    match apic::APIC.wait() {
+        Some(apic) => {
+            dispatch_irq(irq);
+            apic.end_of_interrupt();
+        },
+        None => pic::CHAINED_PICS.lock().handle_interrupt(irq, || dispatch_irq(irq)),
+    }
+}
+
 #[repr(u8)]
 pub enum Irq {
     Pit = 0,
@@ -60,6 +80,48 @@ pub fn init() {
     info!("interupts: initialized");
 }
 
+/// Switches IRQ routing over to the local APIC and I/O APICs if the ACPI MADT describes them. The
+/// 8259 PICs are masked and left alone from then on. If there is no MADT, the PICs are kept.
+pub fn init_apic(acpi: Option<&Acpi>) {
+    let interrupt_model = acpi.and_then(|acpi| acpi.interrupt_model.as_ref());
+
+    if let Some(InterruptModel::Apic {
+        local_apic_address,
+        io_apics,
+        interrupt_source_overrides,
+        ..
+    }) = interrupt_model {
+        without_interrupts(|| {
+            let mut pics = pic::CHAINED_PICS.lock();
+            let masks = pics.masks();
+            pics.disable();
+
+            let apic = apic::APIC.call_once(|| unsafe {
+                apic::Apic::new(
+                    *local_apic_address as usize,
+                    io_apics,
+                    interrupt_source_overrides,
+                )
+            });
+
+            // Keep IRQs enabled if they were enabled on the PIC. IRQ 2 is the PIC cascade and is
+            // never raised.
+            for irq in (0..16).filter(|irq| *irq != 2) {
+                apic.route_isa_irq(irq, masks & (1 << irq) == 0);
+            }
+        });
+
+        info!("interrupts: routing irqs through the io apic");
+    } else {
+        info!("interrupts: no madt found, falling back to pic");
+    }
+}
+
+/// Loads the IDT on an application processor. The BSP loads it in [init].
+pub fn load_idt() {
+    IDT.load();
+}
+
 pub fn enable() {
     unsafe { asm!("sti" :::: "volatile"); }
 }
@@ -68,20 +130,50 @@ pub fn disable() {
     unsafe { asm!("cli" :::: "volatile"); }
 }
 
+/// Returns whether interrupts are enabled (the IF flag is set)
+pub fn enabled() -> bool {
+    let flags: u64;
+    unsafe { asm!("pushfq; popq $0" : "=r"(flags) ::: "volatile"); }
+    flags & (1 << 9) != 0
+}
+
+/// Runs the closure with interrupts disabled, restoring the previous state afterwards
+pub fn without_interrupts<F: FnOnce() -> R, R>(f: F) -> R {
+    let was_enabled = enabled();
+    disable();
+
+    let ret = f();
+
+    if was_enabled {
+        enable();
+    }
+
+    ret
+}
+
 pub fn enable_irq<I: Into<u8>>(irq: I) {
-    pic::CHAINED_PICS.lock().enable_line(irq.into());
+    match apic::APIC.wait() {
+        Some(apic) => apic.set_isa_irq_masked(irq.into(), false),
+        None => pic::CHAINED_PICS.lock().enable_line(irq.into()),
+    }
 }
 
 pub fn disable_irq<I: Into<u8>>(irq: I) {
-    pic::CHAINED_PICS.lock().disable_line(irq.into());
+    match apic::APIC.wait() {
+        Some(apic) => apic.set_isa_irq_masked(irq.into(), true),
+        None => pic::CHAINED_PICS.lock().disable_line(irq.into()),
+    }
 }
 
 macro_rules! init_irq_handlers {
     ($idt:expr, $($irq:expr),*) => {
         $(
             {
-                extern "x86-interrupt" fn handle_irq(_: &mut ExceptionStackFrame) {
-                    pic::CHAINED_PICS.lock().handle_interrupt($irq, || dispatch_irq($irq));
+                extern "x86-interrupt" fn handle_irq(frame: &mut ExceptionStackFrame) {
+                    swapgs_if_from_user(frame);
+                    irq_received($irq);
+                    crate::sched::preempt();
+                    swapgs_if_from_user(frame);
                 }
                 $idt[$irq + 32].set_handler_fn(handle_irq);
             }
@@ -89,53 +181,72 @@ macro_rules! init_irq_handlers {
     };
 }
 
+/// Runs `swapgs` if the interrupt came from user mode, which is told by the RPL of the saved CS.
+/// Handlers which may be entered from user mode call this first, so that GS points at the per-CPU
+/// block (see `smp::percpu::PerCpu`), and again last, to give the user its GS base back.
+#[inline(always)]
+fn swapgs_if_from_user(frame: &ExceptionStackFrame) {
+    if frame.code_segment & 3 != 0 {
+        unsafe { asm!("swapgs" :::: "volatile"); }
+    }
+}
+
+/// The entry stub of an exception vector, as whichever handler function type its IDT entry takes.
+/// The stubs do not use the handler's calling convention, so they must never be called directly.
+unsafe fn exception_stub<F>(vector: usize) -> F {
+    mem::transmute_copy(&exception_stubs[vector])
+}
+
 fn init_interrupt_handlers(idt: &mut InterruptDescriptorTable) {
     unsafe {
-        idt.divide_by_zero.set_handler_fn(exceptions::divide_by_zero)
+        idt.divide_by_zero.set_handler_fn(exception_stub(0))
             .set_stack_index(gdt::PANICKING_EXCEPTION_IST_INDEX);
-        idt.debug.set_handler_fn(exceptions::debug)
+        idt.debug.set_handler_fn(exception_stub(1))
             .set_stack_index(gdt::PANICKING_EXCEPTION_IST_INDEX);
-        idt.non_maskable_interrupt.set_handler_fn(exceptions::nmi)
+        idt.non_maskable_interrupt.set_handler_fn(exception_stub(2))
             .set_stack_index(gdt::PANICKING_EXCEPTION_IST_INDEX);
-        idt.breakpoint.set_handler_fn(exceptions::breakpoint)
+        idt.breakpoint.set_handler_fn(exception_stub(3))
             .set_stack_index(gdt::PANICKING_EXCEPTION_IST_INDEX);
-        idt.overflow.set_handler_fn(exceptions::overflow)
+        idt.overflow.set_handler_fn(exception_stub(4))
             .set_stack_index(gdt::PANICKING_EXCEPTION_IST_INDEX);
-        idt.bound_range_exceeded.set_handler_fn(exceptions::out_of_bounds)
+        idt.bound_range_exceeded.set_handler_fn(exception_stub(5))
             .set_stack_index(gdt::PANICKING_EXCEPTION_IST_INDEX);
-        idt.invalid_opcode.set_handler_fn(exceptions::invalid_opcode)
+        idt.invalid_opcode.set_handler_fn(exception_stub(6))
             .set_stack_index(gdt::PANICKING_EXCEPTION_IST_INDEX);
-        idt.device_not_available.set_handler_fn(exceptions::device_not_available)
+        idt.device_not_available.set_handler_fn(exception_stub(7))
             .set_stack_index(gdt::PANICKING_EXCEPTION_IST_INDEX);
-        idt.double_fault.set_handler_fn(exceptions::double_fault)
+        idt.double_fault.set_handler_fn(exception_stub(8))
             .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        idt.invalid_tss.set_handler_fn(exceptions::invalid_tss)
+        idt.invalid_tss.set_handler_fn(exception_stub(10))
             .set_stack_index(gdt::PANICKING_EXCEPTION_IST_INDEX);
-        idt.segment_not_present.set_handler_fn(exceptions::segment_not_present)
+        idt.segment_not_present.set_handler_fn(exception_stub(11))
             .set_stack_index(gdt::PANICKING_EXCEPTION_IST_INDEX);
-        idt.stack_segment_fault.set_handler_fn(exceptions::stack_segment_fault)
+        idt.stack_segment_fault.set_handler_fn(exception_stub(12))
             .set_stack_index(gdt::PANICKING_EXCEPTION_IST_INDEX);
-        idt.general_protection_fault.set_handler_fn(exceptions::general_protection_fault)
+        idt.general_protection_fault.set_handler_fn(exception_stub(13))
             .set_stack_index(gdt::PANICKING_EXCEPTION_IST_INDEX);
 
-        let page_fault: extern "x86-interrupt" fn(&mut ExceptionStackFrame, u64) = page_fault;
-        let page_fault: extern "x86-interrupt" fn(&mut ExceptionStackFrame, PageFaultErrorCode)
-            = core::mem::transmute(page_fault);
-        idt.page_fault.set_handler_fn(page_fault);
+        // Page faults get a stack of their own so that kernel stack overflows (faults on a guard
+        // page) can be reported rather than turning into double faults. Only resolving faults
+        // happens on it: faults which cannot be resolved are reported on the panicking exception
+        // stack, so that a fault while reporting cannot overwrite the report.
+        idt.page_fault.set_handler_fn(exception_stub(14))
+            .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
 
-        idt.x87_floating_point.set_handler_fn(exceptions::x87_floating_point)
+        idt.x87_floating_point.set_handler_fn(exception_stub(16))
             .set_stack_index(gdt::PANICKING_EXCEPTION_IST_INDEX);
-        idt.alignment_check.set_handler_fn(exceptions::alignment_check)
+        idt.alignment_check.set_handler_fn(exception_stub(17))
             .set_stack_index(gdt::PANICKING_EXCEPTION_IST_INDEX);
-        idt.machine_check.set_handler_fn(exceptions::machine_check)
+        idt.machine_check.set_handler_fn(exception_stub(18))
             .set_stack_index(gdt::PANICKING_EXCEPTION_IST_INDEX);
-        idt.simd_floating_point.set_handler_fn(exceptions::simd_floating_point)
+        idt.simd_floating_point.set_handler_fn(exception_stub(19))
             .set_stack_index(gdt::PANICKING_EXCEPTION_IST_INDEX);
-        idt.virtualization.set_handler_fn(exceptions::virtualization)
+        idt.virtualization.set_handler_fn(exception_stub(20))
             .set_stack_index(gdt::PANICKING_EXCEPTION_IST_INDEX);
-        idt.security_exception.set_handler_fn(exceptions::security_exception)
+        idt.security_exception.set_handler_fn(exception_stub(30))
             .set_stack_index(gdt::PANICKING_EXCEPTION_IST_INDEX);
     }
 
     init_irq_handlers!(idt, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+    idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(apic::spurious);
 }
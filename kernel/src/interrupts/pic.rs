@@ -135,7 +135,12 @@ impl ChainedPics {
         self.slave.write_data(0xFF);
     }
 
-    pub fn handle_interrupt(&mut self, irq: u8, handler: fn()) {
+    /// Returns the masks of both PICs, with the master's in the low byte
+    pub fn masks(&self) -> u16 {
+        (self.slave.read_data() as u16) << 8 | self.master.read_data() as u16
+    }
+
+    pub fn handle_interrupt<F: FnOnce()>(&mut self, irq: u8, handler: F) {
         match self.destination(irq) {
             IrqDestination::Master(_) => {
                 if !self.master.is_spurious(irq) {
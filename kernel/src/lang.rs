@@ -9,6 +9,7 @@ use crate::drivers::vga::VgaWriter;
 use spin::RwLock;
 use crate::terminal::{Stdout, TerminalOutput};
 use crate::serial::{self, SerialPort};
+use crate::backtrace::Backtrace;
 
 // A note on the `#[no_mangle]`s:
 // Apparently, removing them makes it link-error with undefined symbols, so we include them
@@ -19,11 +20,12 @@ extern fn eh_personality() {}
 
 #[panic_handler]
 #[no_mangle]
-// TODO backtrace
 extern fn panic_fmt(info: &PanicInfo) -> ! {
+    let backtrace = Backtrace::capture();
     let vga_writer = RwLock::new(VgaWriter::new());
     let mut writer = Stdout(&vga_writer);
     let mut serial = unsafe { SerialPort::new(serial::PORT_1_ADDR) };
+    let serial_ready = serial.init(serial::MAX_BAUD, false).is_ok();
 
     // Ignore the errors because we can't afford to panic in the panic handler
     let _ = writer.set_color(ColorPair::new(Color::Red, Color::Black));
@@ -41,7 +43,7 @@ extern fn panic_fmt(info: &PanicInfo) -> ! {
             line = loc.line()
         );
 
-        if let Ok(_) = serial.init(serial::MAX_BAUD, false) {
+        if serial_ready {
             let _ = write!(
                 &mut serial,
                 "Panicked at \"{}\", {file}:{line}\n",
@@ -51,13 +53,19 @@ extern fn panic_fmt(info: &PanicInfo) -> ! {
             );
         }
     } else {
-        let _ = write!(&mut writer, "Panicked at \"{}\" at an undefined location", arguments);
+        let _ = write!(&mut writer, "Panicked at \"{}\" at an undefined location\n", arguments);
 
-        if let Ok(_) = serial.init(serial::MAX_BAUD, false) {
-            let _ = write!(&mut serial, "Panicked at \"{}\" at an undefined location", arguments);
+        if serial_ready {
+            let _ = write!(&mut serial, "Panicked at \"{}\" at an undefined location\n", arguments);
         }
     }
 
+    let _ = write!(&mut writer, "{}", backtrace);
+
+    if serial_ready {
+        let _ = write!(&mut serial, "{}", backtrace);
+    }
+
     halt()
 }
 
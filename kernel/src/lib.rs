@@ -55,11 +55,18 @@ mod terminal;
 mod io;
 mod interrupts;
 mod memory;
+mod backtrace;
 mod drivers;
 mod acpi_impl;
 mod gdt;
 mod cpuid;
 mod snake;
+mod sched;
+mod smp;
+mod syscall;
+mod usermode;
+mod elf;
+mod loader;
 
 use crate::memory::heap::Heap;
 
@@ -74,16 +81,34 @@ pub extern fn kmain(multiboot_info_addr: usize, guard_page_addr: usize) -> ! {
     info!("serial: initialized port 1");
     log::init();
     memory::init_memory(multiboot_info_addr, guard_page_addr);
-    gdt::init();
+    smp::init_bsp();
+    syscall::init();
     interrupts::init();
+    unsafe { HEAP.enable_demand_paging(); }
     interrupts::enable();
     info!("interrupts: ready");
 
     drivers::pit::CONTROLLER.lock().initialize();
+    sched::init();
+
+    let acpi = acpi_impl::acpi_init().ok();
+    memory::numa::init();
+    interrupts::init_apic(acpi.as_ref());
+    smp::boot_aps(acpi.as_ref());
+    unsafe { memory::reclaim::reclaim_boot_memory(); }
+    memory::stats::print_memory_stats();
+
+    sched::spawn("snake", run_snake);
+    #[cfg(feature = "user_test")]
+    usermode::test_program::spawn();
+    loader::launch_modules();
+
+    // The boot thread has nothing left to do, so it becomes the idle thread
+    sched::idle()
+}
 
-    let _acpi = acpi_impl::acpi_init();
-
-    // Initialize the PS/2 controller and run the keyboard echo loop
+/// Initialize the PS/2 controller and run snake
+fn run_snake() {
     let mut controller = ps2::CONTROLLER.lock();
     match controller.initialize() {
         Ok(_) => info!("ps2c: init successful"),
@@ -91,8 +116,6 @@ pub extern fn kmain(multiboot_info_addr: usize, guard_page_addr: usize) -> ! {
     }
 
     snake::snake(&mut controller);
-
-    halt()
 }
 
 /// Say hello to the user and print flower
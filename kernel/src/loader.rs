@@ -0,0 +1,110 @@
+//! Loading and launching static ELF64 executables passed by the bootloader as multiboot2 modules.
+//! Each one runs in ring 3 on its own thread, in its own address space.
+
+use core::slice;
+use crate::memory::{self, BootModule, physical_mapping};
+use crate::memory::paging::EntryFlags;
+use crate::memory::address_space::{AddressSpace, AddressSpaceError};
+use crate::elf::{Elf, ElfError, SegmentFlags};
+use crate::syscall::USER_SPACE_END;
+use crate::{sched, usermode, util};
+
+/// The top of the stack of every user program
+const USER_STACK_TOP: usize = 0x7fff_ffff_f000;
+/// Size of user program stacks in 4kib pages
+const USER_STACK_PAGES: usize = 16;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LoadError {
+    Elf(ElfError),
+    /// A segment or the entry point does not lie in user space
+    OutsideUserSpace,
+    /// A segment could not be mapped, e.g because it shares a page with another segment
+    AddressSpace(AddressSpaceError),
+}
+
+impl From<AddressSpaceError> for LoadError {
+    fn from(error: AddressSpaceError) -> Self {
+        LoadError::AddressSpace(error)
+    }
+}
+
+/// Spawns a thread for every boot module which loads and runs it in user mode.
+pub fn launch_modules() {
+    for module in memory::boot_modules() {
+        let module = module.clone();
+        sched::spawn("module", move || run_module(module));
+    }
+}
+
+fn run_module(module: BootModule) {
+    info!("loader: launching \"{}\"", module.name);
+
+    let loaded = {
+        let size = module.physical.end - module.physical.start;
+        let mapping = unsafe {
+            physical_mapping::map_physical_region::<u8>(module.physical.start, size, false)
+        };
+        let data = unsafe { slice::from_raw_parts(&*mapping as *const u8, size) };
+
+        load(data)
+    };
+
+    match loaded {
+        Ok((address_space, entry)) => {
+            sched::set_address_space(address_space);
+            unsafe { usermode::enter(entry, USER_STACK_TOP) }
+        },
+        Err(error) => error!("loader: could not load \"{}\": {:?}", module.name, error),
+    }
+}
+
+/// Loads an executable into a new address space, along with a stack. Returns the address space
+/// and the entry point.
+pub fn load(data: &[u8]) -> Result<(AddressSpace, usize), LoadError> {
+    let elf = Elf::parse(data).map_err(LoadError::Elf)?;
+
+    if elf.entry_point() >= USER_SPACE_END {
+        return Err(LoadError::OutsideUserSpace);
+    }
+
+    let mut address_space = AddressSpace::new()?;
+
+    for segment in elf.segments() {
+        if segment.memory_size == 0 {
+            continue;
+        }
+
+        let start = segment.virtual_address & !0xFFF;
+        let end = segment.virtual_address.checked_add(segment.memory_size)
+            .ok_or(LoadError::OutsideUserSpace)?;
+
+        if end > USER_SPACE_END {
+            return Err(LoadError::OutsideUserSpace);
+        }
+
+        let end = util::round_up_divide(end as u64, 4096) as usize * 4096;
+
+        let mut flags = EntryFlags::empty();
+
+        if segment.flags.contains(SegmentFlags::WRITABLE) {
+            flags |= EntryFlags::WRITABLE;
+        }
+
+        if !segment.flags.contains(SegmentFlags::EXECUTABLE) {
+            flags |= EntryFlags::NO_EXECUTE;
+        }
+
+        address_space.map_anonymous(start, end - start, flags)?;
+        address_space.write(segment.virtual_address, segment.data)?;
+    }
+
+    let stack_size = USER_STACK_PAGES * 4096;
+    address_space.map_anonymous(
+        USER_STACK_TOP - stack_size,
+        stack_size,
+        EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+    )?;
+
+    Ok((address_space, elf.entry_point()))
+}
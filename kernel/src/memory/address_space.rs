@@ -0,0 +1,521 @@
+//! User address spaces. An [AddressSpace] owns a P4 table whose kernel half is shared with every
+//! other page map, and keeps track of the virtual memory areas mapped in its lower half. Anonymous
+//! memory is shared copy on write with address spaces forked from it (see [AddressSpace::fork]).
+
+use core::{cmp, ptr};
+use core::ops::Range;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use crate::interrupts;
+use crate::syscall::USER_SPACE_END;
+use super::physical_allocator::PHYSICAL_ALLOCATOR;
+use super::{frame_refcount, physical_mapping};
+use super::paging::{self, PAGE_TABLES, Page, PageSize, PhysicalAddress, EntryFlags,
+                    InvalidateTlb, FreeMemory, InactivePageMap, Mapper, PageTableEntry};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AddressSpaceError {
+    /// The address or length is not page aligned
+    Unaligned,
+    /// The range does not lie in user space
+    OutsideUserSpace,
+    /// The range overlaps an existing area
+    Overlapping,
+    /// Part of the range is not mapped
+    NotMapped,
+    OutOfMemory,
+}
+
+/// What an area's pages are mapped to
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Backing {
+    /// Zeroed frames owned by the address space, freed on unmap
+    Anonymous,
+    /// A contiguous range of physical memory beginning at the given address, which is not freed
+    Physical(PhysicalAddress),
+}
+
+/// A contiguous, page aligned range of virtual memory with the same flags and backing
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct VirtualMemoryArea {
+    pub start: usize,
+    pub length: usize,
+    pub flags: EntryFlags,
+    pub backing: Backing,
+}
+
+impl VirtualMemoryArea {
+    pub fn end(&self) -> usize {
+        self.start + self.length
+    }
+
+    /// Returns the part of this area which lies in the range, if any
+    fn slice(&self, range: &Range<usize>) -> Option<VirtualMemoryArea> {
+        let start = cmp::max(self.start, range.start);
+        let end = cmp::min(self.end(), range.end);
+
+        if start >= end {
+            return None;
+        }
+
+        let backing = match self.backing {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::Physical(base) => Backing::Physical(PhysicalAddress(base.0 + start - self.start)),
+        };
+
+        Some(VirtualMemoryArea { start, length: end - start, flags: self.flags, backing })
+    }
+}
+
+pub struct AddressSpace {
+    page_map: InactivePageMap,
+    /// Areas keyed by their start address
+    areas: BTreeMap<usize, VirtualMemoryArea>,
+}
+
+impl AddressSpace {
+    /// Creates an empty address space
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        let frame = PHYSICAL_ALLOCATOR.allocate(0).ok_or(AddressSpaceError::OutOfMemory)?;
+
+        let page_map = with_page_tables(|tables| {
+            InactivePageMap::new_user(PhysicalAddress(frame as usize), tables)
+        });
+
+        Ok(AddressSpace { page_map, areas: BTreeMap::new() })
+    }
+
+    /// The physical address of the P4 table, to be loaded into CR3
+    pub fn p4_frame(&self) -> PhysicalAddress {
+        self.page_map.p4_frame()
+    }
+
+    pub fn areas(&self) -> impl Iterator<Item = &VirtualMemoryArea> {
+        self.areas.values()
+    }
+
+    /// Finds the area containing the given address
+    pub fn area_containing(&self, address: usize) -> Option<&VirtualMemoryArea> {
+        self.areas.range(..=address)
+            .next_back()
+            .map(|(_, area)| area)
+            .filter(|area| address < area.end())
+    }
+
+    /// Maps zeroed memory at the given page aligned range
+    pub fn map_anonymous(
+        &mut self,
+        start: usize,
+        length: usize,
+        flags: EntryFlags,
+    ) -> Result<(), AddressSpaceError> {
+        self.check_free(start, length)?;
+
+        let mut frames = Vec::with_capacity(length / 4096);
+
+        for _ in 0..length / 4096 {
+            match zeroed_frame() {
+                Some(frame) => frames.push(frame),
+                None => {
+                    for frame in frames {
+                        PHYSICAL_ALLOCATOR.deallocate(frame.0 as *const u8, 0);
+                    }
+
+                    return Err(AddressSpaceError::OutOfMemory);
+                },
+            }
+        }
+
+        self.with_mapper(|mapper| {
+            for (i, frame) in frames.iter().enumerate() {
+                let page = Page::containing_address(start + i * 4096, PageSize::Kib4);
+                unsafe { mapper.map_to(page, *frame, user_flags(flags), InvalidateTlb::NoInvalidate) };
+            }
+        });
+
+        self.areas.insert(start, VirtualMemoryArea { start, length, flags, backing: Backing::Anonymous });
+        Ok(())
+    }
+
+    /// Creates a copy of this address space, as for `fork`. Anonymous memory is shared copy on
+    /// write: its pages are made read only in both address spaces, and the first write to a page
+    /// from either side gives that side a copy of its own. Physical memory is shared as it is.
+    ///
+    /// The child is built completely before this address space is changed, so if that fails, the
+    /// child is dropped (releasing the references it took) and this address space is left as it
+    /// was.
+    pub fn fork(&mut self) -> Result<AddressSpace, AddressSpaceError> {
+        let mut child = AddressSpace::new()?;
+        let areas: Vec<VirtualMemoryArea> = self.areas.values().cloned().collect();
+
+        for area in areas.iter() {
+            if let Backing::Physical(physical_start) = area.backing {
+                unsafe { child.map_physical(area.start, physical_start, area.length, area.flags)? };
+                continue;
+            }
+
+            let frames: Vec<PhysicalAddress> = self.with_mapper(|mapper| {
+                (area.start..area.end()).step_by(4096)
+                    .map(|address| mapper.translate(address).expect("Area is not mapped!"))
+                    .collect()
+            });
+
+            let flags = shared_flags(area.flags);
+
+            // The child holds a reference to every frame as soon as it is mapped there, so that
+            // dropping the child on failure releases exactly the references it took
+            child.with_mapper(|mapper| {
+                for (i, frame) in frames.iter().enumerate() {
+                    let page = Page::containing_address(area.start + i * 4096, PageSize::Kib4);
+                    frame_refcount::share(*frame);
+                    unsafe { mapper.map_to(page, *frame, flags, InvalidateTlb::NoInvalidate) };
+                }
+            });
+
+            child.areas.insert(area.start, *area);
+        }
+
+        for area in areas.iter().filter(|area| area.backing == Backing::Anonymous) {
+            let flags = shared_flags(area.flags);
+
+            self.with_mapper(|mapper| {
+                for address in (area.start..area.end()).step_by(4096) {
+                    let page = Page::containing_address(address, PageSize::Kib4);
+                    unsafe { mapper.set_flags(page, flags, InvalidateTlb::NoInvalidate) };
+                }
+            });
+        }
+
+        Ok(child)
+    }
+
+    /// Maps a range of physical memory, which is not freed when unmapped
+    ///
+    /// # Unsafety
+    ///
+    /// Unsafe as this gives user mode access to arbitrary physical memory.
+    pub unsafe fn map_physical(
+        &mut self,
+        start: usize,
+        physical_start: PhysicalAddress,
+        length: usize,
+        flags: EntryFlags,
+    ) -> Result<(), AddressSpaceError> {
+        if physical_start.0 % 4096 != 0 {
+            return Err(AddressSpaceError::Unaligned);
+        }
+
+        self.check_free(start, length)?;
+
+        self.with_mapper(|mapper| {
+            for offset in (0..length).step_by(4096) {
+                let page = Page::containing_address(start + offset, PageSize::Kib4);
+                let frame = PhysicalAddress(physical_start.0 + offset);
+                mapper.map_to(page, frame, user_flags(flags), InvalidateTlb::NoInvalidate);
+            }
+        });
+
+        let area = VirtualMemoryArea { start, length, flags, backing: Backing::Physical(physical_start) };
+        self.areas.insert(start, area);
+        Ok(())
+    }
+
+    /// Unmaps the given page aligned range, splitting areas which only partly lie in it. Parts of
+    /// the range which are not mapped are ignored.
+    pub fn unmap(&mut self, start: usize, length: usize) -> Result<(), AddressSpaceError> {
+        let range = check_range(start, length)?;
+        let removed = self.split_out(&range);
+
+        self.with_mapper(|mapper| {
+            for area in removed.iter() {
+                if area.backing != Backing::Anonymous {
+                    let (range, free) = (area.start..area.end(), FreeMemory::NoFree);
+                    unsafe { mapper.unmap_range(range, free, InvalidateTlb::NoInvalidate) };
+                    continue;
+                }
+
+                // Frames may be shared with other address spaces, so they are only freed by their
+                // last user
+                for address in (area.start..area.end()).step_by(4096) {
+                    let page = Page::containing_address(address, PageSize::Kib4);
+                    let frame = mapper.translate(address).expect("Area is not mapped!");
+
+                    unsafe { mapper.unmap(page, FreeMemory::NoFree, InvalidateTlb::NoInvalidate) };
+                    frame_refcount::release(frame);
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Changes the flags of the given page aligned range, which must be entirely mapped
+    pub fn protect(
+        &mut self,
+        start: usize,
+        length: usize,
+        flags: EntryFlags,
+    ) -> Result<(), AddressSpaceError> {
+        let range = check_range(start, length)?;
+
+        if !self.is_mapped(&range) {
+            return Err(AddressSpaceError::NotMapped);
+        }
+
+        let mut changed = self.split_out(&range);
+
+        self.with_mapper(|mapper| {
+            for area in changed.iter() {
+                for address in (area.start..area.end()).step_by(4096) {
+                    let page = Page::containing_address(address, PageSize::Kib4);
+                    let frame = mapper.translate(address).expect("Area is not mapped!");
+
+                    // Shared frames must stay copy on write
+                    let shared = frame_refcount::count(frame) > 1;
+                    let flags = match area.backing {
+                        Backing::Anonymous if shared => shared_flags(flags),
+                        _ => user_flags(flags),
+                    };
+
+                    unsafe { mapper.set_flags(page, flags, InvalidateTlb::NoInvalidate) };
+                }
+            }
+        });
+
+        for area in changed.iter_mut() {
+            area.flags = flags;
+            self.areas.insert(area.start, *area);
+        }
+
+        Ok(())
+    }
+
+    /// Copies data into the address space, regardless of the flags of the pages. The range must be
+    /// entirely mapped.
+    pub fn write(&mut self, address: usize, data: &[u8]) -> Result<(), AddressSpaceError> {
+        let end = address.checked_add(data.len()).ok_or(AddressSpaceError::OutsideUserSpace)?;
+        let first_page = address / 4096;
+        let last_page = (end + 4095) / 4096;
+
+        if !self.is_mapped(&(first_page * 4096..last_page * 4096)) {
+            return Err(AddressSpaceError::NotMapped);
+        }
+
+        // Pages shared copy on write are copied first, so that other address spaces do not see
+        // the data
+        let frames: Option<Vec<PhysicalAddress>> = self.with_mapper(|mapper| {
+            (first_page..last_page)
+                .map(|number| {
+                    let page = Page::containing_address(number * 4096, PageSize::Kib4);
+                    let entry = mapper.walk_page_table(page).expect("Area is not mapped!").0;
+
+                    if entry.flags().contains(EntryFlags::COPY_ON_WRITE) {
+                        unsafe { copy_on_write(mapper, page, entry) }
+                    } else {
+                        entry.physical_address()
+                    }
+                })
+                .collect()
+        });
+
+        let frames = frames.ok_or(AddressSpaceError::OutOfMemory)?;
+
+        for (i, frame) in frames.into_iter().enumerate() {
+            let page_start = (first_page + i) * 4096;
+            let copy_start = cmp::max(page_start, address);
+            let copy_end = cmp::min(page_start + 4096, end);
+
+            if copy_start >= copy_end {
+                continue;
+            }
+
+            unsafe {
+                let mut mapping = physical_mapping::map_physical_region::<[u8; 4096]>(
+                    frame.0,
+                    4096,
+                    true,
+                );
+
+                let bytes = mapping.deref_mut().unwrap();
+                bytes[copy_start - page_start..copy_end - page_start]
+                    .copy_from_slice(&data[copy_start - address..copy_end - address]);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the range is valid and does not overlap any areas
+    fn check_free(&self, start: usize, length: usize) -> Result<(), AddressSpaceError> {
+        let range = check_range(start, length)?;
+
+        if self.areas.values().any(|area| area.slice(&range).is_some()) {
+            Err(AddressSpaceError::Overlapping)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether the range is entirely covered by areas
+    fn is_mapped(&self, range: &Range<usize>) -> bool {
+        let mut covered = range.start;
+
+        for area in self.areas.values().filter_map(|area| area.slice(range)) {
+            if area.start != covered {
+                return false;
+            }
+
+            covered = area.end();
+        }
+
+        covered >= range.end
+    }
+
+    /// Removes the parts of areas which lie in the range and returns them. Parts which lie outside
+    /// of the range are kept as separate areas.
+    fn split_out(&mut self, range: &Range<usize>) -> Vec<VirtualMemoryArea> {
+        let overlapping: Vec<VirtualMemoryArea> = self.areas.values()
+            .filter(|area| area.slice(range).is_some())
+            .cloned()
+            .collect();
+
+        let mut removed = Vec::with_capacity(overlapping.len());
+
+        for area in overlapping {
+            self.areas.remove(&area.start);
+
+            let before = area.slice(&(area.start..range.start));
+            let after = area.slice(&(range.end..area.end()));
+
+            for remaining in before.iter().chain(after.iter()) {
+                self.areas.insert(remaining.start, *remaining);
+            }
+
+            removed.push(area.slice(range).unwrap());
+        }
+
+        removed
+    }
+
+    /// Runs the closure with the mapper pointed at this address space's page map
+    fn with_mapper<F: FnOnce(&mut Mapper) -> R, R>(&mut self, f: F) -> R {
+        let page_map = &mut self.page_map;
+
+        with_page_tables(|tables| tables.with_inactive_p4(page_map, |mapper| f(mapper)))
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let areas: Vec<VirtualMemoryArea> = self.areas.values().cloned().collect();
+
+        self.with_mapper(|mapper| {
+            for area in areas {
+                if area.backing != Backing::Anonymous {
+                    continue;
+                }
+
+                for address in (area.start..area.end()).step_by(4096) {
+                    let frame = mapper.translate(address).unwrap();
+                    frame_refcount::release(frame);
+                }
+            }
+
+            unsafe { mapper.free_lower_half_tables() };
+        });
+
+        PHYSICAL_ALLOCATOR.deallocate(self.page_map.p4_frame().0 as *const u8, 0);
+        paging::count_page_table_frame(false);
+    }
+}
+
+fn check_range(start: usize, length: usize) -> Result<Range<usize>, AddressSpaceError> {
+    if start % 4096 != 0 || length % 4096 != 0 {
+        return Err(AddressSpaceError::Unaligned);
+    }
+
+    match start.checked_add(length) {
+        Some(end) if end <= USER_SPACE_END => Ok(start..end),
+        _ => Err(AddressSpaceError::OutsideUserSpace),
+    }
+}
+
+fn user_flags(flags: EntryFlags) -> EntryFlags {
+    flags | EntryFlags::USER_ACCESSIBLE
+}
+
+/// The flags of a page whose frame is shared with other address spaces
+fn shared_flags(flags: EntryFlags) -> EntryFlags {
+    if flags.contains(EntryFlags::WRITABLE) {
+        user_flags(flags - EntryFlags::WRITABLE) | EntryFlags::COPY_ON_WRITE
+    } else {
+        user_flags(flags)
+    }
+}
+
+/// Tries to resolve a page fault caused by writing to a copy on write page of the current address
+/// space. Returns whether it was resolved.
+pub fn handle_cow_fault(address: usize) -> bool {
+    if address >= USER_SPACE_END {
+        return false;
+    }
+
+    let page = Page::containing_address(address, PageSize::Kib4);
+
+    interrupts::without_interrupts(|| {
+        let mut tables = PAGE_TABLES.lock();
+
+        match tables.walk_page_table(page) {
+            Some((entry, PageSize::Kib4)) if entry.flags().contains(EntryFlags::COPY_ON_WRITE) => {
+                unsafe { copy_on_write(&mut tables, page, entry).is_some() }
+            },
+            _ => false,
+        }
+    })
+}
+
+/// Makes a copy on write page writable, copying its frame unless this is the last reference to
+/// it. Returns the frame that the page is now mapped to, or `None` if out of memory.
+unsafe fn copy_on_write(
+    mapper: &mut Mapper,
+    page: Page,
+    entry: PageTableEntry,
+) -> Option<PhysicalAddress> {
+    let frame = entry.physical_address().unwrap();
+    let flags = (entry.flags() - EntryFlags::COPY_ON_WRITE) | EntryFlags::WRITABLE;
+
+    // Every other address space has already copied or unmapped it
+    if frame_refcount::count(frame) == 1 {
+        mapper.set_flags(page, flags, InvalidateTlb::Invalidate);
+        return Some(frame);
+    }
+
+    let copy = PhysicalAddress(PHYSICAL_ALLOCATOR.allocate(0)? as usize);
+    ptr::copy_nonoverlapping(
+        super::phys_to_virt(frame).0 as *const u8,
+        super::phys_to_virt(copy).0 as *mut u8,
+        4096,
+    );
+
+    mapper.map_to(page, copy, flags, InvalidateTlb::Invalidate);
+    frame_refcount::release(frame);
+
+    Some(copy)
+}
+
+fn zeroed_frame() -> Option<PhysicalAddress> {
+    let frame = PhysicalAddress(PHYSICAL_ALLOCATOR.allocate(0)? as usize);
+
+    unsafe {
+        ptr::write_bytes(super::phys_to_virt(frame).0 as *mut u8, 0, 4096);
+    }
+
+    Some(frame)
+}
+
+/// Runs the closure with the page tables locked, with interrupts disabled
+fn with_page_tables<F, R>(f: F) -> R
+    where F: FnOnce(&mut super::paging::ActivePageMap) -> R
+{
+    interrupts::without_interrupts(|| f(&mut PAGE_TABLES.lock()))
+}
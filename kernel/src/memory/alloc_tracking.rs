@@ -0,0 +1,369 @@
+//! Allocation tracking, enabled by the `alloc_tracking` feature. Every live heap allocation is
+//! recorded along with its size, the time it was made at and the return addresses of its call
+//! site, so that [report] can show where leaked memory comes from. Call [report] wherever a leak
+//! is suspected, e.g after a loop which should free everything it allocates, or use the debugger's
+//! `leaks` command.
+//!
+//! Records are kept in a fixed size table so that tracking never allocates itself. Allocations
+//! made while the table is full are not tracked.
+
+use core::{fmt, alloc::Layout};
+use arrayvec::ArrayVec;
+use spin::Mutex;
+use crate::backtrace::{self, Demangle, Frames};
+use crate::drivers::pit;
+use crate::interrupts;
+
+/// Number of return addresses recorded per call site
+const CALL_SITE_DEPTH: usize = 6;
+/// Frames skipped when recording a call site: those of [record_alloc] and `Heap::alloc`
+const SKIP_FRAMES: usize = 2;
+/// Maximum number of tracked live allocations. Must be a power of two.
+const MAX_TRACKED: usize = 4096;
+/// Maximum number of distinct call sites shown in a report
+const MAX_REPORTED_SITES: usize = 64;
+
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker::new());
+
+type CallSite = [usize; CALL_SITE_DEPTH];
+
+#[derive(Copy, Clone)]
+struct Record {
+    ptr: usize,
+    size: usize,
+    time_ms: usize,
+    call_site: CallSite,
+}
+
+#[derive(Copy, Clone)]
+enum Slot {
+    Empty,
+    Used(Record),
+}
+
+struct Tracker {
+    slots: [Slot; MAX_TRACKED],
+    live: usize,
+    untracked: usize,
+}
+
+impl Tracker {
+    const fn new() -> Self {
+        Tracker { slots: [Slot::Empty; MAX_TRACKED], live: 0, untracked: 0 }
+    }
+
+    fn insert(&mut self, record: Record) {
+        if self.live == MAX_TRACKED {
+            self.untracked += 1;
+            return;
+        }
+
+        for i in probe(record.ptr) {
+            if let Slot::Empty = self.slots[i] {
+                self.slots[i] = Slot::Used(record);
+                self.live += 1;
+                return;
+            }
+        }
+    }
+
+    /// Returns the slot of the record of a pointer, if it is tracked
+    fn find(&self, ptr: usize) -> Option<usize> {
+        for i in probe(ptr) {
+            match self.slots[i] {
+                Slot::Empty => return None,
+                Slot::Used(record) if record.ptr == ptr => return Some(i),
+                Slot::Used(_) => (),
+            }
+        }
+
+        None
+    }
+
+    /// Removes the record of a pointer. The records after it which were probed past it are moved
+    /// back to fill the gap, so that removed records never have to be probed past.
+    fn remove(&mut self, ptr: usize) {
+        let mut gap = match self.find(ptr) {
+            Some(gap) => gap,
+            None => return, // Allocated while the table was full
+        };
+
+        self.live -= 1;
+        let mut next = gap;
+
+        for _ in 1..MAX_TRACKED {
+            next = (next + 1) & (MAX_TRACKED - 1);
+
+            let record = match self.slots[next] {
+                Slot::Empty => break,
+                Slot::Used(record) => record,
+            };
+
+            // The record may only move back as far as its first slot
+            if distance(home(record.ptr), next) >= distance(gap, next) {
+                self.slots[gap] = Slot::Used(record);
+                gap = next;
+            }
+        }
+
+        self.slots[gap] = Slot::Empty;
+    }
+
+    fn records(&self) -> impl Iterator<Item = &Record> {
+        self.slots.iter().filter_map(|slot| match slot {
+            Slot::Used(record) => Some(record),
+            _ => None,
+        })
+    }
+}
+
+/// The first slot to look at for a pointer
+fn home(ptr: usize) -> usize {
+    // Allocations are at least 16 byte aligned, so the low bits carry no information
+    let bits = MAX_TRACKED.trailing_zeros();
+    (ptr >> 4).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - bits)
+}
+
+/// The slots to look at for a pointer, in order
+fn probe(ptr: usize) -> impl Iterator<Item = usize> {
+    let start = home(ptr);
+    (0..MAX_TRACKED).map(move |i| (start + i) & (MAX_TRACKED - 1))
+}
+
+/// How many slots there are from one slot forward to another, wrapping around the table
+fn distance(from: usize, to: usize) -> usize {
+    to.wrapping_sub(from) & (MAX_TRACKED - 1)
+}
+
+/// Records an allocation made by the caller's caller.
+#[inline(never)]
+pub fn record_alloc(ptr: *mut u8, layout: Layout) {
+    if ptr.is_null() {
+        return;
+    }
+
+    let record = Record {
+        ptr: ptr as usize,
+        size: layout.size(),
+        time_ms: pit::time_ms(),
+        call_site: call_site(),
+    };
+
+    interrupts::without_interrupts(|| TRACKER.lock().insert(record));
+}
+
+pub fn record_dealloc(ptr: *mut u8) {
+    interrupts::without_interrupts(|| TRACKER.lock().remove(ptr as usize));
+}
+
+/// The return addresses of the current call stack. Only those found before reaching a frame which
+/// cannot be told to be mapped are recorded, e.g none while the page tables are locked.
+#[inline(always)]
+fn call_site() -> CallSite {
+    let mut call_site = [0; CALL_SITE_DEPTH];
+    let frames = unsafe { Frames::new(backtrace::current_rbp()) };
+
+    for (slot, address) in call_site.iter_mut().zip(frames.skip(SKIP_FRAMES)) {
+        *slot = address;
+    }
+
+    call_site
+}
+
+#[derive(Copy, Clone)]
+struct SiteSummary {
+    call_site: CallSite,
+    count: usize,
+    bytes: usize,
+    oldest_ms: usize,
+}
+
+/// The live allocations, grouped by call site and sorted by bytes allocated
+pub struct Report {
+    sites: ArrayVec<[SiteSummary; MAX_REPORTED_SITES]>,
+    /// The allocations from call sites which did not fit in `sites`
+    other: SiteSummary,
+    live: usize,
+    untracked: usize,
+    time_ms: usize,
+}
+
+impl Report {
+    fn new(tracker: &Tracker) -> Self {
+        let mut sites: ArrayVec<[SiteSummary; MAX_REPORTED_SITES]> = ArrayVec::new();
+        let mut other = SiteSummary {
+            call_site: [0; CALL_SITE_DEPTH],
+            count: 0,
+            bytes: 0,
+            oldest_ms: 0,
+        };
+
+        for record in tracker.records() {
+            let index = sites.iter().position(|site| site.call_site == record.call_site);
+
+            let site = match index {
+                Some(index) => &mut sites[index],
+                None => {
+                    let new = SiteSummary {
+                        call_site: record.call_site,
+                        count: 0,
+                        bytes: 0,
+                        oldest_ms: record.time_ms,
+                    };
+
+                    match sites.try_push(new) {
+                        Ok(()) => sites.last_mut().unwrap(),
+                        Err(_) => &mut other,
+                    }
+                },
+            };
+
+            site.count += 1;
+            site.bytes += record.size;
+            site.oldest_ms = site.oldest_ms.min(record.time_ms);
+        }
+
+        sites.sort_unstable_by(|a, b| b.bytes.cmp(&a.bytes));
+
+        Report {
+            sites,
+            other,
+            live: tracker.live,
+            untracked: tracker.untracked,
+            time_ms: pit::time_ms(),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "alloc tracking: {} live allocations at {} ms", self.live, self.time_ms)?;
+
+        for site in self.sites.iter() {
+            writeln!(
+                f,
+                "  {} bytes in {} allocations, oldest at {} ms, from:",
+                site.bytes,
+                site.count,
+                site.oldest_ms,
+            )?;
+
+            for &address in site.call_site.iter().take_while(|&&address| address != 0) {
+                match backtrace::resolve(address - 1) {
+                    Some((name, start)) => writeln!(
+                        f,
+                        "    0x{:x} - {}+0x{:x}",
+                        address,
+                        Demangle(name),
+                        address - start,
+                    )?,
+                    None => writeln!(f, "    0x{:x}", address)?,
+                }
+            }
+        }
+
+        if self.other.count > 0 {
+            writeln!(
+                f,
+                "  {} bytes in {} allocations from other call sites",
+                self.other.bytes,
+                self.other.count,
+            )?;
+        }
+
+        if self.untracked > 0 {
+            writeln!(f, "  {} allocations were not tracked as the table was full", self.untracked)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Dumps the live allocations to serial, grouped by call site and sorted by bytes allocated.
+pub fn report() {
+    // The report is made before printing so that the lock is not held then, as printing may
+    // allocate
+    let report = interrupts::without_interrupts(|| Report::new(&TRACKER.lock()));
+    serial_print!("{}", report);
+}
+
+/// Like [report], but returns the report rather than printing it, or `None` rather than waiting if
+/// the table is locked, e.g by code which the debugger stopped
+pub fn try_report() -> Option<Report> {
+    interrupts::without_interrupts(|| TRACKER.try_lock().map(|tracker| Report::new(&tracker)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(ptr: usize) -> Record {
+        Record { ptr, size: 16, time_ms: 0, call_site: [0; CALL_SITE_DEPTH] }
+    }
+
+    fn empty_slots(tracker: &Tracker) -> usize {
+        tracker.slots.iter()
+            .filter(|slot| if let Slot::Empty = slot { true } else { false })
+            .count()
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut tracker = Tracker::new();
+
+        for ptr in (0..3000).map(|i| i * 16) {
+            tracker.insert(record(ptr));
+        }
+
+        for ptr in (0..3000).step_by(2).map(|i| i * 16) {
+            tracker.remove(ptr);
+        }
+
+        assert_eq!(tracker.live, 1500);
+        assert_eq!(empty_slots(&tracker), MAX_TRACKED - 1500);
+
+        for i in 0..3000 {
+            assert_eq!(tracker.find(i * 16).is_some(), i % 2 == 1);
+        }
+    }
+
+    #[test]
+    fn test_churn() {
+        let mut tracker = Tracker::new();
+
+        for round in 0..100 {
+            let ptrs = (0..3000).map(|i| (round * 3000 + i) * 16);
+
+            for ptr in ptrs.clone() {
+                tracker.insert(record(ptr));
+            }
+
+            for ptr in ptrs.clone() {
+                assert!(tracker.find(ptr).is_some());
+                tracker.remove(ptr);
+            }
+
+            assert_eq!(tracker.live, 0);
+            assert_eq!(empty_slots(&tracker), MAX_TRACKED);
+        }
+    }
+
+    #[test]
+    fn test_full() {
+        let mut tracker = Tracker::new();
+
+        for ptr in (0..MAX_TRACKED + 1).map(|i| i * 16) {
+            tracker.insert(record(ptr));
+        }
+
+        assert_eq!(tracker.live, MAX_TRACKED);
+        assert_eq!(tracker.untracked, 1);
+
+        tracker.remove(MAX_TRACKED * 16); // Not tracked
+        tracker.remove(0);
+
+        assert_eq!(tracker.live, MAX_TRACKED - 1);
+        assert!(tracker.find(0).is_none());
+        assert!((1..MAX_TRACKED).all(|i| tracker.find(i * 16).is_some()));
+    }
+}
@@ -57,12 +57,40 @@ macro_rules! buddy_allocator_bitmap_tree {
 
         const_assert!(level_count_too_big; $LEVEL_COUNT < 128);
 
+        /// A snapshot of the counters of a tree
+        #[derive(Debug, Copy, Clone)]
+        pub struct TreeStats {
+            /// Bytes of usable memory managed by the tree
+            pub usable_bytes: usize,
+            /// Bytes currently allocated
+            pub allocated_bytes: usize,
+            /// Number of live allocations of each order
+            pub allocations: [usize; $LEVEL_COUNT as usize],
+            /// The order of the largest free block, if there is any free block
+            pub largest_free_order: Option<u8>,
+        }
+
+        impl TreeStats {
+            pub fn free_bytes(&self) -> usize {
+                self.usable_bytes - self.allocated_bytes
+            }
+
+            /// The size in bytes of the largest free block
+            pub fn largest_free_block(&self) -> usize {
+                self.largest_free_order.map_or(0, |order| 1 << (order + $BASE_ORDER))
+            }
+        }
+
         /// A tree of blocks. Contains the flat representation of the tree as a flat array
         struct Tree<B>
             where B: ::core::ops::DerefMut<Target = [Block; BLOCKS_IN_TREE]>,
         {
             /// Flat array representation of tree. Used with the help of the `flat_tree` module.
             flat_blocks: B,
+            /// Number of usable blocks of order 0
+            usable_blocks: usize,
+            /// Number of live allocations of each order
+            allocations: [usize; $LEVEL_COUNT as usize],
         }
 
         impl<B> Tree<B>
@@ -72,7 +100,11 @@ macro_rules! buddy_allocator_bitmap_tree {
                  where I: Iterator<Item=::core::ops::Range<usize>> + Clone,
             {
                 use $crate::memory::buddy_allocator::blocks_in_level;
-                let mut tree = Tree { flat_blocks };
+                let mut tree = Tree {
+                    flat_blocks,
+                    usable_blocks: 0,
+                    allocations: [0; $LEVEL_COUNT as usize],
+                };
 
                 // Set blocks at order 0 (level = MAX_ORDER) in the holes to used & set
                 // their parents accordingly. This is implemented by checking if the block falls
@@ -88,6 +120,7 @@ macro_rules! buddy_allocator_bitmap_tree {
                         unsafe { *tree.block_mut(block_index - 1) = Block::new_used(); }
                     } else {
                         unsafe { *tree.block_mut(block_index - 1) = Block::new_free(0); }
+                        tree.usable_blocks += 1;
                     }
 
                     block_begin += 1 << ($BASE_ORDER);
@@ -173,23 +206,77 @@ macro_rules! buddy_allocator_bitmap_tree {
                 block.order_free = 0;
 
                 self.update_blocks_above(node_index, desired_order);
+                self.allocations[desired_order as usize] += 1;
+
+                Some(addr as *const u8)
+            }
+
+            /// Allocate a block of `desired_order` which lies entirely within `range` if one is
+            /// available, returning a pointer relative to the tree. `range` is also relative to the
+            /// tree. The lowest such block is chosen.
+            pub fn allocate_in(
+                &mut self,
+                desired_order: u8,
+                range: ::core::ops::Range<usize>,
+            ) -> Option<*const u8> {
+                assert!(desired_order <= MAX_ORDER);
+
+                let (index, addr) = self.find_free_in(1, MAX_ORDER, 0, desired_order, &range)?;
+
+                unsafe { self.block_mut(index - 1) }.order_free = 0;
+
+                self.update_blocks_above(index, desired_order);
+                self.allocations[desired_order as usize] += 1;
 
                 Some(addr as *const u8)
             }
 
+            /// Search the subtree under the block at `node_index` (one indexed) of `order` and at
+            /// `addr` for a free block of `desired_order` within `range`, returning its index and
+            /// address.
+            fn find_free_in(
+                &self,
+                node_index: usize,
+                order: u8,
+                addr: usize,
+                desired_order: u8,
+                range: &::core::ops::Range<usize>,
+            ) -> Option<(usize, usize)> {
+                use $crate::memory::buddy_allocator::flat_tree;
+
+                let size: usize = 1 << (order + $BASE_ORDER);
+                let order_free = unsafe { self.block(node_index - 1) }.order_free;
+
+                // Skip subtrees without a big enough free block or which are out of the range
+                if order_free <= desired_order || addr >= range.end || addr + size <= range.start {
+                    return None;
+                }
+
+                if order == desired_order {
+                    return if addr >= range.start && addr + size <= range.end {
+                        Some((node_index, addr))
+                    } else {
+                        None
+                    };
+                }
+
+                let left_child_index = flat_tree::left_child(node_index);
+
+                self.find_free_in(left_child_index, order - 1, addr, desired_order, range)
+                    .or_else(|| self.find_free_in(
+                        left_child_index + 1,
+                        order - 1,
+                        addr + size / 2,
+                        desired_order,
+                        range,
+                    ))
+            }
+
             /// Deallocate a block of memory from a pointer relative to the tree (e.g `0` is the
             /// beginning of the tree's memory) and the order of the block.
             #[inline]
             pub fn deallocate(&mut self, ptr: *const u8, order: u8) {
-                use $crate::memory::buddy_allocator::blocks_in_tree;
-
-                assert!(order <= MAX_ORDER, "Block order > maximum order!");
-
-                let level = MAX_ORDER - order;
-                let level_offset = blocks_in_tree(level);
-                let index = level_offset + ((ptr as usize) >> (order + $BASE_ORDER)) + 1;
-
-                assert!(index < BLOCKS_IN_TREE, "Block index {} out of bounds!", index);
+                let index = Self::index_of(ptr, order);
 
                 assert_eq!(
                     unsafe { self.block(index - 1).order_free },
@@ -202,6 +289,264 @@ macro_rules! buddy_allocator_bitmap_tree {
                 unsafe { self.block_mut(index - 1) }.order_free = order + 1;
 
                 self.update_blocks_above(index, order);
+                self.allocations[order as usize] -= 1;
+            }
+
+            /// Tries to grow the block at `ptr` (relative to the tree) from `order` to `new_order`
+            /// in place, by merging it with its buddies. This only succeeds if the block is the
+            /// lower half of every merged block and all of the buddies are free. Returns whether
+            /// the block was grown.
+            pub fn grow(&mut self, ptr: *const u8, order: u8, new_order: u8) -> bool {
+                use $crate::memory::buddy_allocator::flat_tree;
+
+                assert!(order <= new_order, "Cannot grow a block to a smaller order!");
+                assert!(new_order <= MAX_ORDER, "Block order > maximum order!");
+
+                if (ptr as usize) & ((1 << (new_order + $BASE_ORDER)) - 1) != 0 {
+                    return false;
+                }
+
+                let index = Self::index_of(ptr, order);
+
+                assert_eq!(
+                    unsafe { self.block(index - 1).order_free },
+                    0,
+                    "Block to grow (index {}) must be used!",
+                    index,
+                );
+
+                // As the block is a left child at every level, its buddy is the next block. The
+                // zero indexed buddy index is hence the one indexed index of the block.
+                let mut node_index = index;
+                for buddy_order in order..new_order {
+                    if unsafe { self.block(node_index) }.order_free != buddy_order + 1 {
+                        return false;
+                    }
+
+                    node_index = flat_tree::parent(node_index);
+                }
+
+                // Free the block and merge it upwards, so that the merged block's descendants are
+                // left as free, as they would be in any other used block
+                unsafe { self.block_mut(index - 1) }.order_free = order + 1;
+
+                let mut node_index = index;
+                for merged_order in order + 1..=new_order {
+                    node_index = flat_tree::parent(node_index);
+                    self.update_block(node_index, merged_order);
+                }
+
+                unsafe { self.block_mut(node_index - 1) }.order_free = 0;
+                self.update_blocks_above(node_index, new_order);
+
+                self.allocations[order as usize] -= 1;
+                self.allocations[new_order as usize] += 1;
+
+                true
+            }
+
+            /// Shrinks the block at `ptr` (relative to the tree) from `order` to `new_order` in
+            /// place, freeing the rest of it.
+            pub fn shrink(&mut self, ptr: *const u8, order: u8, new_order: u8) {
+                assert!(new_order <= order, "Cannot shrink a block to a bigger order!");
+
+                let index = Self::index_of(ptr, order);
+
+                assert_eq!(
+                    unsafe { self.block(index - 1).order_free },
+                    0,
+                    "Block to shrink (index {}) must be used!",
+                    index,
+                );
+
+                if new_order == order {
+                    return;
+                }
+
+                // The descendants of a used block are all free, so marking the smaller block as
+                // used and updating its parents splits the rest of the block off
+                let new_index = Self::index_of(ptr, new_order);
+                unsafe { self.block_mut(new_index - 1) }.order_free = 0;
+                self.update_blocks_above(new_index, new_order);
+
+                self.allocations[order as usize] -= 1;
+                self.allocations[new_order as usize] += 1;
+            }
+
+            /// Marks the blocks of base order overlapping `range` (relative to the tree) as used,
+            /// so that they are never allocated. Blocks which are already used, e.g because they
+            /// were never usable, are left as they are. Reserved blocks no longer count as usable.
+            ///
+            /// # Panicking
+            ///
+            /// Panics if the range overlaps an allocated block bigger than the base order.
+            pub fn reserve(&mut self, range: ::core::ops::Range<usize>) {
+                self.set_range(range, false);
+            }
+
+            /// Marks the blocks of base order entirely within `range` (relative to the tree) as
+            /// free, handing back memory which was reserved or was never usable. Blocks which are
+            /// already free are left as they are. Returns the number of bytes which were freed.
+            ///
+            /// # Panicking
+            ///
+            /// Panics if the range overlaps an allocated block bigger than the base order. In
+            /// debug builds, also panics if it can be told to overlap allocations of base order,
+            /// which look the same as reserved blocks: that is when the range holds more used
+            /// blocks than there are reserved or unusable blocks in the whole tree.
+            pub fn release(&mut self, range: ::core::ops::Range<usize>) -> usize {
+                self.set_range(range, true) << $BASE_ORDER
+            }
+
+            /// Marks the blocks of base order in `range` as free or used. Used blocks are those
+            /// overlapping the range, while free blocks are those entirely within it, so that
+            /// memory outside of the range is never handed out. Returns the number of blocks
+            /// which changed.
+            fn set_range(&mut self, range: ::core::ops::Range<usize>, free: bool) -> usize {
+                use $crate::memory::buddy_allocator::flat_tree;
+
+                let block_size = 1 << $BASE_ORDER;
+                let (first_block, end_block) = if free {
+                    ((range.start + block_size - 1) >> $BASE_ORDER, range.end >> $BASE_ORDER)
+                } else {
+                    (range.start >> $BASE_ORDER, (range.end + block_size - 1) >> $BASE_ORDER)
+                };
+                let end_block = ::core::cmp::min(end_block, 1 << MAX_ORDER);
+
+                if first_block >= end_block {
+                    return 0;
+                }
+
+                // Changing the blocks under an allocated block would make it look free once its
+                // parents are updated
+                let blocks = (first_block << $BASE_ORDER)..(end_block << $BASE_ORDER);
+                assert!(
+                    !self.overlaps_allocation(1, MAX_ORDER, 0, &blocks),
+                    "Range 0x{:x} to 0x{:x} overlaps an allocation!",
+                    range.start,
+                    range.end,
+                );
+
+                if free && cfg!(debug_assertions) {
+                    let used = (first_block..end_block)
+                        .filter(|block| {
+                            unsafe { self.block((1 << MAX_ORDER) + block - 1) }.order_free == 0
+                        })
+                        .count();
+
+                    debug_assert!(
+                        used <= (1 << MAX_ORDER) - self.usable_blocks,
+                        "Range 0x{:x} to 0x{:x} overlaps an allocation!",
+                        range.start,
+                        range.end,
+                    );
+                }
+
+                let order_free = if free { 1 } else { 0 };
+                let mut changed = 0;
+
+                for block in first_block..end_block {
+                    let index = (1 << MAX_ORDER) + block;
+                    let leaf = unsafe { self.block_mut(index - 1) };
+
+                    if leaf.order_free != order_free {
+                        leaf.order_free = order_free;
+                        changed += 1;
+
+                        if free {
+                            self.usable_blocks += 1;
+                        } else {
+                            self.usable_blocks -= 1;
+                        }
+                    }
+                }
+
+                // Update the parents of the changed blocks level by level
+                let mut first = (1 << MAX_ORDER) + first_block;
+                let mut last = (1 << MAX_ORDER) + end_block - 1;
+
+                for order in 1..=MAX_ORDER {
+                    first = flat_tree::parent(first);
+                    last = flat_tree::parent(last);
+
+                    for node_index in first..=last {
+                        self.update_block(node_index, order);
+                    }
+                }
+
+                changed
+            }
+
+            /// Whether a block bigger than the base order which is allocated overlaps `range`, in
+            /// the subtree under the block at `node_index` (one indexed) of `order` and at `addr`
+            fn overlaps_allocation(
+                &self,
+                node_index: usize,
+                order: u8,
+                addr: usize,
+                range: &::core::ops::Range<usize>,
+            ) -> bool {
+                use $crate::memory::buddy_allocator::flat_tree;
+
+                let size: usize = 1 << (order + $BASE_ORDER);
+
+                if order == 0 || addr >= range.end || addr + size <= range.start {
+                    return false;
+                }
+
+                let order_free = unsafe { self.block(node_index - 1) }.order_free;
+
+                // Entirely free
+                if order_free == order + 1 {
+                    return false;
+                }
+
+                let left_child_index = flat_tree::left_child(node_index);
+                let left = unsafe { self.block(left_child_index - 1) }.order_free;
+                let right = unsafe { self.block(left_child_index) }.order_free;
+
+                // An allocated block is used while its children are left free. A block which is
+                // used because its children are both used is not allocated itself.
+                if order_free == 0 && (left != 0 || right != 0) {
+                    return true;
+                }
+
+                self.overlaps_allocation(left_child_index, order - 1, addr, range) ||
+                    self.overlaps_allocation(left_child_index + 1, order - 1, addr + size / 2, range)
+            }
+
+            /// Returns the one indexed index of the block of `order` at `ptr` (relative to the
+            /// tree)
+            #[inline]
+            fn index_of(ptr: *const u8, order: u8) -> usize {
+                use $crate::memory::buddy_allocator::blocks_in_tree;
+
+                assert!(order <= MAX_ORDER, "Block order > maximum order!");
+
+                let level = MAX_ORDER - order;
+                let level_offset = blocks_in_tree(level);
+                let index = level_offset + ((ptr as usize) >> (order + $BASE_ORDER)) + 1;
+
+                assert!(index < BLOCKS_IN_TREE, "Block index {} out of bounds!", index);
+
+                index
+            }
+
+            /// Returns a snapshot of the tree's counters
+            pub fn stats(&self) -> TreeStats {
+                let allocated_bytes = self.allocations.iter()
+                    .enumerate()
+                    .map(|(order, count)| count << (order + $BASE_ORDER as usize))
+                    .sum();
+
+                let root = unsafe { self.block(0) };
+
+                TreeStats {
+                    usable_bytes: self.usable_blocks << $BASE_ORDER,
+                    allocated_bytes,
+                    allocations: self.allocations,
+                    largest_free_order: root.order_free.checked_sub(1),
+                }
             }
 
             /// Update a block from its children
@@ -386,6 +731,169 @@ mod test {
         assert_eq!(tree.allocate(5).unwrap(), 0x0 as *const u8);
     }
 
+    #[test]
+    fn test_stats() {
+        let mut tree = Tree::new(
+            iter::once(0..(1 << 30 + 1)),
+            unsafe { box mem::uninitialized() }
+        );
+
+        let stats = tree.stats();
+        assert_eq!(stats.usable_bytes, 1 << MAX_ORDER_SIZE);
+        assert_eq!(stats.free_bytes(), stats.usable_bytes);
+        assert_eq!(stats.largest_free_order, Some(MAX_ORDER));
+
+        let ptr = tree.allocate(2).unwrap();
+        tree.allocate(0).unwrap();
+
+        let stats = tree.stats();
+        assert_eq!(stats.allocated_bytes, (4 + 1) << 12);
+        assert_eq!(stats.allocations[2], 1);
+        assert_eq!(stats.largest_free_order, Some(MAX_ORDER - 1));
+
+        tree.deallocate(ptr, 2);
+        assert_eq!(tree.stats().allocations[2], 0);
+        assert_eq!(tree.stats().allocated_bytes, 1 << 12);
+    }
+
+    #[test]
+    fn test_allocate_in() {
+        let mut tree = Tree::new(
+            iter::once(0..(1 << 30 + 1)),
+            unsafe { box mem::uninitialized() }
+        );
+
+        assert_eq!(tree.allocate_in(0, 0x5000..0x10000), Some(0x5000 as *const u8));
+        assert_eq!(tree.allocate_in(0, 0x5000..0x10000), Some(0x6000 as *const u8));
+
+        // Blocks are aligned to their size, so no block of order 3 fits
+        assert_eq!(tree.allocate_in(3, 0x1000..0xF000), None);
+        assert_eq!(tree.allocate_in(3, 0x1000..0x10000), Some(0x8000 as *const u8));
+
+        // Used blocks are skipped
+        assert_eq!(tree.allocate_in(2, 0x4000..0x8000), None);
+        assert_eq!(tree.allocate(2), Some(0x0 as *const u8));
+    }
+
+    #[test]
+    fn test_reserve_release() {
+        let mut tree = Tree::new(
+            iter::once(0..0x10000),
+            unsafe { box mem::uninitialized() }
+        );
+
+        tree.reserve(0x1800..0x3000);
+        assert_eq!(tree.stats().usable_bytes, 0xE000);
+        assert_eq!(tree.allocate(0), Some(0x0 as *const u8));
+        assert_eq!(tree.allocate(0), Some(0x3000 as *const u8));
+        assert_eq!(tree.allocate(1), Some(0x4000 as *const u8));
+
+        // Memory which was never usable can be released too
+        tree.release(0x1000..0x3000);
+        tree.release(0x10000..0x12000);
+        assert_eq!(tree.stats().usable_bytes, 0x12000);
+        assert_eq!(tree.allocate(0), Some(0x1000 as *const u8));
+        assert_eq!(tree.allocate_in(1, 0x10000..0x20000), Some(0x10000 as *const u8));
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps an allocation")]
+    fn test_reserve_allocated() {
+        let mut tree = Tree::new(
+            iter::once(0..0x10000),
+            unsafe { box mem::uninitialized() }
+        );
+
+        let ptr = tree.allocate(2).unwrap();
+        tree.reserve(ptr as usize + 0x1000..ptr as usize + 0x2000);
+    }
+
+    #[test]
+    fn test_release_unaligned() {
+        let mut tree = Tree::new(
+            iter::once(0..0x10000),
+            unsafe { box mem::uninitialized() }
+        );
+
+        tree.reserve(0x1000..0x4000);
+        assert_eq!(tree.stats().usable_bytes, 0xD000);
+
+        // Only the block entirely within the range is freed
+        assert_eq!(tree.release(0x1800..0x3800), 0x1000);
+        assert_eq!(tree.stats().usable_bytes, 0xE000);
+        assert_eq!(tree.allocate_in(0, 0x1000..0x4000), Some(0x2000 as *const u8));
+        assert_eq!(tree.allocate_in(0, 0x1000..0x4000), None);
+
+        // Nothing is freed if no block is entirely within the range, or if it is already free
+        assert_eq!(tree.release(0x3800..0x4800), 0);
+        assert_eq!(tree.release(0x8000..0x9000), 0);
+        assert_eq!(tree.stats().usable_bytes, 0xE000);
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps an allocation")]
+    fn test_release_allocated() {
+        let mut tree = Tree::new(
+            iter::once(0..(1 << 30)),
+            unsafe { box mem::uninitialized() }
+        );
+
+        let ptr = tree.allocate(0).unwrap();
+        tree.release(ptr as usize..ptr as usize + 0x1000);
+    }
+
+    #[test]
+    fn test_grow() {
+        let mut tree = Tree::new(
+            iter::once(0..(1 << 30 + 1)),
+            unsafe { box mem::uninitialized() }
+        );
+
+        let ptr = tree.allocate(0).unwrap();
+        assert!(tree.grow(ptr, 0, 3));
+        assert_eq!(tree.stats().allocations[0], 0);
+        assert_eq!(tree.stats().allocations[3], 1);
+
+        // The rest of the grown block must not be handed out again
+        assert_eq!(tree.allocate(3), Some((8 << 12) as *const u8));
+
+        // The buddy of the first block is now used
+        assert!(!tree.grow(ptr, 3, 4));
+
+        // Blocks that are not the lower half of the merged block cannot be grown in place
+        let ptr2 = tree.allocate(3).unwrap();
+        assert!(!tree.grow(ptr2, 3, 5));
+
+        tree.deallocate(ptr, 3);
+        tree.deallocate((8 << 12) as *const u8, 3);
+        tree.deallocate(ptr2, 3);
+        assert_eq!(tree.allocate(MAX_ORDER), Some(0x0 as *const u8));
+    }
+
+    #[test]
+    fn test_shrink() {
+        let mut tree = Tree::new(
+            iter::once(0..(1 << 30 + 1)),
+            unsafe { box mem::uninitialized() }
+        );
+
+        let ptr = tree.allocate(3).unwrap();
+        tree.shrink(ptr, 3, 1);
+        assert_eq!(tree.stats().allocated_bytes, 2 << 12);
+
+        // The freed part of the block can be allocated again
+        assert_eq!(tree.allocate(1), Some((2 << 12) as *const u8));
+        assert_eq!(tree.allocate(2), Some((4 << 12) as *const u8));
+
+        assert!(!tree.grow(ptr, 1, 2));
+        tree.deallocate((2 << 12) as *const u8, 1);
+        assert!(tree.grow(ptr, 1, 2));
+
+        tree.deallocate(ptr, 2);
+        tree.deallocate((4 << 12) as *const u8, 2);
+        assert_eq!(tree.allocate(MAX_ORDER), Some(0x0 as *const u8));
+    }
+
     #[test]
     fn test_alloc_unique_addresses() {
         let max_blocks = blocks_in_level(MAX_ORDER);
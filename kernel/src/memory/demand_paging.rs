@@ -0,0 +1,82 @@
+//! Demand paging of kernel memory. Pages in a registered lazy region are only backed by a zeroed
+//! frame once they are first touched, through the page fault handler.
+//!
+//! # Locking
+//!
+//! The page tables of lazy regions are never modified through `PAGE_TABLES`, but through
+//! [with_lazy_mapper]. This means that faults can be resolved even if the faulting code holds
+//! `PAGE_TABLES` (e.g when it allocates on the heap while mapping something). In turn, code holding
+//! the lazy mapper must never touch lazy memory.
+
+use core::ops::Range;
+use arrayvec::ArrayVec;
+use spin::Mutex;
+use super::paging::{PAGE_TABLES, ActivePageMap, Page, PageSize, EntryFlags, InvalidateTlb};
+use crate::interrupts;
+
+const MAX_LAZY_REGIONS: usize = 8;
+
+lazy_static! {
+    static ref LAZY_REGIONS: Mutex<ArrayVec<[LazyRegion; MAX_LAZY_REGIONS]>> =
+        Mutex::new(ArrayVec::new());
+}
+
+static LAZY_MAPPER: Mutex<ActivePageMap> = Mutex::new(unsafe { ActivePageMap::new() });
+
+#[derive(Debug, Clone)]
+struct LazyRegion {
+    range: Range<usize>,
+    flags: EntryFlags,
+}
+
+/// Registers a region of kernel memory to be mapped on demand with the given flags. The region must
+/// be 1GiB aligned, so that it has page tables of its own.
+///
+/// # Unsafety
+///
+/// Unsafe as the region must not be used for anything else. The page fault handler must also be
+/// installed before any of the region is touched.
+pub unsafe fn register(range: Range<usize>, flags: EntryFlags) {
+    assert!(
+        range.start % (1 << 30) == 0 && range.end % (1 << 30) == 0,
+        "Lazy region must be 1GiB aligned!",
+    );
+
+    // Create the P3 entries of the region now, since the P3 tables are shared with other mappings
+    interrupts::without_interrupts(|| {
+        let mut tables = PAGE_TABLES.lock();
+
+        for gib in (range.start..range.end).step_by(1 << 30) {
+            tables.create_p2_table(Page::containing_address(gib, PageSize::Kib4));
+        }
+    });
+
+    let mut regions = LAZY_REGIONS.lock();
+    regions.try_push(LazyRegion { range, flags }).expect("Too many lazy regions!");
+}
+
+/// Runs the closure with the mapper for lazy regions, with interrupts disabled. See the module
+/// level docs for the rules.
+pub fn with_lazy_mapper<F: FnOnce(&mut ActivePageMap) -> R, R>(f: F) -> R {
+    interrupts::without_interrupts(|| f(&mut LAZY_MAPPER.lock()))
+}
+
+/// Tries to resolve a page fault caused by the kernel accessing a non-present page. Returns whether
+/// it was resolved, i.e whether the address lies in a lazy region.
+pub fn handle_fault(address: usize) -> bool {
+    let flags = match LAZY_REGIONS.lock().iter().find(|region| region.range.contains(&address)) {
+        Some(region) => region.flags,
+        None => return false,
+    };
+
+    let page = Page::containing_address(address, PageSize::Kib4);
+
+    with_lazy_mapper(|mapper| {
+        // Another CPU may have got here first
+        if mapper.walk_page_table(page).is_none() {
+            unsafe { mapper.map(page, flags, InvalidateTlb::Invalidate) };
+        }
+    });
+
+    true
+}
@@ -0,0 +1,88 @@
+//! Physically contiguous buffers for devices which do DMA. Devices which cannot address all of
+//! physical memory (ISA DMA, legacy and 32 bit only PCI devices) can ask for a buffer within the
+//! range that they can reach.
+
+use core::{ptr, slice, ops::{Deref, DerefMut, Range}};
+use core::mem::ManuallyDrop;
+use super::physical_allocator::PHYSICAL_ALLOCATOR;
+use super::physical_mapping::{self, PhysicalMapping};
+use super::paging::PhysicalAddress;
+use crate::util;
+
+/// The physical memory reachable by ISA DMA
+pub const ISA_DMA_RANGE: Range<usize> = 0..16 << 20;
+/// The physical memory reachable by devices with 32 bit addressing
+pub const DMA32_RANGE: Range<usize> = 0..4 << 30;
+
+/// A zeroed, physically contiguous buffer. Its frames are freed and its mapping is removed when it
+/// is dropped.
+pub struct DmaBuffer {
+    mapping: ManuallyDrop<PhysicalMapping<u8>>,
+    physical_start: PhysicalAddress,
+    size: usize,
+    order: u8,
+}
+
+impl DmaBuffer {
+    /// Allocates a buffer of at least `size` bytes which lies entirely within the physical `range`.
+    /// The buffer's size is rounded up to a power of two number of frames, and it is aligned to its
+    /// size, so it never crosses a boundary of that size (e.g the 64KiB boundaries which ISA DMA
+    /// transfers cannot cross). Returns `None` if no such memory is available.
+    pub fn new(size: usize, range: Range<usize>) -> Option<DmaBuffer> {
+        let frames = util::round_up_divide(size as u64, 4096).max(1);
+        let order = frames.next_power_of_two().trailing_zeros() as u8;
+
+        let physical_start = PHYSICAL_ALLOCATOR.allocate_in(order, range)? as usize;
+        let size = 4096 << order;
+
+        let mapping = unsafe {
+            let mapping = physical_mapping::map_physical_region(physical_start, size, true);
+            ptr::write_bytes(mapping.virtual_address() as *mut u8, 0, size);
+            mapping
+        };
+
+        Some(DmaBuffer {
+            mapping: ManuallyDrop::new(mapping),
+            physical_start: PhysicalAddress(physical_start),
+            size,
+            order,
+        })
+    }
+
+    /// The physical address of the buffer, to be given to the device
+    pub fn physical_address(&self) -> PhysicalAddress {
+        self.physical_start
+    }
+
+    /// A pointer to the buffer through which the kernel can access it
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.mapping.virtual_address() as *mut u8
+    }
+
+    /// The size of the buffer, which may be bigger than what was asked for
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Deref for DmaBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.size) }
+    }
+}
+
+impl DerefMut for DmaBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_ptr(), self.size) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        // Unmap the buffer before its frames can be handed out again
+        unsafe { ManuallyDrop::drop(&mut self.mapping); }
+        PHYSICAL_ALLOCATOR.deallocate(self.physical_start.0 as *const u8, self.order);
+    }
+}
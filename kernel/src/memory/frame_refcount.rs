@@ -0,0 +1,109 @@
+//! Reference counts of physical frames which are shared between address spaces, such as copy on
+//! write pages after a fork. Only shared frames are kept in the table -- a frame which is not in it
+//! has a single owner, who frees it as usual.
+
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+use super::physical_allocator::PHYSICAL_ALLOCATOR;
+use super::paging::PhysicalAddress;
+use crate::interrupts;
+
+lazy_static! {
+    static ref REFCOUNTS: Mutex<RefCounts> = Mutex::new(RefCounts::new());
+}
+
+/// Reference counts keyed by frame address. Counts are always at least 2.
+struct RefCounts(BTreeMap<usize, usize>);
+
+impl RefCounts {
+    fn new() -> Self {
+        RefCounts(BTreeMap::new())
+    }
+
+    fn share(&mut self, frame: usize) {
+        *self.0.entry(frame).or_insert(1) += 1;
+    }
+
+    fn count(&self, frame: usize) -> usize {
+        self.0.get(&frame).cloned().unwrap_or(1)
+    }
+
+    /// Drops a reference, returning whether it was the last one
+    fn release(&mut self, frame: usize) -> bool {
+        match self.count(frame) {
+            1 => true,
+            2 => {
+                self.0.remove(&frame);
+                false
+            },
+            count => {
+                self.0.insert(frame, count - 1);
+                false
+            },
+        }
+    }
+}
+
+/// Adds a reference to an allocated 4kib frame
+pub fn share(frame: PhysicalAddress) {
+    interrupts::without_interrupts(|| REFCOUNTS.lock().share(frame.0));
+}
+
+/// The number of references to an allocated 4kib frame
+pub fn count(frame: PhysicalAddress) -> usize {
+    interrupts::without_interrupts(|| REFCOUNTS.lock().count(frame.0))
+}
+
+/// Drops a reference to an allocated 4kib frame, freeing it if this was the last one. Returns
+/// whether it was freed.
+pub fn release(frame: PhysicalAddress) -> bool {
+    let last = interrupts::without_interrupts(|| REFCOUNTS.lock().release(frame.0));
+
+    if last {
+        PHYSICAL_ALLOCATOR.deallocate(frame.0 as *const u8, 0);
+    }
+
+    last
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FRAME: usize = 0x1000;
+
+    #[test]
+    fn test_fork_write_unmap() {
+        let mut counts = RefCounts::new();
+
+        // Fork: the child maps the parent's frame
+        counts.share(FRAME);
+        assert_eq!(counts.count(FRAME), 2);
+
+        // The child writes, so it copies the frame and drops its reference
+        assert!(!counts.release(FRAME));
+        assert_eq!(counts.count(FRAME), 1);
+        assert!(counts.0.is_empty());
+
+        // The parent writes to the last reference in place, then unmaps it
+        assert!(counts.release(FRAME));
+    }
+
+    #[test]
+    fn test_fork_drop() {
+        let mut counts = RefCounts::new();
+
+        // Two children forked from the same parent
+        counts.share(FRAME);
+        counts.share(FRAME);
+        assert_eq!(counts.count(FRAME), 3);
+
+        // Dropping the children releases their references, leaving the parent's
+        assert!(!counts.release(FRAME));
+        assert!(!counts.release(FRAME));
+        assert_eq!(counts.count(FRAME), 1);
+
+        assert!(counts.release(FRAME));
+        assert!(counts.0.is_empty());
+    }
+}
@@ -2,19 +2,38 @@
 pub const HEAP_START: usize = 0xffffffff40000000;
 
 use core::alloc::{GlobalAlloc, Layout};
-use core::{iter, mem};
+use core::{cmp, iter, mem, ptr};
 use core::ptr::Unique;
-use core::ops::{Deref, DerefMut};
+use core::ops::{Deref, DerefMut, Range};
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::{Once, Mutex};
-use super::paging::{PAGE_TABLES, Page, PageSize, EntryFlags, FreeMemory, InvalidateTlb};
+use super::paging::{PAGE_TABLES, ActivePageMap, Page, PageSize, EntryFlags, FreeMemory,
+                    InvalidateTlb};
+use super::demand_paging::{self, with_lazy_mapper};
+use super::slab::{self, SlabAllocator, SlabSource};
+#[cfg(feature = "debug")]
+use super::heap_debug::{self, AllocatedBits, DebugHeap};
+#[cfg(feature = "alloc_tracking")]
+use super::alloc_tracking;
 use crate::memory::{buddy_allocator, paging::PhysicalAddress};
-use crate::util;
+use crate::{util, interrupts};
 // use ...::Block // <-- this one comes from the macro invocation below
 
 const BASE_ORDER: u8 = 6;
 
 buddy_allocator_bitmap_tree!(LEVEL_COUNT = 25, BASE_ORDER = BASE_ORDER);
 
+/// Heap blocks whose allocated bits fit in one page of the allocated bitmap
+#[cfg(feature = "debug")]
+const BLOCKS_PER_BITMAP_PAGE: usize = 4096 * 8;
+/// Pages in the bitmap of allocated heap blocks, with a bit for every possible block
+#[cfg(feature = "debug")]
+const ALLOCATED_BITMAP_PAGES: usize = (1 << 30) / heap_debug::BLOCK_ALIGN / BLOCKS_PER_BITMAP_PAGE;
+
+#[cfg(feature = "debug")]
+static DEBUG_HEAP: Mutex<DebugHeap<AllocatedBitmap>> =
+    Mutex::new(DebugHeap::new(AllocatedBitmap([ptr::null_mut(); ALLOCATED_BITMAP_PAGES])));
+
 /// Wrapper that just impls deref for a Unique.
 ///
 /// # Safety
@@ -42,13 +61,35 @@ impl<T> DerefMut for DerefPtr<T> {
     }
 }
 
+/// The kernel heap. Its pages are mapped eagerly on allocation during early boot, and on demand
+/// by the page fault handler once [Heap::enable_demand_paging] is called. All page table changes
+/// in the heap area go through the lazy mapper (see `memory::demand_paging`).
+///
+/// Allocations of up to 2048 bytes are served by slab caches (see `memory::slab`), which carve up
+/// pages allocated from the buddy tree. Larger allocations go to the buddy tree directly.
 pub struct Heap {
     tree: Once<Mutex<Tree<DerefPtr<[Block; BLOCKS_IN_TREE]>>>>,
+    slabs: SlabAllocator,
+    demand_paged: AtomicBool,
 }
 
 impl Heap {
     pub const fn new() -> Self {
-        Heap { tree: Once::new() }
+        Heap { tree: Once::new(), slabs: SlabAllocator::new(), demand_paged: AtomicBool::new(false) }
+    }
+
+    /// Stops mapping heap pages on allocation and instead lets them be mapped when first touched.
+    ///
+    /// # Unsafety
+    ///
+    /// Unsafe as the page fault handler must be installed.
+    pub unsafe fn enable_demand_paging(&self) {
+        demand_paging::register(
+            HEAP_START..HEAP_START + (1 << 30),
+            EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+        );
+
+        self.demand_paged.store(true, Ordering::SeqCst);
     }
     
     /// Initializes the heap. Required for it to be usable, otherwise all of its methods will panic.
@@ -92,7 +133,8 @@ impl Heap {
     /// requirements about where it is to be placed in physical memory.
     ///
     /// Note: `physical_begin_frame` is the frame number of the beginning physical frame to allocate
-    /// memory from (i.e address / 4096).
+    /// memory from (i.e address / 4096). `flags` are added to the default writable, non executable
+    /// flags of the pages (e.g `NO_CACHE` for MMIO).
     ///
     /// # Panicking
     ///
@@ -106,30 +148,35 @@ impl Heap {
         &self,
         physical_begin_frame: usize,
         frames: usize,
+        flags: EntryFlags,
     ) -> *mut u8 {
-        let mut tree = self.tree.wait().expect("Heap not initialized!").lock();
-        
-        let order = order(frames * 4096);
-        if order > MAX_ORDER { return 0 as *mut _; }
+        interrupts::without_interrupts(|| {
+            let mut tree = self.tree.wait().expect("Heap not initialized!").lock();
 
-        let ptr = tree.allocate(order);
+            let order = order(frames * 4096);
+            if order > MAX_ORDER { return 0 as *mut _; }
 
-        if ptr.is_none() { return 0 as *mut _; }
+            let ptr = tree.allocate(order);
 
-        let ptr = (ptr.unwrap() as usize + HEAP_START) as *mut u8;
+            if ptr.is_none() { return 0 as *mut _; }
 
-        // Map pages that must be mapped
-        for page in 0..util::round_up_divide(1u64 << (order + BASE_ORDER), 4096) as usize {
-            let page_addr = ptr as usize + (page * 4096);
-            PAGE_TABLES.lock().map_to(
-                Page::containing_address(page_addr, PageSize::Kib4),
-                PhysicalAddress((physical_begin_frame + page) * 4096),
-                EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
-                InvalidateTlb::Invalidate,
-            );
-        }
+            let ptr = (ptr.unwrap() as usize + HEAP_START) as *mut u8;
 
-        ptr
+            // Map pages that must be mapped
+            with_lazy_mapper(|mapper| {
+                for page in 0..util::round_up_divide(1u64 << (order + BASE_ORDER), 4096) as usize {
+                    let page_addr = ptr as usize + (page * 4096);
+                    mapper.map_to(
+                        Page::containing_address(page_addr, PageSize::Kib4),
+                        PhysicalAddress((physical_begin_frame + page) * 4096),
+                        EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE | flags,
+                        InvalidateTlb::Invalidate,
+                    );
+                }
+            });
+
+            ptr
+        })
     }
 
     /// The `dealloc` counterpart to `alloc_specific`. This function does not free the backing
@@ -160,18 +207,39 @@ impl Heap {
         let global_ptr = ptr;
         let ptr = ptr as usize - HEAP_START;
 
-        self.tree.wait().expect("Heap not initialized!").lock().deallocate(ptr as *mut _, order);
+        interrupts::without_interrupts(|| {
+            self.tree.wait().expect("Heap not initialized!").lock().deallocate(ptr as *mut _, order);
 
-        // Unmap pages that have were used for this alloc
-        for page in 0..util::round_up_divide(1u64 << (order + BASE_ORDER), 4096) as usize {
-            let page_addr = global_ptr as usize + (page * 4096);
+            // Unmap pages that have were used for this alloc
+            with_lazy_mapper(|mapper| {
+                let size = util::round_up_divide(1u64 << (order + BASE_ORDER), 4096) as usize * 4096;
 
-            PAGE_TABLES.lock().unmap(
-                Page::containing_address(page_addr, PageSize::Kib4),
-                FreeMemory::NoFree,
-                InvalidateTlb::NoInvalidate,
-            );
-        }
+                mapper.unmap_range(
+                    global_ptr as usize..global_ptr as usize + size,
+                    FreeMemory::NoFree,
+                    InvalidateTlb::NoInvalidate,
+                );
+            });
+        });
+    }
+
+    /// Returns a snapshot of the buddy tree's counters. Slabs count as allocated in full.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if the heap is not initialized.
+    pub fn stats(&self) -> TreeStats {
+        interrupts::without_interrupts(|| {
+            self.tree.wait().expect("Heap not initialized!").lock().stats()
+        })
+    }
+
+    /// Like `stats`, but returns `None` rather than waiting if the tree is locked, e.g by code
+    /// which the debugger stopped, or if the heap is not initialized
+    pub fn try_stats(&self) -> Option<TreeStats> {
+        interrupts::without_interrupts(|| {
+            self.tree.r#try()?.try_lock().map(|tree| tree.stats())
+        })
     }
 
     pub const fn tree_size() -> usize {
@@ -179,8 +247,8 @@ impl Heap {
     }
 }
 
-unsafe impl GlobalAlloc for Heap {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+impl Heap {
+    unsafe fn alloc_inner(&self, layout: Layout) -> *mut u8 {
         let mut tree = self.tree.wait().expect("Heap not initialized!").lock();
 
         let order = order(layout.size());
@@ -190,29 +258,21 @@ unsafe impl GlobalAlloc for Heap {
         if ptr.is_none() { return 0 as *mut _ }
         let ptr = (ptr.unwrap() as usize + HEAP_START) as *mut u8;
 
-        // Map pages that have yet to be mapped
-        for page in 0..util::round_up_divide(1u64 << (order + BASE_ORDER - 1), 4096) as usize {
-            let mut page_tables = PAGE_TABLES.lock();
-
-            let page_addr = ptr as usize + (page * 4096);
+        // Once demand paged, pages are mapped by the page fault handler when first touched
+        if self.demand_paged.load(Ordering::SeqCst) {
+            return ptr;
+        }
 
-            let mapped = page_tables
-                .walk_page_table(
-                    Page::containing_address(page_addr, PageSize::Kib4)
-                ).is_some();
+        // Map pages that have yet to be mapped
+        with_lazy_mapper(|mapper| {
+            let size = util::round_up_divide(1u64 << (order + BASE_ORDER - 1), 4096) as usize * 4096;
+            map_unmapped(mapper, ptr as usize..ptr as usize + size);
+        });
 
-            if !mapped {
-                page_tables.map(
-                    Page::containing_address(page_addr, PageSize::Kib4),
-                    EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
-                    InvalidateTlb::NoInvalidate,
-                );
-            }
-        }
         ptr
     }
 
-   unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+   unsafe fn dealloc_inner(&self, ptr: *mut u8, layout: Layout) {
         if ptr.is_null() {
             return;
         }
@@ -248,27 +308,270 @@ unsafe impl GlobalAlloc for Heap {
             if order_free == page_order + 1 {
                 let global_ptr = page_base_ptr + HEAP_START;
 
-                PAGE_TABLES.lock().unmap(
-                    Page::containing_address(global_ptr, PageSize::Kib4),
-                    FreeMemory::Free,
-                    InvalidateTlb::Invalidate,
-                );
+                with_lazy_mapper(|mapper| {
+                    unmap_if_mapped(mapper, Page::containing_address(global_ptr, PageSize::Kib4));
+                });
             }
         } else {
            // Unmap pages that have were only used for this alloc
-           for page in 0..util::round_up_divide(1u64 << (order + BASE_ORDER - 1), 4096) as usize {
-               let page_addr = global_ptr as usize + (page * 4096);
+           with_lazy_mapper(|mapper| {
+               let size = util::round_up_divide(1u64 << (order + BASE_ORDER - 1), 4096) as usize * 4096;
 
-               PAGE_TABLES.lock().unmap(
-                   Page::containing_address(page_addr, PageSize::Kib4),
+               mapper.unmap_range(
+                   global_ptr as usize..global_ptr as usize + size,
                    FreeMemory::Free,
                    InvalidateTlb::Invalidate,
                );
-           }
+           });
        }
    }
 }
 
+impl Heap {
+    /// Tries to resize an allocation without moving it. Allocations in the same slab size class
+    /// are resized trivially, and buddy allocations are grown by merging them with their free
+    /// buddies or shrunk by splitting them, mapping or unmapping only the pages that changed.
+    /// Returns whether the allocation was resized.
+    unsafe fn realloc_in_place(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+
+        match (slab::size_class(&layout), slab::size_class(&new_layout)) {
+            (Some(class), Some(new_class)) => return class == new_class,
+            (None, None) => (),
+            _ => return false,
+        }
+
+        let old_order = order(layout.size());
+        let new_order = order(new_size);
+        if new_order > MAX_ORDER { return false; }
+
+        interrupts::without_interrupts(|| {
+            let mut tree = self.tree.wait().expect("Heap not initialized!").lock();
+            let tree_ptr = (ptr as usize - HEAP_START) as *const u8;
+
+            if new_order > old_order {
+                if !tree.grow(tree_ptr, old_order, new_order) {
+                    return false;
+                }
+
+                // Once demand paged, pages are mapped by the page fault handler when first touched
+                if !self.demand_paged.load(Ordering::SeqCst) {
+                    with_lazy_mapper(|mapper| {
+                        map_unmapped(mapper, ptr as usize + layout.size()..ptr as usize + new_size);
+                    });
+                }
+            } else if new_order < old_order {
+                tree.shrink(tree_ptr, old_order, new_order);
+
+                // Unmap the pages which are no longer used by the allocation
+                let new_end = ptr as u64 + new_size as u64;
+                let start = util::round_up_divide(new_end, 4096) as usize * 4096;
+                let end = ptr as usize + (1 << (old_order + BASE_ORDER));
+
+                if start < end {
+                    with_lazy_mapper(|mapper| {
+                        mapper.unmap_range(start..end, FreeMemory::Free, InvalidateTlb::Invalidate);
+                    });
+                }
+            }
+
+            true
+        })
+    }
+}
+
+impl SlabSource for Heap {
+    unsafe fn alloc_slab(&self, size: usize) -> *mut u8 {
+        // Buddy blocks are aligned to their size, so this is too
+        self.alloc_inner(Layout::from_size_align_unchecked(size, size))
+    }
+
+    unsafe fn free_slab(&self, ptr: *mut u8, size: usize) {
+        self.dealloc_inner(ptr, Layout::from_size_align_unchecked(size, size))
+    }
+}
+
+/// The allocated bit of every block the heap could hand out, for the `debug` feature. The bitmap
+/// is split into pages, which are allocated from the heap (bypassing the debug heap) the first
+/// time a block they cover is allocated, and are never freed. This way, it only takes up as much
+/// memory as the part of the heap that has been used.
+#[cfg(feature = "debug")]
+struct AllocatedBitmap([*mut u64; ALLOCATED_BITMAP_PAGES]);
+
+// Only accessed through `DEBUG_HEAP`
+#[cfg(feature = "debug")]
+unsafe impl Send for AllocatedBitmap {}
+
+#[cfg(feature = "debug")]
+impl AllocatedBitmap {
+    /// Returns the page of the bitmap, the word in that page and the bit in that word of a block
+    fn bit(block: usize) -> (usize, usize, u64) {
+        let offset = block.wrapping_sub(HEAP_START);
+
+        assert!(
+            offset < (1 << 30) && offset % heap_debug::BLOCK_ALIGN == 0,
+            "heap: 0x{:x} is not a heap block",
+            block,
+        );
+
+        let index = offset / heap_debug::BLOCK_ALIGN;
+        let in_page = index % BLOCKS_PER_BITMAP_PAGE;
+        (index / BLOCKS_PER_BITMAP_PAGE, in_page / 64, 1 << (in_page % 64))
+    }
+}
+
+#[cfg(feature = "debug")]
+impl AllocatedBits for AllocatedBitmap {
+    fn is_allocated(&self, block: usize) -> bool {
+        let (page, word, bit) = Self::bit(block);
+
+        // Nothing it covers has been allocated yet
+        if self.0[page].is_null() {
+            return false;
+        }
+
+        unsafe { *self.0[page].add(word) & bit != 0 }
+    }
+
+    fn set_allocated(&mut self, block: usize, allocated: bool) {
+        let (page, word, bit) = Self::bit(block);
+
+        if self.0[page].is_null() {
+            if !allocated {
+                return;
+            }
+
+            let new_page = unsafe {
+                crate::HEAP.alloc_raw(Layout::from_size_align_unchecked(4096, 4096)) as *mut u64
+            };
+
+            assert!(!new_page.is_null(), "heap: out of memory for the allocated bitmap");
+            unsafe { ptr::write_bytes(new_page, 0, 4096 / mem::size_of::<u64>()); }
+            self.0[page] = new_page;
+        }
+
+        let word = unsafe { &mut *self.0[page].add(word) };
+
+        if allocated {
+            *word |= bit;
+        } else {
+            *word &= !bit;
+        }
+    }
+}
+
+/// Maps the heap pages overlapping the range which have yet to be mapped
+unsafe fn map_unmapped(mapper: &mut ActivePageMap, range: Range<usize>) {
+    let mut page_addr = range.start & !0xFFF;
+
+    while page_addr < range.end {
+        let page = Page::containing_address(page_addr, PageSize::Kib4);
+
+        if mapper.walk_page_table(page).is_none() {
+            mapper.map(
+                page,
+                EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+                InvalidateTlb::NoInvalidate,
+            );
+        }
+
+        page_addr += 4096;
+    }
+}
+
+/// Unmaps and frees a heap page, unless it was never touched and so never mapped
+unsafe fn unmap_if_mapped(mapper: &mut ActivePageMap, page: Page) {
+    if mapper.walk_page_table(page).is_some() {
+        mapper.unmap(page, FreeMemory::Free, InvalidateTlb::Invalidate);
+    }
+}
+
+impl Heap {
+    unsafe fn alloc_raw(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| match slab::size_class(&layout) {
+            Some(class) => self.slabs.alloc(class, self),
+            None => self.alloc_inner(layout),
+        })
+    }
+
+    unsafe fn dealloc_raw(&self, ptr: *mut u8, layout: Layout) {
+        if ptr.is_null() {
+            return;
+        }
+
+        interrupts::without_interrupts(|| match slab::size_class(&layout) {
+            Some(class) => self.slabs.dealloc(ptr, class, self),
+            None => self.dealloc_inner(ptr, layout),
+        })
+    }
+}
+
+// The heap lock must never be held by a thread that gets preempted, or another thread allocating
+// with interrupts disabled (e.g the scheduler) would deadlock. Hence, interrupts are disabled for
+// the duration of every heap operation.
+//
+// With the `debug` feature, allocations are checked for corruption (see `memory::heap_debug`), and
+// with the `alloc_tracking` feature, live allocations are recorded (see `memory::alloc_tracking`).
+// Reallocations are done in place when possible, and by copying otherwise.
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "debug")]
+        let ptr = interrupts::without_interrupts(|| {
+            DEBUG_HEAP.lock().alloc(layout, |outer| self.alloc_raw(outer))
+        });
+        #[cfg(not(feature = "debug"))]
+        let ptr = self.alloc_raw(layout);
+
+        #[cfg(feature = "alloc_tracking")]
+        alloc_tracking::record_alloc(ptr, layout);
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if ptr.is_null() {
+            return;
+        }
+
+        #[cfg(feature = "alloc_tracking")]
+        alloc_tracking::record_dealloc(ptr);
+
+        #[cfg(feature = "debug")]
+        interrupts::without_interrupts(|| {
+            DEBUG_HEAP.lock().dealloc(ptr, layout, |block, outer| self.dealloc_raw(block, outer))
+        });
+        #[cfg(not(feature = "debug"))]
+        self.dealloc_raw(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+
+        // Debug allocations record their size in their header and red zones, so they are always
+        // moved. This also makes uses of the old pointer show up as reads of freed memory.
+        #[cfg(not(feature = "debug"))]
+        {
+            if self.realloc_in_place(ptr, layout, new_size) {
+                #[cfg(feature = "alloc_tracking")]
+                {
+                    alloc_tracking::record_dealloc(ptr);
+                    alloc_tracking::record_alloc(ptr, new_layout);
+                }
+
+                return ptr;
+            }
+        }
+
+        let new_ptr = self.alloc(new_layout);
+
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, cmp::min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+
+        new_ptr
+    }
+}
 
 /// Converts log2 to order (NOT minus 1)
 fn order(val: usize) -> u8 {
@@ -0,0 +1,320 @@
+//! Heap debugging, enabled by the `debug` feature. Every allocation is surrounded by red zones and
+//! preceded by a header recording its layout. Fresh memory is filled with [ALLOC_POISON] and freed
+//! memory with [FREE_POISON], so that reads of uninitialised or freed memory stand out. Overwritten
+//! red zones, double frees and frees with the wrong layout panic with the offending address.
+//!
+//! ```text
+//! | padding | header | front red zone | object | back red zone |
+//!                                      ^ pointer handed out
+//! ```
+//!
+//! Whether a block is allocated is kept outside of it (see [AllocatedBits]), as the underlying
+//! allocator reuses freed memory for its own bookkeeping and unmaps freed pages. Freed blocks are
+//! also held back in a quarantine for a while before being handed to the underlying allocator, so
+//! that a double free is caught even if it comes a bit after the first free.
+
+use core::{cmp, mem, ptr, slice};
+use core::alloc::Layout;
+
+/// Byte that fresh allocations are filled with
+pub const ALLOC_POISON: u8 = 0xCD;
+/// Byte that freed allocations are filled with
+pub const FREE_POISON: u8 = 0xDD;
+/// Byte that red zones are filled with
+pub const RED_ZONE_BYTE: u8 = 0xFD;
+/// Every underlying block is aligned to at least this, so there is at most one block per this many
+/// bytes
+pub const BLOCK_ALIGN: usize = 64;
+/// Minimum size of each red zone
+const RED_ZONE_SIZE: usize = 16;
+/// Number of freed blocks held back from the underlying allocator
+const QUARANTINE_SIZE: usize = 64;
+
+const HEADER_MAGIC: u64 = 0xA110_CA7E_DB10_C000;
+
+#[repr(C)]
+struct Header {
+    /// Always `HEADER_MAGIC`, to tell if the header was overwritten
+    magic: u64,
+    size: usize,
+    align: usize,
+}
+
+/// Keeps the allocated bit of every underlying block, by the block's address
+pub trait AllocatedBits {
+    fn is_allocated(&self, block: usize) -> bool;
+    fn set_allocated(&mut self, block: usize, allocated: bool);
+}
+
+/// Where the parts of a debug allocation lie, relative to the start of the underlying block
+struct DebugLayout {
+    /// The layout of the underlying block
+    outer: Layout,
+    /// Offset of the object, which is also the end of the front red zone
+    object: usize,
+}
+
+impl DebugLayout {
+    fn new(layout: &Layout) -> Option<Self> {
+        let align = cmp::max(layout.align(), mem::align_of::<Header>());
+        let front = mem::size_of::<Header>() + RED_ZONE_SIZE;
+        let object = (front + align - 1) & !(align - 1);
+        let size = object.checked_add(layout.size())?.checked_add(RED_ZONE_SIZE)?;
+        let outer = Layout::from_size_align(size, cmp::max(align, BLOCK_ALIGN)).ok()?;
+
+        Some(DebugLayout { outer, object })
+    }
+
+    /// The header lies right before the front red zone
+    fn header(&self) -> usize {
+        self.object - RED_ZONE_SIZE - mem::size_of::<Header>()
+    }
+}
+
+/// The allocated bits and quarantine of a debug heap
+pub struct DebugHeap<B> {
+    allocated: B,
+    /// Freed blocks which have not been handed back to the underlying allocator yet, along with
+    /// their layouts
+    quarantine: [Option<(usize, Layout)>; QUARANTINE_SIZE],
+    /// The slot of the oldest block in the quarantine
+    oldest: usize,
+}
+
+impl<B> DebugHeap<B> {
+    pub const fn new(allocated: B) -> Self {
+        DebugHeap { allocated, quarantine: [None; QUARANTINE_SIZE], oldest: 0 }
+    }
+}
+
+impl<B: AllocatedBits> DebugHeap<B> {
+    /// Allocates memory for the layout through `alloc_raw`, wrapping it in red zones and poisoning
+    /// it
+    pub unsafe fn alloc<F: FnOnce(Layout) -> *mut u8>(
+        &mut self,
+        layout: Layout,
+        alloc_raw: F,
+    ) -> *mut u8 {
+        let debug_layout = match DebugLayout::new(&layout) {
+            Some(debug_layout) => debug_layout,
+            None => return ptr::null_mut(),
+        };
+
+        let block = alloc_raw(debug_layout.outer);
+        if block.is_null() {
+            return block;
+        }
+
+        self.allocated.set_allocated(block as usize, true);
+
+        let object = block.add(debug_layout.object);
+
+        ptr::write(
+            block.add(debug_layout.header()) as *mut Header,
+            Header { magic: HEADER_MAGIC, size: layout.size(), align: layout.align() },
+        );
+
+        ptr::write_bytes(object.sub(RED_ZONE_SIZE), RED_ZONE_BYTE, RED_ZONE_SIZE);
+        ptr::write_bytes(object, ALLOC_POISON, layout.size());
+        ptr::write_bytes(object.add(layout.size()), RED_ZONE_BYTE, RED_ZONE_SIZE);
+
+        object
+    }
+
+    /// Checks and poisons an allocation made through [DebugHeap::alloc], then puts it in the
+    /// quarantine. The oldest block in the quarantine is freed through `dealloc_raw` if it is full.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if the allocation was already freed, was not allocated through [DebugHeap::alloc],
+    /// was allocated with a different layout, or if one of its red zones was overwritten.
+    pub unsafe fn dealloc<F: FnOnce(*mut u8, Layout)>(
+        &mut self,
+        ptr: *mut u8,
+        layout: Layout,
+        dealloc_raw: F,
+    ) {
+        let debug_layout = DebugLayout::new(&layout).expect("Invalid layout passed to dealloc!");
+        let block = ptr.sub(debug_layout.object);
+
+        // Checked before anything in the block is read, as it may be unmapped once freed
+        if !self.allocated.is_allocated(block as usize) {
+            panic!("heap: double free of {:?}, or it was not allocated", ptr);
+        }
+
+        let header = &*(block.add(debug_layout.header()) as *const Header);
+
+        if header.magic != HEADER_MAGIC {
+            panic!(
+                "heap: freeing {:?}, whose header was overwritten (magic 0x{:x})",
+                ptr,
+                header.magic,
+            );
+        }
+
+        if header.size != layout.size() || header.align != layout.align() {
+            panic!(
+                "heap: layout mismatch freeing {:?}: allocated with size {} and align {}, but \
+                 freed with size {} and align {}",
+                ptr,
+                header.size,
+                header.align,
+                layout.size(),
+                layout.align(),
+            );
+        }
+
+        check_red_zone(ptr, ptr.sub(RED_ZONE_SIZE), "before");
+        check_red_zone(ptr, ptr.add(layout.size()), "after");
+
+        self.allocated.set_allocated(block as usize, false);
+        ptr::write_bytes(ptr.sub(RED_ZONE_SIZE), FREE_POISON, layout.size() + RED_ZONE_SIZE * 2);
+
+        let freed = Some((block as usize, debug_layout.outer));
+        let evicted = mem::replace(&mut self.quarantine[self.oldest], freed);
+        self.oldest = (self.oldest + 1) % QUARANTINE_SIZE;
+
+        if let Some((oldest, outer)) = evicted {
+            dealloc_raw(oldest as *mut u8, outer);
+        }
+    }
+}
+
+unsafe fn check_red_zone(object: *mut u8, zone: *mut u8, position: &str) {
+    let bytes = slice::from_raw_parts(zone, RED_ZONE_SIZE);
+
+    if let Some(offset) = bytes.iter().position(|&byte| byte != RED_ZONE_BYTE) {
+        panic!(
+            "heap: red zone {} {:?} overwritten at {:?} (found 0x{:x})",
+            position,
+            object,
+            zone.add(offset),
+            bytes[offset],
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::alloc::{alloc as std_alloc, dealloc as std_dealloc};
+    use std::collections::HashSet;
+    use super::*;
+
+    impl AllocatedBits for HashSet<usize> {
+        fn is_allocated(&self, block: usize) -> bool {
+            self.contains(&block)
+        }
+
+        fn set_allocated(&mut self, block: usize, allocated: bool) {
+            if allocated {
+                self.insert(block);
+            } else {
+                self.remove(&block);
+            }
+        }
+    }
+
+    unsafe fn test_alloc(heap: &mut DebugHeap<HashSet<usize>>, layout: Layout) -> *mut u8 {
+        heap.alloc(layout, |outer| std_alloc(outer))
+    }
+
+    unsafe fn test_dealloc(heap: &mut DebugHeap<HashSet<usize>>, ptr: *mut u8, layout: Layout) {
+        heap.dealloc(ptr, layout, |block, outer| std_dealloc(block, outer));
+    }
+
+    #[test]
+    fn test_debug_alloc() {
+        let mut heap = DebugHeap::new(HashSet::new());
+        let layout = Layout::from_size_align(100, 64).unwrap();
+
+        unsafe {
+            let ptr = test_alloc(&mut heap, layout);
+            assert_eq!(ptr as usize % 64, 0);
+            assert!(slice::from_raw_parts(ptr, 100).iter().all(|&byte| byte == ALLOC_POISON));
+
+            ptr::write_bytes(ptr, 0, 100);
+            test_dealloc(&mut heap, ptr, layout);
+        }
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let mut heap = DebugHeap::new(HashSet::new());
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let mut freed = 0;
+
+        unsafe {
+            for _ in 0..QUARANTINE_SIZE + 1 {
+                let ptr = test_alloc(&mut heap, layout);
+                assert_eq!(ptr as usize % 8, 0);
+
+                heap.dealloc(ptr, layout, |block, outer| {
+                    freed += 1;
+                    std_dealloc(block, outer);
+                });
+            }
+        }
+
+        assert_eq!(freed, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn test_double_free() {
+        let mut heap = DebugHeap::new(HashSet::new());
+        let layout = Layout::from_size_align(32, 8).unwrap();
+
+        unsafe {
+            let ptr = test_alloc(&mut heap, layout);
+            test_dealloc(&mut heap, ptr, layout);
+            test_dealloc(&mut heap, ptr, layout);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn test_double_free_after_quarantine() {
+        let mut heap = DebugHeap::new(HashSet::new());
+        let layout = Layout::from_size_align(32, 8).unwrap();
+
+        unsafe {
+            let ptr = test_alloc(&mut heap, layout);
+            test_dealloc(&mut heap, ptr, layout);
+
+            // Push the block out of the quarantine, so that it is really freed. The other blocks
+            // are kept allocated so that it is not reused.
+            let others: Vec<_> = (0..QUARANTINE_SIZE)
+                .map(|_| test_alloc(&mut heap, layout))
+                .collect();
+            for other in others {
+                test_dealloc(&mut heap, other, layout);
+            }
+
+            test_dealloc(&mut heap, ptr, layout);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "layout mismatch")]
+    fn test_layout_mismatch() {
+        let mut heap = DebugHeap::new(HashSet::new());
+
+        unsafe {
+            let ptr = test_alloc(&mut heap, Layout::from_size_align(32, 8).unwrap());
+            test_dealloc(&mut heap, ptr, Layout::from_size_align(48, 8).unwrap());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "red zone after")]
+    fn test_overflow() {
+        let mut heap = DebugHeap::new(HashSet::new());
+        let layout = Layout::from_size_align(32, 8).unwrap();
+
+        unsafe {
+            let ptr = test_alloc(&mut heap, layout);
+            *ptr.add(32) = 0;
+            test_dealloc(&mut heap, ptr, layout);
+        }
+    }
+}
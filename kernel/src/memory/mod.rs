@@ -7,37 +7,152 @@
 //!
 //! | Address range                             |  Usage                    |
 //! |-------------------------------------------|---------------------------|
+//! | `0x0` ~ `0x00007fffffffffff`              | User space                |
+//! | `0xffff800000000000` ~ . + 64TiB          | Direct map of RAM         |
+//! | `0xffffffff00000000` ~ . + 1GiB           | Kernel thread stacks      |
 //! | `0xffffffff40000000` ~ . + 1GiB           | Kernel heap               |
 //! | `0xffffffff800b8000` ~ . + `0x1000`       | VGA frame buffer          |
 //! | `0xffffffff80100000` + 1MiB ~ kernel end  | Kernel elf                |
 //! | . ~ . + size of bootstrap heap            | Bootstrap heap            |
 //! | . ~ . + size of heap buddy allocator tree | Heap buddy allocator tree |
-//! | . ~ . + 7 * size of stack                 | IST stacks                |
+//! | . ~ . + 7 * size of stack * max CPUs      | IST stacks                |
 
 #[macro_use]
 mod buddy_allocator;
 pub mod paging;
 pub mod heap;
+pub mod slab;
+#[cfg(feature = "debug")]
+pub mod heap_debug;
+#[cfg(feature = "alloc_tracking")]
+pub mod alloc_tracking;
 pub mod bootstrap_heap;
 pub mod physical_allocator;
+pub mod frame_refcount;
 pub mod physical_mapping;
-mod stack_allocator;
-
-use core::{mem, iter, ops::{Range, RangeInclusive}};
-use x86_64::structures::tss::TaskStateSegment;
-use arrayvec::ArrayVec;
+pub mod dma;
+pub mod numa;
+pub mod stack_allocator;
+pub mod address_space;
+pub mod demand_paging;
+pub mod stats;
+pub mod reclaim;
+
+use core::{cmp, mem, iter, ops::{Range, RangeInclusive}};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, Once, RwLock};
+use alloc::vec::Vec;
+use arrayvec::{ArrayVec, ArrayString};
 use multiboot2::{self, BootInformation, MemoryMapTag};
 use self::physical_allocator::{PHYSICAL_ALLOCATOR, BLOCKS_IN_TREE};
 use self::buddy_allocator::Block;
 use self::stack_allocator::StackAllocator;
 use self::bootstrap_heap::{BootstrapHeap, BOOTSTRAP_HEAP};
 use self::paging::{Page, PageSize, PhysicalAddress, VirtualAddress, PAGE_TABLES, EntryFlags,
-                   PageRangeMapping, remap, InvalidateTlb};
+                   PageRangeMapping, remap, InvalidateTlb, FreeMemory};
 use crate::util::round_up_divide;
-use crate::gdt;
+use crate::{backtrace, smp};
 
 pub const KERNEL_MAPPING_BEGIN: usize = 0xffffffff80000000;
-const IST_STACK_SIZE_PAGES: usize = 3;
+/// The start of the direct map, where every usable physical address `p` is mapped at
+/// `PHYSICAL_MAP_BEGIN + p`
+pub const PHYSICAL_MAP_BEGIN: usize = 0xffff800000000000;
+/// The end of the space reserved for the direct map (64TiB)
+pub const PHYSICAL_MAP_END: usize = PHYSICAL_MAP_BEGIN + (1 << 46);
+/// Size of each IST stack in 4kib pages, not including its guard page. Panics run on the panicking
+/// exception stack, and need room for formatting register dumps and symbolized backtraces.
+const IST_STACK_SIZE_PAGES: usize = 4;
+const IST_STACKS_PER_CPU: usize = 7;
+const MAX_BOOT_MODULES: usize = 16;
+/// The kernel, the multiboot2 info, the kernel's symbol and string tables and the boot modules
+const MAX_BOOT_AREAS: usize = MAX_BOOT_MODULES + 4;
+const MAX_MEMORY_AREAS: usize = 64;
+/// The legacy VGA framebuffer, option ROMs and BIOS, which some memory maps do not exclude
+const LEGACY_VIDEO_AND_BIOS: Range<usize> = 0xA0000..0x100000;
+
+static IST_STACK_ALLOCATOR: Once<Mutex<IstStackAllocator>> = Once::new();
+static BOOT_MODULES: Once<ArrayVec<[BootModule; MAX_BOOT_MODULES]>> = Once::new();
+/// The guard page below the boot stack, set up by `boot.asm`
+static BOOT_GUARD_PAGE: AtomicUsize = AtomicUsize::new(0);
+/// The physical ranges which are direct mapped
+static DIRECT_MAP: Once<RwLock<ArrayVec<[Range<usize>; MAX_MEMORY_AREAS]>>> = Once::new();
+
+/// A multiboot2 module loaded by the bootloader. Its memory is never handed out by the physical
+/// allocator.
+#[derive(Debug, Clone)]
+pub struct BootModule {
+    /// The module's command line, truncated if too long
+    pub name: ArrayString<[u8; 64]>,
+    pub physical: Range<usize>,
+}
+
+/// The modules passed by the bootloader. Empty until memory is initialised.
+pub fn boot_modules() -> &'static [BootModule] {
+    BOOT_MODULES.r#try().map(|modules| modules.as_slice()).unwrap_or(&[])
+}
+
+/// Returns the address at which a physical address is mapped in the direct map. Only usable RAM
+/// (as reported by the bootloader's memory map) is direct mapped.
+pub fn phys_to_virt(address: PhysicalAddress) -> VirtualAddress {
+    debug_assert!(
+        is_direct_mapped(&(address.0..address.0 + 1)),
+        "Physical address 0x{:x} is not direct mapped!",
+        address.0,
+    );
+    VirtualAddress(PHYSICAL_MAP_BEGIN + address.0)
+}
+
+/// Translates a virtual address into a physical address. Addresses in the direct map are
+/// translated directly, while anything else goes through the page tables.
+///
+/// # Deadlocking
+///
+/// Locks `PAGE_TABLES` for addresses outside of the direct map, so the caller must not hold it.
+pub fn virt_to_phys(address: VirtualAddress) -> Option<PhysicalAddress> {
+    if in_direct_map(address.0) {
+        Some(PhysicalAddress(address.0 - PHYSICAL_MAP_BEGIN))
+    } else {
+        crate::interrupts::without_interrupts(|| PAGE_TABLES.lock().translate(address.0))
+    }
+}
+
+/// Whether the whole physical range is reachable through the direct map. Always false before the
+/// direct map is set up.
+pub fn is_direct_mapped(range: &Range<usize>) -> bool {
+    DIRECT_MAP.r#try()
+        .map(|areas| {
+            areas.read().iter().any(|area| area.start <= range.start && range.end <= area.end)
+        })
+        .unwrap_or(false)
+}
+
+/// Adds a page aligned physical range to the direct map, e.g for memory handed back to the
+/// physical allocator which was not usable at boot. Returns false if there are too many areas.
+fn extend_direct_map(range: Range<usize>) -> bool {
+    let mut areas = DIRECT_MAP.wait().expect("Direct map not set up!").write();
+
+    if areas.is_full() {
+        return false;
+    }
+
+    crate::interrupts::without_interrupts(|| unsafe {
+        PAGE_TABLES.lock().map_contiguous(
+            PHYSICAL_MAP_BEGIN + range.start,
+            PhysicalAddress(range.start),
+            range.end - range.start,
+            EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+            InvalidateTlb::Invalidate,
+        );
+    });
+
+    areas.push(range);
+    true
+}
+
+/// Whether the virtual address lies in the space reserved for the direct map
+pub fn in_direct_map(address: usize) -> bool {
+    address >= PHYSICAL_MAP_BEGIN && address < PHYSICAL_MAP_END
+}
 
 pub fn init_memory(mb_info_addr: usize, guard_page_addr: usize) {
     info!("mem: initialising");
@@ -51,21 +166,39 @@ pub fn init_memory(mb_info_addr: usize, guard_page_addr: usize) {
 
     print_memory_info(memory_map);
 
+    // The multiboot2 info is not mapped after the remap, so the module list is copied out now
+    let modules = boot_module_list(&mb_info);
+    let symbol_table = backtrace::init_symbols(&mb_info);
+    reclaim::set_acpi_reclaimable(acpi_reclaimable_areas(memory_map));
+    let direct_map = direct_map_areas(memory_map);
+
     debug!("mem: initialising bootstrap heap");
     let (bootstrap_heap_phys, bootstrap_heap_virtual) = unsafe {
-        let physical_start = PhysicalAddress(mb_info_phys.end() + 1); // TODO what if really high and no more space ?
+        let mut used_areas: ArrayVec<[Range<usize>; MAX_BOOT_AREAS]> = ArrayVec::new();
+        used_areas.push(0..kernel_area.end() - KERNEL_MAPPING_BEGIN + 1);
+        used_areas.push(*mb_info_phys.start()..*mb_info_phys.end() + 1);
+
+        if let Some(table) = symbol_table {
+            used_areas.push(table.symbols.clone());
+            used_areas.push(table.names.clone());
+        }
+
+        used_areas.extend(modules.iter().map(|module| module.physical.clone()));
+
+        let physical_start = bootstrap_heap_location(memory_map, &used_areas);
         let virtual_start = VirtualAddress(kernel_area.end() + 1);
 
          setup_bootstrap_heap(virtual_start, physical_start)
     };
 
     debug!("mem: initialising pmm (1/2)");
-    let (gibbibytes, usable) = unsafe {
+    let usable = unsafe {
         setup_physical_allocator_prelim(
             &mb_info,
             mb_info_phys,
             bootstrap_heap_phys.clone(),
-            kernel_area
+            kernel_area,
+            &modules,
         )
     };
 
@@ -78,10 +211,11 @@ pub fn init_memory(mb_info_addr: usize, guard_page_addr: usize) {
     let heap_tree_end = heap_tree_start + heap::Heap::tree_size();
 
     debug!("mem: initialising pmm (2/2)");
-    unsafe { setup_physical_allocator_rest(gibbibytes, usable.iter()) };
+    unsafe { setup_physical_allocator_rest(usable.iter(), &modules) };
 
     debug!("mem: remapping kernel");
-    remap::remap_kernel(&mb_info, heap_tree_start);
+    remap::remap_kernel(&mb_info, heap_tree_start, &direct_map);
+    DIRECT_MAP.call_once(|| RwLock::new(direct_map));
 
     trace!("mem: setting up guard page");
     unsafe { setup_guard_page(guard_page_addr) };
@@ -94,6 +228,8 @@ pub fn init_memory(mb_info_addr: usize, guard_page_addr: usize) {
 
     unsafe { setup_ist( page) }
 
+    BOOT_MODULES.call_once(|| modules);
+
     info!("mem: initialised");
 }
 
@@ -112,41 +248,234 @@ fn print_memory_info(memory_map: &MemoryMapTag) {
         .map(|area| (area.end_address() - area.start_address()) as usize)
         .sum();
 
+    stats::set_total_memory(bytes_available);
+
     let gibbibytes_available = bytes_available as f64 / (1 << 30) as f64;
     info!("{:.3} GiB of RAM available", gibbibytes_available);
 }
 
-unsafe fn setup_ist(begin: Page) {
-    let mut allocator = StackAllocator::new(begin, 7, IST_STACK_SIZE_PAGES);
-
-    let pages = IST_STACK_SIZE_PAGES * 7;
-
-    for page in 0..pages {
-        if page % IST_STACK_SIZE_PAGES == 0 {
-            // Page is guard page: do not map
-        } else {
-            PAGE_TABLES.lock().map(
-                Page::containing_address(begin.start_address().unwrap() + (page * 4096), PageSize::Kib4),
-                EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
-                InvalidateTlb::Invalidate,
-            );
+/// Returns the page aligned physical ranges of RAM to direct map
+fn direct_map_areas(memory_map: &MemoryMapTag) -> ArrayVec<[Range<usize>; MAX_MEMORY_AREAS]> {
+    let mut areas = ArrayVec::new();
+
+    for area in memory_map.memory_areas() {
+        let start = round_up_divide(area.start_address() as u64, 4096) as usize * 4096;
+        let end = area.end_address() as usize & !0xFFF;
+
+        if start >= end {
+            continue;
+        }
+
+        if areas.try_push(start..end).is_err() {
+            warn!("mem: more than {} memory areas, not direct mapping the rest", MAX_MEMORY_AREAS);
+            break;
+        }
+    }
+
+    areas
+}
+
+/// Returns the page aligned physical ranges of ACPI reclaimable memory, which can be handed back
+/// to the physical allocator once the ACPI tables in it are parsed. `MemoryMapTag::memory_areas`
+/// only yields usable areas, so the tag is read as laid out in the multiboot2 specification.
+fn acpi_reclaimable_areas(memory_map: &MemoryMapTag) -> ArrayVec<[Range<usize>; MAX_MEMORY_AREAS]> {
+    #[repr(C)]
+    struct RawMemoryMapTag {
+        typ: u32,
+        size: u32,
+        entry_size: u32,
+        entry_version: u32,
+    }
+
+    #[repr(C)]
+    struct RawMemoryArea {
+        base_addr: u64,
+        length: u64,
+        typ: u32,
+        reserved: u32,
+    }
+
+    const ACPI_RECLAIMABLE: u32 = 3;
+
+    let mut areas = ArrayVec::new();
+    let tag = memory_map as *const MemoryMapTag as *const RawMemoryMapTag;
+    let (tag_size, entry_size) = unsafe { ((*tag).size as usize, (*tag).entry_size as usize) };
+
+    let mut entry = tag as usize + mem::size_of::<RawMemoryMapTag>();
+    let tag_end = tag as usize + tag_size;
+
+    while entry_size != 0 && entry + mem::size_of::<RawMemoryArea>() <= tag_end {
+        let area = unsafe { &*(entry as *const RawMemoryArea) };
+        entry += entry_size;
+
+        if area.typ != ACPI_RECLAIMABLE {
+            continue;
+        }
+
+        // Only whole frames can be handed back
+        let start = round_up_divide(area.base_addr, 4096) as usize * 4096;
+        let end = (area.base_addr + area.length) as usize & !0xFFF;
+
+        if start < end && areas.try_push(start..end).is_err() {
+            warn!("mem: more than {} ACPI reclaimable areas, ignoring the rest", MAX_MEMORY_AREAS);
+            break;
         }
     }
 
-    gdt::TSS.call_once(|| {
-        let mut tss = TaskStateSegment::new();
+    areas
+}
 
-        for i in 0..7 { // Packed struct; cannot safely borrow fields
-            let stack_start = allocator.alloc().unwrap();
-            let stack_end = stack_start as u64 + (IST_STACK_SIZE_PAGES * 4096) as u64;
+fn boot_module_list(mb_info: &BootInformation) -> ArrayVec<[BootModule; MAX_BOOT_MODULES]> {
+    let mut modules = ArrayVec::new();
 
-            tss.interrupt_stack_table[i] = x86_64::VirtAddr::new(stack_end);
+    for tag in mb_info.module_tags() {
+        let mut name = ArrayString::new();
+        for character in tag.name().chars() {
+            if name.try_push(character).is_err() {
+                break;
+            }
         }
 
-        tss
+        let module = BootModule {
+            name,
+            physical: tag.start_address() as usize..tag.end_address() as usize,
+        };
+
+        debug!(
+            "mem: module \"{}\" at 0x{:x} to 0x{:x}",
+            module.name,
+            module.physical.start,
+            module.physical.end,
+        );
+
+        if modules.try_push(module).is_err() {
+            warn!("mem: more than {} boot modules, ignoring the rest", MAX_BOOT_MODULES);
+            break;
+        }
+    }
+
+    modules
+}
+
+struct IstStackAllocator {
+    allocator: StackAllocator,
+    /// The bottoms of stacks which were freed, to be handed out again
+    free: Vec<usize>,
+}
+
+unsafe fn setup_ist(begin: Page) {
+    IST_STACK_ALLOCATOR.call_once(|| {
+        Mutex::new(IstStackAllocator {
+            allocator: StackAllocator::new(
+                begin,
+                IST_STACKS_PER_CPU * smp::MAX_CPUS,
+                IST_STACK_SIZE_PAGES,
+            ),
+            free: Vec::new(),
+        })
     });
 }
 
+/// Allocates and maps a set of IST stacks for one CPU, returning the top of each stack. The page
+/// below each stack is left unmapped as a guard page.
+pub fn alloc_ist_stacks() -> [usize; IST_STACKS_PER_CPU] {
+    let mut stacks = [0; IST_STACKS_PER_CPU];
+
+    // Page tables are locked with interrupts disabled by the heap, so they must never be held by a
+    // preempted thread
+    crate::interrupts::without_interrupts(|| {
+        let mut allocator = IST_STACK_ALLOCATOR.wait().expect("IST stacks not set up!").lock();
+        let mut page_tables = PAGE_TABLES.lock();
+
+        for stack in stacks.iter_mut() {
+            let bottom = match allocator.free.pop() {
+                Some(bottom) => bottom,
+                None => allocator.allocator.alloc().expect("Ran out of IST stacks!") as usize,
+            };
+
+            for page in 0..IST_STACK_SIZE_PAGES {
+                unsafe {
+                    page_tables.map(
+                        Page::containing_address(bottom + (page * 4096), PageSize::Kib4),
+                        EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+                        InvalidateTlb::Invalidate,
+                    );
+                }
+            }
+
+            *stack = bottom + (IST_STACK_SIZE_PAGES * 4096);
+        }
+    });
+
+    stacks
+}
+
+/// Unmaps IST stacks allocated by [alloc_ist_stacks], given their tops, and returns them to the
+/// allocator.
+///
+/// # Unsafety
+///
+/// Unsafe as the stacks must not be in use by any CPU.
+pub unsafe fn free_ist_stacks(tops: &[usize]) {
+    crate::interrupts::without_interrupts(|| {
+        let mut page_tables = PAGE_TABLES.lock();
+
+        for &top in tops {
+            let bottom = top - (IST_STACK_SIZE_PAGES * 4096);
+
+            for page in 0..IST_STACK_SIZE_PAGES {
+                page_tables.unmap(
+                    Page::containing_address(bottom + (page * 4096), PageSize::Kib4),
+                    FreeMemory::Free,
+                    InvalidateTlb::Invalidate,
+                );
+            }
+        }
+    });
+
+    // Not done while the page tables are locked, as growing the free list may map heap pages
+    let mut allocator = IST_STACK_ALLOCATOR.wait().expect("IST stacks not set up!").lock();
+    allocator.free.extend(tops.iter().map(|top| top - (IST_STACK_SIZE_PAGES * 4096)));
+}
+
+/// Finds the lowest physical memory which is large enough to hold the bootstrap heap and does not
+/// overlap any of the given areas, which are in use. Modules and the multiboot2 info may be loaded
+/// anywhere, so the memory map must be searched.
+fn bootstrap_heap_location(memory_map: &MemoryMapTag, used: &[Range<usize>]) -> PhysicalAddress {
+    // The heap begins on the frame after the one containing its start address
+    let size = BootstrapHeap::space_taken() + 2 * 4096;
+
+    let mut lowest = None;
+
+    for area in memory_map.memory_areas() {
+        // Subtracting each used area can split a free area in two, so there is at most one more
+        // free area than used areas
+        let mut free: ArrayVec<[Range<usize>; MAX_BOOT_AREAS + 1]> = ArrayVec::new();
+        free.push(area.start_address() as usize..area.end_address() as usize);
+
+        for used_area in used {
+            let remaining = mem::replace(&mut free, ArrayVec::new());
+
+            for free_area in remaining {
+                let [first, second] = range_sub(&free_area, used_area);
+                free.extend(first.into_iter().chain(second));
+            }
+        }
+
+        let start = free.iter()
+            .filter(|free_area| free_area.end - free_area.start >= size)
+            .map(|free_area| free_area.start)
+            .min();
+
+        lowest = match (lowest, start) {
+            (Some(lowest), Some(start)) => Some(cmp::min(lowest, start)),
+            (lowest, start) => lowest.or(start),
+        };
+    }
+
+    PhysicalAddress(lowest.expect("No physical memory large enough for the bootstrap heap!"))
+}
+
 /// Sets up the bootstrap heap and returns its physical address range and its virtual address range
 /// (physical in the tuple first).
 ///
@@ -194,19 +523,11 @@ unsafe fn setup_physical_allocator_prelim(
     mb_info_phys: RangeInclusive<usize>,
     bootstrap_heap_phys: RangeInclusive<usize>,
     kernel_area: RangeInclusive<usize>,
-) -> (u8, ArrayVec<[Range<usize>; 256]>) {
+    modules: &[BootModule],
+) -> ArrayVec<[Range<usize>; 256]> {
     let memory_map = mb_info.memory_map_tag()
         .expect("Expected a multiboot2 memory map tag, but it is not present!");
 
-    let highest_address = memory_map.memory_areas()
-        .map(|area| area.end_address() - 1)
-        .max()
-        .expect("No usable physical memory available!");
-
-    // Do round-up division by 2^30 = 1GiB in bytes
-    let trees = round_up_divide(highest_address as u64, 1 << 30) as u8;
-    trace!("Allocating {} trees", trees);
-
     // Calculate the usable memory areas by using the MB2 memory map but excluding kernel areas
     let usable_areas = memory_map
         .memory_areas()
@@ -234,29 +555,50 @@ unsafe fn setup_physical_allocator_prelim(
     let usable_areas = usable_areas.collect::<ArrayVec<[_; 256]>>();
 
     PHYSICAL_ALLOCATOR.init_prelim(usable_areas.iter());
+    reserve_boot_areas(modules);
 
-    (trees, usable_areas)
+    usable_areas
 }
 
-unsafe fn setup_physical_allocator_rest<'a, I>(gibbibytes: u8, usable_areas: I)
-    where I: Iterator<Item=&'a Range<usize>> + Clone + 'a
+unsafe fn setup_physical_allocator_rest<'a, I>(
+    usable_areas: I,
+    modules: &[BootModule],
+) where I: Iterator<Item=&'a Range<usize>> + Clone + 'a
 {
-    PHYSICAL_ALLOCATOR.init_rest(
-        gibbibytes,
-        usable_areas,
-    );
+    PHYSICAL_ALLOCATOR.init_rest(usable_areas);
+
+    // Reserve again for the trees which were just set up. Already reserved frames are left as is.
+    reserve_boot_areas(modules);
+}
+
+/// Reserves physical memory which is in use, but may be marked as usable in the memory map
+fn reserve_boot_areas(modules: &[BootModule]) {
+    PHYSICAL_ALLOCATOR.reserve(LEGACY_VIDEO_AND_BIOS);
+
+    for module in modules {
+        PHYSICAL_ALLOCATOR.reserve(module.physical.clone());
+    }
+
+    // Kept for symbolizing backtraces
+    if let Some(table) = backtrace::symbol_table() {
+        PHYSICAL_ALLOCATOR.reserve(table.symbols.clone());
+        PHYSICAL_ALLOCATOR.reserve(table.names.clone());
+    }
 }
 
 unsafe fn setup_guard_page(addr: usize) {
     use self::paging::*;
 
+    // The kernel's sections may be mapped with huge pages, which unmapping splits
     let page = Page::containing_address(addr, PageSize::Kib4);
-
-    // Check it is a 4kib page
-    let size = PAGE_TABLES.lock().walk_page_table(page).expect("Guard page must be mapped!").1;
-    assert_eq!(size, PageSize::Kib4, "Guard page must be on a 4kib page!");
-
     PAGE_TABLES.lock().unmap(page, FreeMemory::NoFree, InvalidateTlb::Invalidate);
+    BOOT_GUARD_PAGE.store(addr & !0xFFF, Ordering::Relaxed);
+}
+
+/// Whether the address lies in the guard page below the boot stack
+pub fn is_boot_stack_guard_page(address: usize) -> bool {
+    let guard_page = BOOT_GUARD_PAGE.load(Ordering::Relaxed);
+    guard_page != 0 && address & !0xFFF == guard_page
 }
 
 fn kernel_area(mb_info: &BootInformation) -> RangeInclusive<usize> {
@@ -265,8 +607,10 @@ fn kernel_area(mb_info: &BootInformation) -> RangeInclusive<usize> {
     let elf_sections = mb_info.elf_sections_tag()
         .expect("Expected a multiboot2 elf sections tag, but it is not present!");
 
+    // Sections which are not allocated (e.g the symbol table) are loaded elsewhere by the
+    // bootloader, and are kept by `reserve_boot_areas` if needed
     let used_areas = elf_sections.sections()
-        .filter(|section| section.flags().contains(ElfSectionFlags::ALLOCATED)) // TODO this will overwrite debug info
+        .filter(|section| section.flags().contains(ElfSectionFlags::ALLOCATED))
         .map(|section| section.start_address()..section.end_address() + 1);
 
     let begin = used_areas.clone().map(
@@ -286,7 +630,9 @@ fn range_sub<T>(
 ) -> [Option<Range<T>>; 2]
     where T: Ord + Copy,
 {
-    if sub.start <= main.start { // Hole starts before range
+    if sub.end <= main.start || sub.start >= main.end { // Hole is outside of range -- full range
+        [Some(main.start..main.end), None]
+    } else if sub.start <= main.start { // Hole starts before range
         if sub.end < main.end { // Hole covers entire bottom section of range  -- only top section
             [None, Some(sub.end..main.end)]
         } else { // Hole covers entire range -- no range
@@ -0,0 +1,234 @@
+//! NUMA topology, read from the ACPI SRAT (which proximity domain each CPU and range of memory
+//! belongs to) and SLIT (the relative distances between domains). Nodes are identified by their
+//! proximity domain. Without an SRAT, the system is treated as having a single node and the
+//! physical allocator ignores nodes altogether.
+
+use core::{cmp, mem, ptr};
+use arrayvec::ArrayVec;
+use spin::Once;
+use crate::acpi_impl::{self, AcpiTable};
+use crate::smp::{self, percpu};
+use super::physical_allocator::PHYSICAL_ALLOCATOR;
+
+/// The number of nodes whose distances are kept from the SLIT
+const MAX_NODES: usize = 16;
+/// The distance from a node to itself
+pub const LOCAL_DISTANCE: usize = 10;
+/// The distance between two different nodes when the SLIT does not say
+pub const REMOTE_DISTANCE: usize = 20;
+/// The largest distance, which the SLIT uses for nodes which cannot reach each other
+pub const MAX_DISTANCE: usize = 255;
+
+/// Reserved bytes between the SRAT's header and its entries
+const SRAT_RESERVED: usize = 12;
+const SRAT_PROCESSOR_AFFINITY: u8 = 0;
+const SRAT_MEMORY_AFFINITY: u8 = 1;
+const SRAT_X2APIC_AFFINITY: u8 = 2;
+/// Set in the flags of an SRAT entry if it is to be used
+const SRAT_ENABLED: u32 = 1 << 0;
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C, packed)]
+struct ProcessorAffinity {
+    entry_type: u8,
+    length: u8,
+    domain_low: u8,
+    apic_id: u8,
+    flags: u32,
+    sapic_eid: u8,
+    domain_high: [u8; 3],
+    clock_domain: u32,
+}
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C, packed)]
+struct MemoryAffinity {
+    entry_type: u8,
+    length: u8,
+    domain: u32,
+    reserved: u16,
+    base_address: u64,
+    region_length: u64,
+    reserved_1: u32,
+    flags: u32,
+    reserved_2: u64,
+}
+
+#[derive(Debug, Copy, Clone)]
+#[repr(C, packed)]
+struct X2ApicAffinity {
+    entry_type: u8,
+    length: u8,
+    reserved: u16,
+    domain: u32,
+    x2apic_id: u32,
+    flags: u32,
+    clock_domain: u32,
+    reserved_1: u32,
+}
+
+struct Topology {
+    /// The node of each CPU, by APIC ID
+    cpus: ArrayVec<[(u32, usize); smp::MAX_CPUS]>,
+    /// The distances between the first [MAX_NODES] nodes, if there is a SLIT
+    distances: Option<[[u8; MAX_NODES]; MAX_NODES]>,
+}
+
+static TOPOLOGY: Once<Topology> = Once::new();
+
+/// Reads the SRAT and SLIT, tagging the physical allocator's memory with the node it belongs to.
+/// Does nothing if there is no SRAT. Must be called after ACPI has been initialised and the BSP's
+/// per-CPU data has been set up.
+pub fn init() {
+    let srat = match acpi_impl::find_table(b"SRAT") {
+        Some(srat) => srat,
+        None => {
+            info!("numa: no srat, assuming a single node");
+            return;
+        }
+    };
+
+    let mut cpus = ArrayVec::new();
+    let mut nodes: ArrayVec<[usize; MAX_NODES]> = ArrayVec::new();
+
+    let mut add_node = |node| {
+        if !nodes.contains(&node) {
+            let _ = nodes.try_push(node);
+        }
+    };
+
+    for (entry_type, entry) in srat_entries(&srat) {
+        match entry_type {
+            SRAT_PROCESSOR_AFFINITY => {
+                let affinity: ProcessorAffinity = match read(entry) {
+                    Some(affinity) => affinity,
+                    None => continue,
+                };
+
+                let high = affinity.domain_high;
+                let node = affinity.domain_low as usize |
+                    (high[0] as usize) << 8 |
+                    (high[1] as usize) << 16 |
+                    (high[2] as usize) << 24;
+
+                if affinity.flags & SRAT_ENABLED != 0 {
+                    let _ = cpus.try_push((affinity.apic_id as u32, node));
+                    add_node(node);
+                }
+            },
+            SRAT_X2APIC_AFFINITY => {
+                let affinity: X2ApicAffinity = match read(entry) {
+                    Some(affinity) => affinity,
+                    None => continue,
+                };
+
+                if affinity.flags & SRAT_ENABLED != 0 {
+                    let _ = cpus.try_push((affinity.x2apic_id, affinity.domain as usize));
+                    add_node(affinity.domain as usize);
+                }
+            },
+            SRAT_MEMORY_AFFINITY => {
+                let affinity: MemoryAffinity = match read(entry) {
+                    Some(affinity) => affinity,
+                    None => continue,
+                };
+
+                if affinity.flags & SRAT_ENABLED == 0 {
+                    continue;
+                }
+
+                let start = affinity.base_address as usize;
+                let end = start + affinity.region_length as usize;
+                let node = affinity.domain as usize;
+
+                debug!("numa: 0x{:x} to 0x{:x} is in node {}", start, end, node);
+                PHYSICAL_ALLOCATOR.set_node(start..end, node);
+                add_node(node);
+            },
+            _ => (),
+        }
+    }
+
+    let distances = acpi_impl::find_table(b"SLIT").and_then(|slit| parse_slit(&slit));
+
+    if distances.is_none() {
+        debug!("numa: no slit, using default distances");
+    }
+
+    info!("numa: {} nodes, {} cpus", nodes.len(), cpus.len());
+    TOPOLOGY.call_once(|| Topology { cpus, distances });
+}
+
+/// Iterates over the type and bytes of each entry in the SRAT
+fn srat_entries<'a>(srat: &'a AcpiTable) -> impl Iterator<Item = (u8, &'a [u8])> + 'a {
+    let entries = srat.body().get(SRAT_RESERVED..).unwrap_or(&[]);
+    let mut offset = 0;
+
+    (0..).map(move |_| {
+        let header = entries.get(offset..offset + 2)?;
+        let length = header[1] as usize;
+        let entry = entries.get(offset..offset + length).filter(|_| length >= 2)?;
+
+        offset += length;
+        Some((header[0], entry))
+    })
+    .take_while(Option::is_some)
+    .filter_map(|entry| entry)
+}
+
+/// Reads the distance matrix from the SLIT, keeping the first [MAX_NODES] nodes
+fn parse_slit(slit: &AcpiTable) -> Option<[[u8; MAX_NODES]; MAX_NODES]> {
+    let localities = read::<u64>(slit.body())? as usize;
+    let matrix = slit.body().get(mem::size_of::<u64>()..)?;
+
+    if matrix.len() < localities.checked_mul(localities)? {
+        return None;
+    }
+
+    let mut distances = [[MAX_DISTANCE as u8; MAX_NODES]; MAX_NODES];
+    let count = cmp::min(localities, MAX_NODES);
+
+    for from in 0..count {
+        for to in 0..count {
+            distances[from][to] = matrix[from * localities + to];
+        }
+    }
+
+    Some(distances)
+}
+
+/// Reads a structure from the start of the data, if it fits
+fn read<T: Copy>(data: &[u8]) -> Option<T> {
+    if data.len() >= mem::size_of::<T>() {
+        Some(unsafe { ptr::read_unaligned(data.as_ptr() as *const T) })
+    } else {
+        None
+    }
+}
+
+/// Whether the SRAT has been read, i.e whether allocations should take nodes into account
+pub fn is_numa() -> bool {
+    TOPOLOGY.r#try().is_some()
+}
+
+/// The node of the current CPU, or node 0 if it is not known
+pub fn current_node() -> usize {
+    let apic_id = percpu::current().apic_id as u32;
+
+    TOPOLOGY.r#try()
+        .and_then(|topology| topology.cpus.iter().find(|&&(id, _)| id == apic_id))
+        .map(|&(_, node)| node)
+        .unwrap_or(0)
+}
+
+/// The relative distance between two nodes, where a node's distance to itself is
+/// [LOCAL_DISTANCE]. Nodes which are not in the SLIT are assumed to be [REMOTE_DISTANCE] apart.
+pub fn distance(from: usize, to: usize) -> usize {
+    let distances = TOPOLOGY.r#try().and_then(|topology| topology.distances.as_ref());
+
+    match distances {
+        Some(distances) if from < MAX_NODES && to < MAX_NODES => distances[from][to] as usize,
+        _ if from == to => LOCAL_DISTANCE,
+        _ => REMOTE_DISTANCE,
+    }
+}
@@ -7,12 +7,32 @@ pub use self::page_map::*;
 
 use core::{marker::PhantomData, ptr::Unique};
 use core::ops::{Add, Index, IndexMut};
-use spin::Mutex;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, Once};
 use super::physical_allocator::PHYSICAL_ALLOCATOR;
 use x86_64::instructions::tlb;
+use crate::cpuid;
 
 const PAGE_TABLE_ENTRIES: usize = 512;
 pub static PAGE_TABLES: Mutex<ActivePageMap> = Mutex::new(unsafe { ActivePageMap::new() });
+static GIB1_PAGES_SUPPORTED: Once<bool> = Once::new();
+/// Number of frames used by page tables that the kernel allocated (i.e not those set up at boot)
+static PAGE_TABLE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the number of frames used by page tables allocated since boot
+pub fn page_table_frames() -> usize {
+    PAGE_TABLE_FRAMES.load(Ordering::Relaxed)
+}
+
+/// Keeps count of frames used by page tables. To be called whenever a frame starts or stops being
+/// used as a page table.
+pub(in crate::memory) fn count_page_table_frame(allocated: bool) {
+    if allocated {
+        PAGE_TABLE_FRAMES.fetch_add(1, Ordering::Relaxed);
+    } else {
+        PAGE_TABLE_FRAMES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone, Ord, PartialOrd)]
 pub struct PhysicalAddress(pub usize);
@@ -21,11 +41,13 @@ pub struct PhysicalAddress(pub usize);
 pub struct VirtualAddress(pub usize);
 
 /// The size of a page. Distinct from `memory::PageSize` in that it only enumerates page sizes
-/// supported by the paging module at this time.
+/// supported by the paging module at this time. 1GiB pages are only usable if the CPU supports them
+/// (see [PageSize::gib1_supported]).
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Ord, PartialOrd)]
 pub enum PageSize {
     Kib4,
     Mib2,
+    Gib1,
 }
 
 impl PageSize {
@@ -35,6 +57,47 @@ impl PageSize {
         match self {
             Kib4 => 4 * 1024,
             Mib2 => 2 * 1024 * 1024,
+            Gib1 => 1024 * 1024 * 1024,
+        }
+    }
+
+    /// The order of a physical allocation of one page of this size
+    fn physical_order(self) -> u8 {
+        use self::PageSize::*;
+
+        match self {
+            Kib4 => 0,
+            Mib2 => 9,
+            Gib1 => 18,
+        }
+    }
+
+    /// Whether the CPU supports 1GiB pages
+    pub fn gib1_supported() -> bool {
+        *GIB1_PAGES_SUPPORTED.call_once(|| {
+            cpuid::extended_features().contains(cpuid::ExtendedFeatures::GIB_PAGES)
+        })
+    }
+
+    /// Returns the largest supported page size which both addresses are aligned to and which fits
+    /// in `length` bytes
+    pub fn largest_fitting(
+        virtual_address: usize,
+        physical_address: usize,
+        length: usize,
+    ) -> PageSize {
+        let fits = |size: PageSize| {
+            virtual_address % size.bytes() == 0 &&
+                physical_address % size.bytes() == 0 &&
+                length >= size.bytes()
+        };
+
+        if PageSize::gib1_supported() && fits(PageSize::Gib1) {
+            PageSize::Gib1
+        } else if fits(PageSize::Mib2) {
+            PageSize::Mib2
+        } else {
+            PageSize::Kib4
         }
     }
 }
@@ -166,6 +229,9 @@ bitflags! {
         const HUGE_PAGE = 1 << 7;
         /// If set, this page will not be flushed in the TLB. PGE bit in CR4 must be set.
         const GLOBAL = 1 << 8;
+        /// Ignored by the CPU. Set on read only user pages whose frame is shared copy on write, so
+        /// that the page fault handler gives the page a copy of its own when it is written to.
+        const COPY_ON_WRITE = 1 << 9;
         /// Do not allow executing code from this page. NXE bit in EFER must be set.
         const NO_EXECUTE = 1 << 63;
     }
@@ -218,6 +284,11 @@ impl<L: TableLevel> PageTable<L> {
         }
     }
 
+    /// Whether no entry of the table is in use
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| entry.0 == 0)
+    }
+
     fn next_table_addr(&self, index: usize) -> Option<usize>
         where L: HierarchicalLevel
     {
@@ -252,23 +323,40 @@ impl<L: TableLevel> PageTable<L> {
     }
 
 
-    pub fn next_table_create(&mut self, index: usize) -> Option<&mut PageTable<L::NextLevel>>
+    /// Returns the next table at the index, creating it if it does not exist. Tables which lead to
+    /// user pages (`user`) are made user accessible, so that the flags of the final entry alone
+    /// decide whether a page can be accessed from ring 3. Other tables are never user accessible,
+    /// so kernel mappings cannot be reached from ring 3 even if their flags are wrong.
+    pub fn next_table_create(
+        &mut self,
+        index: usize,
+        user: bool,
+    ) -> Option<&mut PageTable<L::NextLevel>>
         where L: HierarchicalLevel
     {
+        let mut table_flags = self::EntryFlags::PRESENT | self::EntryFlags::WRITABLE;
+
+        if user {
+            table_flags |= self::EntryFlags::USER_ACCESSIBLE;
+        }
+
         if self.next_page_table(index).is_none() {
             if self.entries[index].flags().contains(self::EntryFlags::HUGE_PAGE){
                 assert!(L::CAN_BE_HUGE, "Page has huge bit but cannot be huge!");
             } else {
                 let ptr = PHYSICAL_ALLOCATOR.allocate(0).expect("No physical frames available!");
                 let frame = PhysicalAddress(ptr as usize);
+                count_page_table_frame(true);
 
-                self.entries[index].set(
-                    frame,
-                    self::EntryFlags::PRESENT |
-                        self::EntryFlags::WRITABLE
-                );
+                self.entries[index].set(frame, table_flags);
                 self.next_page_table_mut(index).expect("No next table!").zero();
             }
+        } else if user && !self.entries[index].flags().contains(self::EntryFlags::USER_ACCESSIBLE) {
+            // A table which was created for kernel pages now leads to a user page as well
+            let entry = &mut self.entries[index];
+            let frame = entry.physical_address().expect("Table entry is not present!");
+            let flags = entry.flags() | self::EntryFlags::USER_ACCESSIBLE;
+            entry.set(frame, flags);
         }
 
         self.next_page_table_mut(index)
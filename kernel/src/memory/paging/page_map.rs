@@ -6,7 +6,6 @@ use core::ops::{Deref, DerefMut};
 use core::ops::RangeInclusive;
 use crate::util::{self, round_up_divide};
 use core::ops::Range;
-use alloc::vec::Vec;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum FreeMemory {
@@ -52,9 +51,15 @@ impl Mapper {
             p3.and_then(|p3| {
                 // 1GiB page
                 let p3_entry = &p3[page.p3_index()];
-                if p3_entry.physical_address().is_some() {
+                if let Some(start_frame) = p3_entry.physical_address() {
                     if p3_entry.flags().contains(EntryFlags::HUGE_PAGE) {
-                        panic!("1 GiB pages are not supported!");
+                        // Check that the address is 1GiB aligned
+                        assert_eq!(
+                            start_frame.0 % PageSize::Gib1.bytes(),
+                            0,
+                            "Adress is not 1GiB aligned!"
+                        );
+                        return Some((*p3_entry, PageSize::Gib1));
                     }
                 }
 
@@ -94,6 +99,15 @@ impl Mapper {
             .or_else(huge_page)
     }
 
+    /// Translates a virtual address into the physical address it is mapped to, whatever the size
+    /// of the page it lies in
+    pub fn translate(&self, address: usize) -> Option<PhysicalAddress> {
+        let (entry, size) = self.walk_page_table(Page::containing_address(address, PageSize::Kib4))?;
+        let base = entry.physical_address()?;
+
+        Some(PhysicalAddress(base.0 + address % size.bytes()))
+    }
+
     pub unsafe fn map_to(
         &mut self,
         page: Page,
@@ -101,37 +115,73 @@ impl Mapper {
         flags: EntryFlags,
         invplg: InvalidateTlb,
     ) {
-        let mut p2 = self.p4_mut()
-            .next_table_create(page.p4_index()).expect("No next p3 table!")
-            .next_table_create(page.p3_index()).expect("No next p2 table!");
-
         assert!(page.size.is_some(), "Page to map requires size!");
+        let size = page.size.unwrap();
 
-        if page.size.unwrap() == PageSize::Kib4 {
+        assert_eq!(
+            physical_address.0 % size.bytes(),
+            0,
+            "Physical address 0x{:x} is not aligned to the page size {:?}!",
+            physical_address.0,
+            size,
+        );
 
-            let mut p1 = match p2.next_table_create(page.p2_index()) {
-                Some(p1) => p1,
-                None => {
-                    if p2[page.p2_index()].flags().contains(EntryFlags::HUGE_PAGE) {
-                        panic!("No next p1 table - the area is mapped in 2mib pages")
-                    } else {
-                        panic!("No next p1 table (unknown reason)")
+        let user = flags.contains(EntryFlags::USER_ACCESSIBLE);
+        let p3 = self.p4_mut()
+            .next_table_create(page.p4_index(), user).expect("No next p3 table!");
+
+        match size {
+            PageSize::Gib1 => {
+                assert!(PageSize::gib1_supported(), "1GiB pages are not supported by this CPU!");
+                assert!(
+                    p3.next_page_table(page.p3_index()).is_none(),
+                    "Cannot map a 1GiB page - the area is already mapped in smaller pages",
+                );
+
+                p3[page.p3_index()].set(
+                    physical_address,
+                    flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE,
+                );
+            },
+            PageSize::Mib2 => {
+                let p2 = p3.next_table_create(page.p3_index(), user)
+                    .expect("No next p2 table - the area is mapped in 1gib pages");
+
+                assert!(
+                    p2.next_page_table(page.p2_index()).is_none(),
+                    "Cannot map a 2MiB page - the area is already mapped in 4kib pages",
+                );
+
+                p2[page.p2_index()].set(
+                    physical_address,
+                    flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE,
+                );
+            },
+            PageSize::Kib4 => {
+                let p2 = p3.next_table_create(page.p3_index(), user)
+                    .expect("No next p2 table - the area is mapped in 1gib pages");
+
+                let p1 = match p2.next_table_create(page.p2_index(), user) {
+                    Some(p1) => p1,
+                    None => {
+                        if p2[page.p2_index()].flags().contains(EntryFlags::HUGE_PAGE) {
+                            panic!("No next p1 table - the area is mapped in 2mib pages")
+                        } else {
+                            panic!("No next p1 table (unknown reason)")
+                        }
                     }
-                }
-            };
-
-
-            // 4kib page
-            p1[page.p1_index()].set(
-                physical_address,
-                flags | EntryFlags::PRESENT,
-            );
+                };
+
+                // 4kib page
+                p1[page.p1_index()].set(
+                    physical_address,
+                    flags | EntryFlags::PRESENT,
+                );
+            },
+        }
 
-            if invplg == InvalidateTlb::Invalidate {
-                tlb::flush(::x86_64::VirtAddr::new(page.start_address().unwrap() as u64));
-            }
-        } else {
-            panic!("2mib pages are only partially supported!");
+        if invplg == InvalidateTlb::Invalidate {
+            tlb::flush(::x86_64::VirtAddr::new(page.start_address().unwrap() as u64));
         }
     }
 
@@ -139,11 +189,7 @@ impl Mapper {
         use core::ptr;
 
         assert!(page.size.is_some(), "Page needs size!");
-        let order = if page.size.unwrap() == PageSize::Kib4 {
-            0
-        } else {
-            9
-        };
+        let order = page.size.unwrap().physical_order();
 
         let ptr = PHYSICAL_ALLOCATOR.allocate(order).expect("Out of physical memory!");
         let frame = PhysicalAddress(ptr as usize);
@@ -157,48 +203,216 @@ impl Mapper {
         );
     }
 
+    /// Unmaps the given page. If it lies in a larger page, that page is first split into pages of
+    /// the given size, so that the rest of it stays mapped.
+    ///
+    /// # Panicking
+    ///
+    /// Panics if the page is not mapped, if it is mapped with smaller pages, or if part of a larger
+    /// page is to be freed, as its frame was allocated as a whole.
     pub unsafe fn unmap(&mut self, page: Page, free_physmem: FreeMemory, invplg: InvalidateTlb) {
-        assert!(page.start_address().is_some(), "Page to map requires size!");
+        let size = page.size.expect("Page to unmap requires size!");
+
+        let mapped_size = match self.walk_page_table(page) {
+            Some((_, mapped_size)) => mapped_size,
+            None => panic!("Virtual address 0x{:x} is not mapped!", page.start_address().unwrap()),
+        };
+
         assert!(
-            self.walk_page_table(page).is_some(),
-            "Virtual address 0x{:x} is not mapped!",
-            page.start_address().unwrap()
+            mapped_size.bytes() >= size.bytes(),
+            "Cannot unmap a {:?} page at 0x{:x} - it is mapped in smaller pages",
+            size,
+            page.start_address().unwrap(),
         );
 
-        let mut p2 = self.p4_mut()
-            .next_page_table_mut(page.p4_index()).expect("Unmap called on unmapped page!")
-            .next_page_table_mut(page.p3_index()).expect("Unmap called on unmapped page!");
+        if mapped_size != size {
+            assert!(
+                free_physmem == FreeMemory::NoFree,
+                "Cannot free part of the {:?} page containing 0x{:x}",
+                mapped_size,
+                page.start_address().unwrap(),
+            );
 
-        let p1 = p2.next_page_table_mut(page.p2_index());
+            self.split_huge_pages(page);
+        }
 
-        if let Some(p1) = p1 {
-            // 4kib page
+        {
+            let p3 = self.p4_mut()
+                .next_page_table_mut(page.p4_index()).expect("Unmap called on unmapped page!");
+
+            let entry = match size {
+                PageSize::Gib1 => &mut p3[page.p3_index()],
+                PageSize::Mib2 => {
+                    let p2 = p3.next_page_table_mut(page.p3_index())
+                        .expect("Unmap called on unmapped page!");
+                    &mut p2[page.p2_index()]
+                },
+                PageSize::Kib4 => {
+                    let p1 = p3.next_page_table_mut(page.p3_index())
+                        .and_then(|p2| p2.next_page_table_mut(page.p2_index()))
+                        .expect("Unmap called on unmapped page!");
+                    &mut p1[page.p1_index()]
+                },
+            };
 
-            let frame = p1[page.p1_index()].physical_address().expect("Page already unmapped!");
-            p1[page.p1_index()].set_unused();
+            let frame = entry.physical_address().expect("Page already unmapped!");
+            entry.set_unused();
 
-            // TODO free p1/p2/p3 tables if they are empty
             if free_physmem == FreeMemory::Free {
-                PHYSICAL_ALLOCATOR.deallocate(frame.0 as *const _, 0);
+                PHYSICAL_ALLOCATOR.deallocate(frame.0 as *const _, size.physical_order());
             }
-        } else {
-            // Huge 2mib page
+        }
 
-            let frame = p2[page.p2_index()].physical_address().expect("Page already unmapped!");
-            p2[page.p2_index()].set_unused();
+        self.free_empty_tables(page);
 
-            // TODO free p2/p3 tables if they are empty
-            if free_physmem == FreeMemory::Free {
-                PHYSICAL_ALLOCATOR.deallocate(frame.0 as *const _, 9);
+        if invplg == InvalidateTlb::Invalidate {
+            // Flush tlb
+            tlb::flush(::x86_64::VirtAddr::new(page.start_address().unwrap() as u64));
+        }
+    }
+
+    /// Splits the huge pages containing the given page until it is mapped with its own size. The
+    /// rest of the memory they mapped stays mapped with the same flags.
+    unsafe fn split_huge_pages(&mut self, page: Page) {
+        let size = page.size.expect("Page to split requires size!");
+        let p3 = self.p4_mut()
+            .next_page_table_mut(page.p4_index()).expect("Split called on unmapped page!");
+
+        if p3[page.p3_index()].flags().contains(EntryFlags::HUGE_PAGE) {
+            split_huge_page(&mut p3[page.p3_index()], PageSize::Mib2);
+        }
+
+        if size == PageSize::Kib4 {
+            let p2 = p3.next_page_table_mut(page.p3_index())
+                .expect("Split called on unmapped page!");
+
+            if p2[page.p2_index()].flags().contains(EntryFlags::HUGE_PAGE) {
+                split_huge_page(&mut p2[page.p2_index()], PageSize::Kib4);
+            }
+        }
+    }
+
+    /// Unmaps every mapped page in the given page aligned range, skipping pages which are not
+    /// mapped. Huge pages which only partly overlap the range are split, so that the memory they
+    /// map outside of it stays mapped. Page tables which become empty are freed as with
+    /// [Mapper::unmap].
+    pub unsafe fn unmap_range(
+        &mut self,
+        addresses: Range<usize>,
+        free_physmem: FreeMemory,
+        invplg: InvalidateTlb,
+    ) {
+        let mut address = addresses.start;
+
+        while address < addresses.end {
+            let page = Page::containing_address(address, PageSize::Kib4);
+
+            match self.walk_page_table(page) {
+                Some((_, size)) => {
+                    let start = address & !(size.bytes() - 1);
+                    let end = start + size.bytes();
+
+                    if start >= addresses.start && end <= addresses.end {
+                        self.unmap(Page::containing_address(start, size), free_physmem, invplg);
+                        address = end;
+                    } else {
+                        self.unmap(page, free_physmem, invplg);
+                        address += 4096;
+                    }
+                },
+                None => address += 4096,
             }
         }
+    }
+
+    /// Frees the page tables leading to a page which have become empty, starting from the table
+    /// which mapped it, and clears the entries pointing to them. In the kernel half, only P1
+    /// tables are freed: its P3 tables are shared with every user page map, and lazy regions rely
+    /// on their P2 tables always existing.
+    unsafe fn free_empty_tables(&mut self, page: Page) {
+        let size = page.size.expect("Page to free the tables of requires size!");
+        let lower_half = page.p4_index() < 256;
+
+        let p4 = self.p4_mut();
+        let p3 = match p4.next_page_table_mut(page.p4_index()) {
+            Some(p3) => p3,
+            None => return,
+        };
+
+        // A 1GiB page was mapped by the P3 table itself
+        if size != PageSize::Gib1 {
+            let p2 = match p3.next_page_table_mut(page.p3_index()) {
+                Some(p2) => p2,
+                None => return,
+            };
+
+            // A 2MiB page was mapped by the P2 table, so there is no P1 table
+            if size == PageSize::Kib4 {
+                free_table_if_empty(p2, page.p2_index());
+            }
+
+            if !lower_half || !p2.is_empty() {
+                return;
+            }
+
+            free_table_if_empty(p3, page.p3_index());
+        }
+
+        if lower_half && p3.is_empty() {
+            free_table_if_empty(p4, page.p4_index());
+        }
+    }
+
+    /// Creates the page tables down to the P2 table which covers the given page, if they do not
+    /// exist yet
+    pub fn create_p2_table(&mut self, page: Page) {
+        self.p4_mut()
+            .next_table_create(page.p4_index(), false).expect("No next p3 table!")
+            .next_table_create(page.p3_index(), false).expect("No next p2 table!");
+    }
+
+    /// Changes the flags of a mapped 4kib page, keeping the frame it is mapped to
+    pub unsafe fn set_flags(&mut self, page: Page, flags: EntryFlags, invplg: InvalidateTlb) {
+        let p1 = self.p4_mut()
+            .next_page_table_mut(page.p4_index())
+            .and_then(|p3| p3.next_page_table_mut(page.p3_index()))
+            .and_then(|p2| p2.next_page_table_mut(page.p2_index()))
+            .expect("Page is not mapped in a 4kib page!");
+
+        let frame = p1[page.p1_index()].physical_address().expect("Page is not mapped!");
+        p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
 
         if invplg == InvalidateTlb::Invalidate {
-            // Flush tlb
             tlb::flush(::x86_64::VirtAddr::new(page.start_address().unwrap() as u64));
         }
     }
 
+    /// Frees every page table in the lower half, leaving it empty. Does not free the frames that
+    /// the tables map.
+    pub unsafe fn free_lower_half_tables(&mut self) {
+        let p4 = self.p4_mut();
+
+        for p4_index in 0..256 {
+            if let Some(p3) = p4.next_page_table_mut(p4_index) {
+                for p3_index in 0..PAGE_TABLE_ENTRIES {
+                    if let Some(p2) = p3.next_page_table_mut(p3_index) {
+                        for p2_index in 0..PAGE_TABLE_ENTRIES {
+                            if p2.next_page_table(p2_index).is_some() {
+                                free_table(&mut p2[p2_index]);
+                            }
+                        }
+
+                        free_table(&mut p3[p3_index]);
+                    }
+                }
+
+                free_table(&mut p4[p4_index]);
+            }
+        }
+
+        tlb::flush_all();
+    }
+
     /// Identity maps a range of addresses as 4 kib pages
     pub unsafe fn id_map_range(
         &mut self,
@@ -217,48 +431,131 @@ impl Mapper {
         }
     }
 
-    /// Maps a range of higher half addresses as 4kib pages in the -2GiB higher "half", mapping
-    /// them to their address minus `KERNEL_MAPPING_BEGIN`.
+    /// Maps a range of higher half addresses in the -2GiB higher "half", mapping them to their
+    /// address minus `KERNEL_MAPPING_BEGIN`. The largest page size that fits is used for each part
+    /// of the range. Only the pages overlapping the range are mapped, not the one after its end.
     pub unsafe fn higher_half_map_range(
         &mut self,
         addresses: Range<usize>,
         flags: EntryFlags,
         invplg: InvalidateTlb,
     ) {
-        let frame_end = round_up_divide(addresses.end as u64, 4096) as usize;
-        for frame_no in (addresses.start / 4096)..=frame_end  {
-            let address = frame_no * 4096;
-
-            self.map_to(
-                Page::containing_address(address, PageSize::Kib4),
-                PhysicalAddress((address - crate::memory::KERNEL_MAPPING_BEGIN) as usize),
-                flags,
-                invplg,
-            );
-        }
+        let start = addresses.start & !0xFFF;
+        let end = round_up_divide(addresses.end as u64, 4096) as usize * 4096;
+
+        self.map_contiguous(
+            start,
+            PhysicalAddress(start - crate::memory::KERNEL_MAPPING_BEGIN),
+            end - start,
+            flags,
+            invplg,
+        );
     }
 
+    /// Maps a page range mapping, using the largest page size that fits for each part of it
     pub unsafe fn map_page_range(
         &mut self,
         mapping: PageRangeMapping,
         invplg: InvalidateTlb,
         flags: EntryFlags
     ) {
-        let frames = mapping.start_frame..=mapping.start_frame + mapping.pages.size_hint().1.unwrap();
+        let pages = mapping.pages.end() - mapping.pages.start() + 1;
+
+        self.map_contiguous(
+            mapping.pages.start() * 4096,
+            PhysicalAddress(mapping.start_frame * 4096),
+            pages * 4096,
+            flags,
+            invplg,
+        );
+    }
 
-        for (frame_no, page_no) in frames.zip(mapping.pages) {
-            let phys_address = frame_no * 4096;
-            let virtual_address = page_no * 4096;
+    /// Maps a contiguous range of virtual memory to a contiguous range of physical memory, picking
+    /// the largest page size which both addresses are aligned to at every point
+    pub unsafe fn map_contiguous(
+        &mut self,
+        virtual_start: usize,
+        physical_start: PhysicalAddress,
+        length: usize,
+        flags: EntryFlags,
+        invplg: InvalidateTlb,
+    ) {
+        let mut offset = 0;
+
+        while offset < length {
+            let virtual_address = virtual_start + offset;
+            let physical_address = physical_start.0 + offset;
+            let size = PageSize::largest_fitting(virtual_address, physical_address, length - offset);
 
             self.map_to(
-                Page::containing_address(virtual_address, PageSize::Kib4),
-                PhysicalAddress(phys_address as usize),
+                Page::containing_address(virtual_address, size),
+                PhysicalAddress(physical_address),
                 flags,
                 invplg,
             );
+
+            offset += size.bytes();
         }
     }
+}
+
+/// The table in the given frame, reached through the direct map
+unsafe fn direct_mapped_table<L: TableLevel>(frame: PhysicalAddress) -> &'static mut PageTable<L> {
+    &mut *(crate::memory::phys_to_virt(frame).0 as *mut PageTable<L>)
+}
+
+/// Frees the frame of the table that an entry points to and clears the entry
+fn free_table(entry: &mut PageTableEntry) {
+    let frame = entry.physical_address().expect("Table entry is not present!");
+    PHYSICAL_ALLOCATOR.deallocate(frame.0 as *const u8, 0);
+    count_page_table_frame(false);
+    entry.set_unused();
+}
+
+/// Replaces the huge page mapped by an entry with a table mapping the same memory in pages of the
+/// next size down, with the same flags. The table is filled through the direct map before it is
+/// put in place, so that the memory stays mapped throughout (it may hold the current stack).
+unsafe fn split_huge_page(entry: &mut PageTableEntry, smaller: PageSize) {
+    let frame = entry.physical_address().expect("Huge page is not mapped!");
+    let mut flags = entry.flags();
+
+    if smaller == PageSize::Kib4 {
+        flags.remove(EntryFlags::HUGE_PAGE);
+    }
+
+    let table_frame = PHYSICAL_ALLOCATOR.allocate(0).expect("No physical frames available!");
+    let table_frame = PhysicalAddress(table_frame as usize);
+    count_page_table_frame(true);
+
+    let table = crate::memory::phys_to_virt(table_frame).0 as *mut PageTableEntry;
+
+    for index in 0..PAGE_TABLE_ENTRIES {
+        let frame = PhysicalAddress(frame.0 + index * smaller.bytes());
+        (*table.add(index)).set(frame, flags);
+    }
+
+    // The new table is only user accessible if the huge page was
+    let table_flags = EntryFlags::PRESENT | EntryFlags::WRITABLE |
+        (flags & EntryFlags::USER_ACCESSIBLE);
+    entry.set(table_frame, table_flags);
+
+    // Both the huge page and the recursive mapping of the new table (which used to point into the
+    // huge page) may be cached
+    tlb::flush_all();
+}
+
+/// Frees the table that an entry points to if the table is empty, returning whether it was freed.
+/// The recursive mapping of the table is flushed from the TLB.
+unsafe fn free_table_if_empty<L: HierarchicalLevel>(table: &mut PageTable<L>, index: usize) -> bool {
+    let table_address = match table.next_page_table(index) {
+        Some(next) if next.is_empty() => next as *const _ as u64,
+        _ => return false,
+    };
+
+    free_table(&mut table[index]);
+    tlb::flush(::x86_64::VirtAddr::new(table_address));
 
+    true
 }
 
 /// A 4kib page range mapping -- represents a contigous area of 4kib pages mapped to a contigous
@@ -284,48 +581,6 @@ impl PageRangeMapping {
     }
 }
 
-pub struct TemporaryPage {
-    page: Page,
-}
-
-impl TemporaryPage {
-    pub fn new(page: Page) -> TemporaryPage {
-        TemporaryPage { page }
-    }
-
-    /// Maps the temporary page to the given frame in the active table.
-    /// Returns the start address of the temporary page.
-    pub unsafe fn map(
-        &mut self,
-        frame: PhysicalAddress,
-        active_table: &mut ActivePageMap
-    ) -> VirtualAddress {
-        let page_addr = self.page.start_address().expect("Temporary page requires size");
-        assert!(
-            active_table.walk_page_table(self.page).is_none(),
-            "Temporary page {:?} at 0x{:x} is already mapped",
-            self.page,
-            page_addr,
-        );
-
-        active_table.map_to(self.page, frame, EntryFlags::WRITABLE, InvalidateTlb::Invalidate);
-        VirtualAddress(self.page.start_address().expect("Page in TemporaryPage requires size"))
-    }
-
-    /// Unmaps the temporary page in the active table.
-    pub unsafe fn unmap(&mut self, active_table: &mut ActivePageMap) {
-        active_table.unmap(self.page, FreeMemory::NoFree, InvalidateTlb::NoInvalidate,);
-    }
-
-    pub unsafe fn map_table_frame(
-        &mut self,
-        frame: PhysicalAddress,
-        active_table: &mut ActivePageMap
-    ) -> &mut PageTable<Level1> {
-        &mut *(self.map(frame, active_table).0 as *mut PageTable<Level1>)
-    }
-}
-
 pub struct ActivePageMap {
     mapper: Mapper,
 }
@@ -337,80 +592,50 @@ impl ActivePageMap {
         }
     }
 
+    /// Runs the closure with the mapper pointed at the inactive page map, by overwriting the
+    /// recursive mapping of the active P4 table meanwhile. The active P4 table is put back through
+    /// the direct map, as it cannot be reached recursively in the meantime.
     pub fn with_inactive_p4<F: FnOnce(&mut ActivePageMap) -> R, R>(
         &mut self,
         table: &mut InactivePageMap,
-        temporary_page: &mut TemporaryPage,
         f: F
     ) -> R {
-        let ret = {
-            let backup = PhysicalAddress(util::cr3() as usize);
-
-            // map temporary_page to current p4 table
-            let p4_table = unsafe {
-                temporary_page.map_table_frame(backup.clone(), self)
-            };
+        let active_p4 = unsafe { direct_mapped_table(PhysicalAddress(util::cr3() as usize)) };
+        self.with_recursive_p4(active_p4, table, f)
+    }
 
-            // overwrite recursive mapping
-            self.p4_mut()[510].set(
-                table.p4_frame.clone(),
-                EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE
-            );
+    /// Like [ActivePageMap::with_inactive_p4], but given a mapping of the active P4 table to put
+    /// its recursive mapping back through
+    pub(super) fn with_recursive_p4<F: FnOnce(&mut ActivePageMap) -> R, R>(
+        &mut self,
+        active_p4: &mut PageTable<Level4>,
+        table: &mut InactivePageMap,
+        f: F
+    ) -> R {
+        let backup = PhysicalAddress(util::cr3() as usize);
 
-            tlb::flush_all();
+        // overwrite recursive mapping
+        self.p4_mut()[510].set(
+            table.p4_frame.clone(),
+            EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE
+        );
 
-            // execute f in the new context
-            let ret = f(self);
+        tlb::flush_all();
 
-            // restore recursive mapping to original p4 table
-            p4_table[510].set(
-                backup,
-              EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE
-            );
+        // execute f in the new context
+        let ret = f(self);
 
-            tlb::flush_all();
-
-            ret
-        };
+        // restore recursive mapping to original p4 table
+        active_p4[510].set(
+            backup,
+            EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE
+        );
 
-        unsafe {
-            temporary_page.unmap(self);
-        }
+        tlb::flush_all();
 
         ret
     }
 
-    pub fn remap_range(
-        &mut self,
-        new_table: &mut InactivePageMap,
-        temporary_page: &mut TemporaryPage,
-        pages: RangeInclusive<usize>,
-        flags: EntryFlags
-    ) {
-        let num_pages = pages.end() - pages.start();
-        let mut frames = Vec::with_capacity(num_pages);
-        for i in 0..=num_pages {
-            let page = Page::containing_address(
-                (i + pages.start()) * 4096,
-                PageSize::Kib4
-            );
-
-            let entry = PAGE_TABLES.lock().walk_page_table(page).unwrap().0;
-            frames.push(entry.physical_address().unwrap());
-        }
-
-        self.with_inactive_p4(new_table, temporary_page, |mapper| {
-            for page_no in pages.clone() {
-                let page = Page::containing_address(page_no * 4096, PageSize::Kib4);
-                let phys_addr = frames[page_no - pages.start()];
-
-                unsafe {
-                    mapper.map_to(page, phys_addr, flags, InvalidateTlb::NoInvalidate);
-                }
-            }
-        });
-    }
-
     pub fn switch(&mut self, new_table: InactivePageMap) -> InactivePageMap {
         let old_table = InactivePageMap {
             p4_frame: PhysicalAddress(util::cr3() as usize)
@@ -443,30 +668,42 @@ pub struct InactivePageMap {
 }
 
 impl InactivePageMap {
-    pub fn new(
-        frame: PhysicalAddress,
-        active_table: &mut ActivePageMap,
-        temporary_page: &mut TemporaryPage)
-    -> InactivePageMap {
-        {
-            let table = unsafe {
-                temporary_page.map_table_frame(frame.clone(), active_table)
-            };
+    /// Sets up the frame as an empty P4 table, which maps itself recursively
+    pub fn new(frame: PhysicalAddress) -> InactivePageMap {
+        Self::new_in(unsafe { direct_mapped_table(frame) }, frame)
+    }
 
-            table.zero();
+    /// Like [InactivePageMap::new], but given a mapping of the frame to set up the table through
+    pub(super) fn new_in(table: &mut PageTable<Level4>, frame: PhysicalAddress) -> InactivePageMap {
+        table.zero();
+        count_page_table_frame(true);
 
-            // Set up recursive mapping for table
-            table[510].set(
-                frame.clone(),
-                EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE
-            );
-        }
+        // Set up recursive mapping for table
+        table[510].set(
+            frame.clone(),
+            EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE
+        );
 
-        unsafe {
-            temporary_page.unmap(active_table);
+        InactivePageMap { p4_frame: frame }
+    }
+
+    /// Creates a page map for a user address space. The lower half is empty, while the kernel half
+    /// shares its P3 tables with the active page map. Kernel mappings made afterwards are only
+    /// shared as long as they fall under a P4 entry which already exists.
+    pub fn new_user(frame: PhysicalAddress, active_table: &ActivePageMap) -> InactivePageMap {
+        let map = InactivePageMap::new(frame);
+        let table = unsafe { direct_mapped_table::<Level4>(frame) };
+
+        // Every kernel entry but the recursive mapping
+        for i in (256..PAGE_TABLE_ENTRIES).filter(|&i| i != 510) {
+            table[i] = active_table.p4()[i];
         }
 
-        InactivePageMap { p4_frame: frame }
+        map
+    }
+
+    pub fn p4_frame(&self) -> PhysicalAddress {
+        self.p4_frame
     }
 }
 
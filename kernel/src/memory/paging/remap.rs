@@ -1,24 +1,29 @@
-use core::alloc::Layout;
+use core::ops::{Range, RangeInclusive};
+use alloc::vec::Vec;
 use multiboot2::BootInformation;
-use crate::memory::paging::{self, PAGE_TABLES, Page, PhysicalAddress, EntryFlags,
-                            page_map::TemporaryPage, InvalidateTlb, FreeMemory};
+use crate::memory::paging::{self, PAGE_TABLES, Page, PhysicalAddress, EntryFlags, InvalidateTlb,
+                            FreeMemory, ActivePageMap, InactivePageMap, PageTable, Level4,
+                            TableLevel, VirtualAddress};
 use crate::memory::{bootstrap_heap::BOOTSTRAP_HEAP, physical_allocator::PHYSICAL_ALLOCATOR};
 use crate::memory::heap::Heap;
+use crate::memory::PHYSICAL_MAP_BEGIN;
 use crate::memory::paging::PageSize;
 use crate::util;
 
 pub fn remap_kernel(
     boot_info: &BootInformation,
     heap_tree_start_virt: usize,
+    direct_map: &[Range<usize>],
 ) {
-    use core::alloc::GlobalAlloc;
     use multiboot2::ElfSectionFlags;
     use x86_64::registers::control::{Cr0, Cr0Flags};
 
-    // Allocate some heap memory for us to put the temporary page on
-    let heap_layout = Layout::from_size_align(0x1000, 0x1000).unwrap();
+    // Allocate some heap memory for us to put the temporary page on. This goes to the buddy tree
+    // directly, as anything the debug heap (see `memory::heap_debug`) allocated for its own
+    // bookkeeping now would not be mapped after the remap.
+    let heap_page_frame = PHYSICAL_ALLOCATOR.allocate(0).expect("no more frames");
     let heap_page_addr = unsafe {
-        crate::HEAP.alloc(heap_layout)
+        crate::HEAP.alloc_specific(heap_page_frame as usize / 4096, 1, EntryFlags::empty())
     };
 
     let heap_page = Page::containing_address(
@@ -44,11 +49,16 @@ pub fn remap_kernel(
     );
 
     let paddr = heap_frame_addr.physical_address().unwrap().0 as *const u8;
-    let mut new_table = paging::InactivePageMap::new(frame, &mut active_table, &mut temporary_page);
+    let mut new_table = unsafe {
+        let table = temporary_page.map_table_frame(frame, &mut active_table);
+        let new_table = InactivePageMap::new_in(table, frame);
+        temporary_page.unmap(&mut active_table);
+        new_table
+    };
 
     trace!("Mapping new page tables");
 
-    active_table.with_inactive_p4(&mut new_table, &mut temporary_page, |mapper| {
+    with_new_p4(&mut active_table, &mut new_table, &mut temporary_page, |mapper| {
         let elf_sections_tag = boot_info.elf_sections_tag()
             .expect("Memory map tag required");
 
@@ -91,6 +101,19 @@ pub fn remap_kernel(
                 InvalidateTlb::NoInvalidate,
             );
         }
+
+        // Map the direct map of RAM
+        for area in direct_map {
+            unsafe {
+                mapper.map_contiguous(
+                    PHYSICAL_MAP_BEGIN + area.start,
+                    PhysicalAddress(area.start),
+                    area.end - area.start,
+                    EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+                    InvalidateTlb::NoInvalidate,
+                );
+            }
+        }
     });
 
     // Map bootstrap heap
@@ -101,7 +124,8 @@ pub fn remap_kernel(
     ) as usize;
     let bootstrap_heap_page_range = bootstrap_heap_start_page..=bootstrap_heap_end_page;
 
-    active_table.remap_range(
+    remap_range(
+        &mut active_table,
         &mut new_table,
         &mut temporary_page,
         bootstrap_heap_page_range,
@@ -116,7 +140,8 @@ pub fn remap_kernel(
     ) as usize;
     let heap_tree_page_range = heap_tree_start_page..=heap_tree_end_page;
 
-    active_table.remap_range(
+    remap_range(
+        &mut active_table,
         &mut new_table,
         &mut temporary_page,
         heap_tree_page_range,
@@ -136,8 +161,102 @@ pub fn remap_kernel(
         );
     }
 
-    unsafe { crate::HEAP.dealloc(heap_page_addr, heap_layout) };
+    unsafe { crate::HEAP.dealloc_specific(heap_page_addr, 1) };
+    PHYSICAL_ALLOCATOR.deallocate(heap_page_frame, 0);
 
     trace!("mem: enabling write protection");
     unsafe { Cr0::write(Cr0::read() | Cr0Flags::WRITE_PROTECT) };
 }
+
+/// Runs the closure with the mapper pointed at the new page map. The direct map does not exist yet,
+/// so the active P4 table is reached through the temporary page instead.
+fn with_new_p4<F: FnOnce(&mut ActivePageMap) -> R, R>(
+    active_table: &mut ActivePageMap,
+    new_table: &mut InactivePageMap,
+    temporary_page: &mut TemporaryPage,
+    f: F,
+) -> R {
+    let backup = PhysicalAddress(util::cr3() as usize);
+
+    let ret = unsafe {
+        let active_p4 = temporary_page.map_table_frame::<Level4>(backup, active_table);
+        active_table.with_recursive_p4(active_p4, new_table, f)
+    };
+
+    unsafe {
+        temporary_page.unmap(active_table);
+    }
+
+    ret
+}
+
+/// Maps the pages in the new page map to the frames they are mapped to in the active one
+fn remap_range(
+    active_table: &mut ActivePageMap,
+    new_table: &mut InactivePageMap,
+    temporary_page: &mut TemporaryPage,
+    pages: RangeInclusive<usize>,
+    flags: EntryFlags
+) {
+    let num_pages = pages.end() - pages.start();
+    let mut frames = Vec::with_capacity(num_pages);
+    for i in 0..=num_pages {
+        let frame = PAGE_TABLES.lock().translate((i + pages.start()) * 4096).unwrap();
+        frames.push(frame);
+    }
+
+    with_new_p4(active_table, new_table, temporary_page, |mapper| {
+        for page_no in pages.clone() {
+            let page = Page::containing_address(page_no * 4096, PageSize::Kib4);
+            let phys_addr = frames[page_no - pages.start()];
+
+            unsafe {
+                mapper.map_to(page, phys_addr, flags, InvalidateTlb::NoInvalidate);
+            }
+        }
+    });
+}
+
+/// A page which frames are mapped to while remapping the kernel, as there is no direct map to
+/// reach them through until the kernel is remapped
+struct TemporaryPage {
+    page: Page,
+}
+
+impl TemporaryPage {
+    fn new(page: Page) -> TemporaryPage {
+        TemporaryPage { page }
+    }
+
+    /// Maps the temporary page to the given frame in the active table.
+    /// Returns the start address of the temporary page.
+    unsafe fn map(
+        &mut self,
+        frame: PhysicalAddress,
+        active_table: &mut ActivePageMap
+    ) -> VirtualAddress {
+        let page_addr = self.page.start_address().expect("Temporary page requires size");
+        assert!(
+            active_table.walk_page_table(self.page).is_none(),
+            "Temporary page {:?} at 0x{:x} is already mapped",
+            self.page,
+            page_addr,
+        );
+
+        active_table.map_to(self.page, frame, EntryFlags::WRITABLE, InvalidateTlb::Invalidate);
+        VirtualAddress(page_addr)
+    }
+
+    /// Unmaps the temporary page in the active table.
+    unsafe fn unmap(&mut self, active_table: &mut ActivePageMap) {
+        active_table.unmap(self.page, FreeMemory::NoFree, InvalidateTlb::NoInvalidate,);
+    }
+
+    unsafe fn map_table_frame<L: TableLevel>(
+        &mut self,
+        frame: PhysicalAddress,
+        active_table: &mut ActivePageMap
+    ) -> &mut PageTable<L> {
+        &mut *(self.map(frame, active_table).0 as *mut PageTable<L>)
+    }
+}
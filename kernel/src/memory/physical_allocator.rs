@@ -1,12 +1,15 @@
 ///! A modified buddy bitmap allocator. Written originally in
 /// [buddy allocator workshop](https://github.com/Restioson/buddy-allocator-workshop).
-use core::{mem, ptr, ops::{Range, Deref, DerefMut}};
+use core::{cmp, mem, ops::{Range, Deref, DerefMut}};
+use core::sync::atomic::{AtomicUsize, Ordering};
 #[cfg(test)]
-use std::boxed::Box;
+use std::{boxed::Box, vec::Vec};
 #[cfg(not(test))]
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
+use arrayvec::ArrayVec;
 use spin::{Mutex, Once};
 use super::bootstrap_heap::{BootstrapHeapBox, BOOTSTRAP_HEAP};
+use super::numa;
 
 /// Number of orders.
 const LEVEL_COUNT: u8 = 19;
@@ -14,56 +17,92 @@ const LEVEL_COUNT: u8 = 19;
 /// is `2^(k + MIN_ORDER)`, not `2^k`.
 const BASE_ORDER: u8 = 12;
 
+/// The size of the memory managed by each tree: 1GiB
+const TREE_SIZE: usize = 1 << (LEVEL_COUNT - 1 + BASE_ORDER);
+/// Number of trees set up on the bootstrap heap. Must match the number of objects that it holds.
+const PRELIM_TREES: usize = 8;
+/// The node of a tree whose memory is not in any NUMA node
+const NO_NODE: usize = usize::max_value();
+
 /// The physical frame allocator. Requires the bootstrap heap to be initialized, or else the
 /// initializer will panic.
 pub static PHYSICAL_ALLOCATOR: PhysicalAllocator<'static> = PhysicalAllocator {
-    trees: Once::new(),
+    prelim_trees: Once::new(),
+    rest_trees: Once::new(),
 };
 
 // Panics from `buddy_allocator.rs` will say they're from here. Go there instead.
 buddy_allocator_bitmap_tree!(LEVEL_COUNT = LEVEL_COUNT, BASE_ORDER = BASE_ORDER);
 
+/// The tree managing one GiB of physical memory
+struct GibTree<'a> {
+    gib: usize,
+    /// The NUMA node (proximity domain) which the GiB belongs to, or [NO_NODE]
+    node: AtomicUsize,
+    tree: Mutex<Tree<TreeBox<'a>>>,
+}
+
+impl<'a> GibTree<'a> {
+    fn new(gib: usize, tree: Tree<TreeBox<'a>>) -> Self {
+        GibTree { gib, node: AtomicUsize::new(NO_NODE), tree: Mutex::new(tree) }
+    }
+
+    fn node(&self) -> Option<usize> {
+        match self.node.load(Ordering::Relaxed) {
+            NO_NODE => None,
+            node => Some(node),
+        }
+    }
+
+    /// Converts an address relative to the tree to a physical address
+    fn physical(&self, address: *const u8) -> *const u8 {
+        (address as usize + self.gib * TREE_SIZE) as *const u8
+    }
+}
+
+/// Trees only exist for GiBs which contain usable memory, so physical memory may be sparse and as
+/// large as the address space allows. The trees of each stage of initialisation are sorted by GiB.
 pub struct PhysicalAllocator<'a> {
-    // Max 256GiB
-    trees: Once<[Mutex<Option<Tree<TreeBox<'a>>>>; 256]>,
+    /// The trees of the first GiBs with usable memory, set up on the bootstrap heap
+    prelim_trees: Once<ArrayVec<[GibTree<'a>; PRELIM_TREES]>>,
+    /// The trees of the rest of the GiBs with usable memory, set up on the kernel heap
+    rest_trees: Once<Vec<GibTree<'a>>>,
 }
 
 impl<'a> PhysicalAllocator<'a> {
     /// Create a new, initialized allocator
     #[cfg(test)]
-    fn new<'r, I>(gibbibytes: u8, usable: I) -> Self
+    fn new<'r, I>(usable: I) -> Self
         where I: Iterator<Item=&'r Range<usize>> + Clone + 'r
     {
         let allocator = PhysicalAllocator {
-            trees: Once::new(),
+            prelim_trees: Once::new(),
+            rest_trees: Once::new(),
         };
 
         allocator.init_prelim(usable.clone());
-        allocator.init_rest(gibbibytes, usable);
+        allocator.init_rest(usable);
 
         allocator
     }
 
-    /// Initialize the allocator's first 8 gibbibytes. The PMM has a two stage init -- in the first
-    /// stage, the first 8 GiBs are set up, using the bootstrap heap. This is enough to set up the
-    /// main kernel heap. In the second stage, the rest of the GiBs are set up, using the kernel
-    /// heap.
+    /// Initialize the trees of the first 8 GiBs which contain usable memory. The PMM has a two
+    /// stage init -- in the first stage, the first 8 GiBs are set up, using the bootstrap heap.
+    /// This is enough to set up the main kernel heap. In the second stage, the rest of the GiBs
+    /// are set up, using the kernel heap.
     pub fn init_prelim<'r, I>(&self, usable: I)
         where I: Iterator<Item=&'r Range<usize>> + Clone + 'r
     {
-        self.trees.call_once(|| {
-            let mut trees: [Mutex<Option<Tree<TreeBox<'a>>>>; 256] = unsafe {
-                mem::uninitialized()
-            };
+        self.prelim_trees.call_once(|| {
+            let mut trees = ArrayVec::new();
+            let mut gib = None;
 
-            // Set up all as Nones to avoid any UB from `panic`s
-            for slot in trees.iter_mut() {
-                unsafe { ptr::write(slot as *mut _, Mutex::new(None)); }
-            }
+            while let Some(next) = Self::next_usable_gib(usable.clone(), gib) {
+                if trees.is_full() {
+                    break;
+                }
 
-            // Init the first 8 trees on the bootstrap heap
-            for (i, slot) in trees.iter_mut().take(8).enumerate() {
-                let usable = Self::localize(i as u8, usable.clone());
+                let usable = Self::localize(next, usable.clone());
 
                 #[cfg(not(test))]
                 let tree = Tree::new(
@@ -81,34 +120,115 @@ impl<'a> PhysicalAllocator<'a> {
                     TreeBox::Heap(box unsafe { mem::uninitialized() }),
                 );
 
-                *slot = Mutex::new(Some(tree));
+                trees.push(GibTree::new(next, tree));
+                gib = Some(next);
             }
 
             trees
         });
     }
 
-    /// Initialise the rest of the allocator's gibbibytes. See [PhysicalAllocator.init_prelim].
-    pub fn init_rest<'r, I>(&self, gibbibytes: u8, usable: I)
+    /// Initialise the trees of the rest of the GiBs. See [PhysicalAllocator.init_prelim].
+    pub fn init_rest<'r, I>(&self, usable: I)
         where I: Iterator<Item=&'r Range<usize>> + Clone + 'r
     {
-        let trees = self.trees.wait().unwrap();
+        let prelim_trees = self.prelim_trees.wait().unwrap();
+
+        // Only continue if the first stage ran out of trees
+        if !prelim_trees.is_full() {
+            self.rest_trees.call_once(Vec::new);
+            return;
+        }
+
+        self.rest_trees.call_once(|| {
+            let mut trees = Vec::new();
+            let mut gib = prelim_trees.last().map(|tree| tree.gib);
+
+            while let Some(next) = Self::next_usable_gib(usable.clone(), gib) {
+                let usable = Self::localize(next, usable.clone());
+                let tree = Tree::new(usable, TreeBox::Heap(box unsafe { mem::uninitialized() }));
+
+                trees.push(GibTree::new(next, tree));
+                gib = Some(next);
+            }
+
+            trees
+        });
+    }
+
+    /// Returns the lowest GiB above `after` which contains usable memory
+    fn next_usable_gib<'r, I>(usable: I, after: Option<usize>) -> Option<usize>
+        where I: Iterator<Item=&'r Range<usize>> + 'r
+    {
+        usable
+            .filter(|range| range.start < range.end)
+            .filter_map(|range| {
+                let first = range.start / TREE_SIZE;
+                let last = (range.end - 1) / TREE_SIZE;
+                let next = after.map_or(first, |after| cmp::max(first, after + 1));
+
+                if next <= last {
+                    Some(next)
+                } else {
+                    None
+                }
+            })
+            .min()
+    }
 
-        for i in 8..=gibbibytes {
-            let usable = Self::localize(i as u8, usable.clone());
+    /// All of the trees, sorted by GiB
+    fn trees(&self) -> impl Iterator<Item = &GibTree<'a>> + Clone {
+        self.prelim_trees.wait()
+            .expect("Physical allocator not initialized!")
+            .iter()
+            .chain(self.rest_trees.r#try().into_iter().flat_map(|trees| trees.iter()))
+    }
+
+    /// Returns the tree managing the given GiB, if there is one
+    fn tree(&self, gib: usize) -> Option<&GibTree<'a>> {
+        let prelim_trees = self.prelim_trees.wait().expect("Physical allocator not initialized!");
+        let rest_trees = self.rest_trees.r#try().map(|trees| trees.as_slice()).unwrap_or(&[]);
+
+        [prelim_trees.as_slice(), rest_trees].iter()
+            .filter_map(|trees| {
+                trees.binary_search_by_key(&gib, |tree| tree.gib).ok().map(|index| &trees[index])
+            })
+            .next()
+    }
 
-            let tree = Tree::new(usable, TreeBox::Heap(box unsafe { mem::uninitialized() }));
-            *trees[i as usize].lock() = Some(tree);
+    /// Moves the trees which were set up on the bootstrap heap to the main heap, so that the
+    /// bootstrap heap can be reclaimed. Returns the number of trees moved.
+    pub fn migrate_from_bootstrap_heap(&self) -> usize {
+        let trees = self.prelim_trees.wait().unwrap();
+        let mut moved = 0;
+
+        for slot in trees.iter() {
+            if !slot.tree.lock().flat_blocks.is_bootstrap() {
+                continue;
+            }
+
+            // Zeroing the new tree maps its pages before the lock is taken, as mapping them may
+            // need frames from this very tree
+            let mut blocks: Box<[Block; BLOCKS_IN_TREE]> = box unsafe { mem::zeroed() };
+
+            let mut tree = slot.tree.lock();
+
+            blocks.copy_from_slice(&tree.flat_blocks[..]);
+            tree.flat_blocks = TreeBox::Heap(blocks); // Frees the tree on the bootstrap heap
+
+            moved += 1;
         }
+
+        moved
     }
 
     /// Filter out addresses that apply to a GiB and make them local to it
-    fn localize<'r, I>(gib: u8, usable: I) -> impl Iterator<Item=Range<usize>> + Clone + 'r
+    fn localize<'r, I>(gib: usize, usable: I) -> impl Iterator<Item=Range<usize>> + Clone + 'r
         where I: Iterator<Item=&'r Range<usize>> + Clone + 'r
     {
         (&usable).clone()
             .filter_map(move |range| {
-                let gib = ((gib as usize) << 30)..((gib as usize + 1 << 30) + 1);
+                let gib = (gib << 30)..((gib + 1 << 30) + 1);
 
                 // If the range covers any portion of the GiB
                 if !(range.start > gib.end) && !(range.end < gib.start) {
@@ -126,61 +246,213 @@ impl<'a> PhysicalAllocator<'a> {
             })
     }
 
-    /// Allocate a frame of order `order`. Panics if not initialized. Does __not__ zero the memory.
+    /// Tags the trees overlapping the physical `range` as belonging to a NUMA node. A GiB which
+    /// spans several nodes keeps the first node that it was tagged with.
+    pub fn set_node(&self, range: Range<usize>, node: usize) {
+        for slot in self.trees() {
+            if Self::localize_range(slot.gib, &range).is_some() {
+                let _ = slot.node.compare_exchange(
+                    NO_NODE,
+                    node,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+            }
+        }
+    }
+
+    /// Allocate a frame of order `order`. On NUMA systems, memory from the current CPU's node is
+    /// preferred, falling back to the nearest node which has memory free. Panics if not initialized.
+    /// Does __not__ zero the memory.
     pub fn allocate(&self, order: u8) -> Option<*const u8> {
-        #[derive(Eq, PartialEq, Copy, Clone, Debug)]
-        enum TryState {
-            Tried,
-            WasInUse,
-            Untried,
+        if numa::is_numa() {
+            return self.allocate_on(numa::current_node(), order);
         }
 
-        let mut tried = [TryState::Untried; 256];
+        Self::allocate_from(self.trees(), order)
+    }
 
-        // Try every tree. If it's locked, come back to it later.
+    /// Allocate a frame of order `order`, preferring memory from the given NUMA node and falling
+    /// back to the other nodes in order of their distance from it. Memory which is not in any node
+    /// is used last. Panics if not initialized. Does __not__ zero the memory.
+    pub fn allocate_on(&self, node: usize, order: u8) -> Option<*const u8> {
+        let distance = |slot: &GibTree| {
+            slot.node().map_or(numa::MAX_DISTANCE + 1, |to| numa::distance(node, to))
+        };
+
+        let mut tried = None;
+
+        // Try all trees at the nearest distance not yet tried, then move further out
         loop {
-            let index = tried.iter()
-                .position(|i| *i == TryState::Untried)
-                .or_else(
-                    || tried.iter().position(|i| *i == TryState::WasInUse)
-                )?;
-
-            let trees = self.trees.wait().unwrap();
-
-            // Try to lock the tree
-            if let Some(ref mut tree) = trees[index].try_lock() {
-                // Get Option<&mut Tree>
-                if let Some(ref mut tree) = tree.as_mut() {
-                    // Try to allocate something on the tree
-                    match tree.allocate(order) {
-                        Some(address) => return Some(
-                            (address as usize + (index * (1 << MAX_ORDER + BASE_ORDER))) as *const u8
-                        ),
-                        None => tried[index] = TryState::Tried, // Tree empty for alloc of this size
+            let nearest = self.trees()
+                .map(distance)
+                .filter(|&d| tried.map_or(true, |tried| d > tried))
+                .min()?;
+
+            let trees = self.trees().filter(|slot| distance(slot) == nearest);
+
+            if let Some(address) = Self::allocate_from(trees, order) {
+                return Some(address);
+            }
+
+            tried = Some(nearest);
+        }
+    }
+
+    /// Allocates from the first of the trees with a free block of the order. Trees which are
+    /// locked are busy and in use by something else (e.g another core), so the others are tried
+    /// first and the locked ones are only waited for if none of the others had a free block.
+    fn allocate_from<'s, I>(trees: I, order: u8) -> Option<*const u8>
+        where I: Iterator<Item = &'s GibTree<'a>> + Clone, 'a: 's
+    {
+        let mut busy = false;
+
+        for slot in trees.clone() {
+            match slot.tree.try_lock() {
+                Some(mut tree) => {
+                    if let Some(address) = tree.allocate(order) {
+                        return Some(slot.physical(address));
                     }
-                } else {
-                    // Tree was None and nonexistent. We've tried it so set it to tried
-                    tried[index] = TryState::Tried;
-                }
-            } else {
-                // Tree was already locked -- it is busy and in use by something else (in futuure,
-                // another core)
-                tried[index] = TryState::WasInUse;
+                },
+                None => busy = true,
             }
         }
+
+        if !busy {
+            return None;
+        }
+
+        for slot in trees {
+            if let Some(address) = slot.tree.lock().allocate(order) {
+                return Some(slot.physical(address));
+            }
+        }
+
+        None
+    }
+
+    /// Allocate a frame of order `order` which lies entirely within the physical `range`, such as
+    /// below 4GiB for devices which can only address 32 bits. Only the trees covering the range are
+    /// searched. Returns `None` if there is no such frame or if `order` is too big. Panics if not
+    /// initialized. Does __not__ zero the memory.
+    pub fn allocate_in(&self, order: u8, range: Range<usize>) -> Option<*const u8> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        for slot in self.trees() {
+            let local_range = match Self::localize_range(slot.gib, &range) {
+                Some(local_range) => local_range,
+                None => continue,
+            };
+
+            if let Some(address) = slot.tree.lock().allocate_in(order, local_range) {
+                return Some(slot.physical(address));
+            }
+        }
+
+        None
+    }
+
+    /// Reserve the frames overlapping the physical `range` so that they are never allocated, e.g
+    /// for memory used by firmware or boot modules. Frames which are already used or were never
+    /// usable are left as they are, as are GiBs which are not managed. Panics if not initialized,
+    /// or if the range overlaps an allocation of more than one frame.
+    pub fn reserve(&self, range: Range<usize>) {
+        for slot in self.trees() {
+            if let Some(local_range) = Self::localize_range(slot.gib, &range) {
+                slot.tree.lock().reserve(local_range);
+            }
+        }
+    }
+
+    /// Hand back the frames entirely within the physical `range`, which must have been reserved
+    /// or never been usable (e.g ACPI reclaimable memory), so that they can be allocated. Frames
+    /// in GiBs which are not managed are ignored. Returns the number of bytes which were freed.
+    /// Panics if not initialized, or if the range overlaps an allocation of more than one frame
+    /// (or one which can be told apart, in debug builds, see `Tree::release`).
+    pub fn release(&self, range: Range<usize>) -> usize {
+        self.trees()
+            .filter_map(|slot| {
+                let local_range = Self::localize_range(slot.gib, &range)?;
+                Some(slot.tree.lock().release(local_range))
+            })
+            .sum()
+    }
+
+    /// Returns the part of a physical range within a GiB, relative to the GiB, if there is any
+    fn localize_range(gib: usize, range: &Range<usize>) -> Option<Range<usize>> {
+        let tree_start = gib * TREE_SIZE;
+
+        if range.end <= tree_start || range.start >= tree_start + TREE_SIZE {
+            return None;
+        }
+
+        Some(range.start.saturating_sub(tree_start)..cmp::min(range.end - tree_start, TREE_SIZE))
     }
 
     /// Deallocate the block of `order` at `ptr`. Panics if not initialized, if block is free, or if
-    /// block is out of bounds of the # of GiB available.
+    /// block is in a GiB which is not managed.
     pub fn deallocate(&self, ptr: *const u8, order: u8) {
-        let tree = (ptr as usize) >> (LEVEL_COUNT - 1 + BASE_ORDER);
-        let local_ptr = (ptr as usize % (1 << LEVEL_COUNT - 1 + BASE_ORDER)) as *const u8;
+        let gib = (ptr as usize) / TREE_SIZE;
+        let local_ptr = (ptr as usize % TREE_SIZE) as *const u8;
+
+        let slot = self.tree(gib).expect("Block to free is not in managed memory!");
+        slot.tree.lock().deallocate(local_ptr, order);
+    }
+
+    /// Calls `f` with a snapshot of the counters of each tree, along with the GiB that it manages,
+    /// in order of GiB. Does nothing if not initialized.
+    pub fn for_each_tree_stats<F: FnMut(usize, TreeStats)>(&self, mut f: F) {
+        let prelim_trees = self.prelim_trees.r#try().into_iter().flat_map(|trees| trees.iter());
+        let rest_trees = self.rest_trees.r#try().into_iter().flat_map(|trees| trees.iter());
+
+        for slot in prelim_trees.chain(rest_trees) {
+            let stats = slot.tree.lock().stats();
+            f(slot.gib, stats);
+        }
+    }
+
+    /// Returns the counters of all trees added together, along with the largest free block in any
+    /// of them
+    pub fn stats(&self) -> TreeStats {
+        self.total_stats(|tree| Some(tree.lock().stats())).unwrap()
+    }
+
+    /// Like `stats`, but returns `None` rather than waiting if a tree is locked, e.g by code which
+    /// the debugger stopped
+    pub fn try_stats(&self) -> Option<TreeStats> {
+        self.total_stats(|tree| tree.try_lock().map(|tree| tree.stats()))
+    }
+
+    /// Adds together the counters of each tree, as returned by `stats_of`
+    fn total_stats<F>(&self, stats_of: F) -> Option<TreeStats>
+        where F: Fn(&Mutex<Tree<TreeBox<'a>>>) -> Option<TreeStats>
+    {
+        let mut total = TreeStats {
+            usable_bytes: 0,
+            allocated_bytes: 0,
+            allocations: [0; LEVEL_COUNT as usize],
+            largest_free_order: None,
+        };
+
+        let prelim_trees = self.prelim_trees.r#try().into_iter().flat_map(|trees| trees.iter());
+        let rest_trees = self.rest_trees.r#try().into_iter().flat_map(|trees| trees.iter());
+
+        for slot in prelim_trees.chain(rest_trees) {
+            let stats = stats_of(&slot.tree)?;
+
+            total.usable_bytes += stats.usable_bytes;
+            total.allocated_bytes += stats.allocated_bytes;
 
-        let trees = self.trees.wait().unwrap();
-        let mut lock = trees[tree].lock();
-        let tree = lock.as_mut().unwrap();
+            for (sum, count) in total.allocations.iter_mut().zip(stats.allocations.iter()) {
+                *sum += count;
+            }
+
+            total.largest_free_order = total.largest_free_order.max(stats.largest_free_order);
+        }
 
-        tree.deallocate(local_ptr, order);
+        Some(total)
     }
 }
 
@@ -189,6 +461,15 @@ enum TreeBox<'a> {
     Heap(Box<[Block; BLOCKS_IN_TREE]>),
 }
 
+impl<'a> TreeBox<'a> {
+    fn is_bootstrap(&self) -> bool {
+        match self {
+            TreeBox::Bootstrap(_) => true,
+            TreeBox::Heap(_) => false,
+        }
+    }
+}
+
 impl<'a> Deref for TreeBox<'a> {
     type Target = [Block; BLOCKS_IN_TREE];
 
@@ -218,49 +499,102 @@ mod test {
 
     #[test]
     fn test_alloc_physical_allocator() {
-        let allocator = PhysicalAllocator::new(
-            2,
-            iter::once(&(0..(2 << MAX_ORDER + BASE_ORDER) + 1)),
-        );
+        let allocator = PhysicalAllocator::new(iter::once(&(0..(2 << MAX_ORDER + BASE_ORDER) + 1)));
 
         assert_eq!(allocator.allocate(0).unwrap(), 0x0 as *const u8);
 
-        let trees = allocator.trees.wait().unwrap();
-        let _tree_lock = trees[0].lock();
+        let _tree_lock = allocator.tree(0).unwrap().tree.lock();
 
         assert_eq!(allocator.allocate(0).unwrap(), (1 << ((MAX_ORDER + BASE_ORDER) as u32)) as *const u8);
     }
 
     #[test]
     fn test_dealloc_physical_allocator() {
-        let allocator = PhysicalAllocator::new(
-            2,
-             iter::once(&(0..(2 << 30) + 1)),
-        );
+        let allocator = PhysicalAllocator::new(iter::once(&(0..(2 << 30) + 1)));
 
         allocator.allocate(0).unwrap();
         allocator.deallocate(0x0 as *const u8, 0);
         assert_eq!(allocator.allocate(5).unwrap(), 0x0 as *const u8);
     }
 
+    #[test]
+    fn test_allocate_in() {
+        let allocator = PhysicalAllocator::new(iter::once(&(0..(2 << 30) + 1)));
+
+        assert_eq!(
+            allocator.allocate_in(0, (1 << 30) + 0x5000..2 << 30).unwrap(),
+            ((1 << 30) + 0x5000) as *const u8,
+        );
+
+        // A block of order 1 cannot straddle two trees
+        assert_eq!(allocator.allocate_in(1, (1 << 30) - 0x1000..(1 << 30) + 0x1000), None);
+        assert_eq!(allocator.allocate_in(0, 0..16 << 20).unwrap(), 0x0 as *const u8);
+    }
+
+    #[test]
+    fn test_allocate_on() {
+        let allocator = PhysicalAllocator::new(iter::once(&(0..3 << 30)));
+
+        allocator.set_node(0..1 << 30, 0);
+        allocator.set_node(1 << 30..2 << 30, 1);
+
+        assert_eq!(allocator.allocate_on(1, 0).unwrap(), (1 << 30) as *const u8);
+
+        // Node 1 has no block of the max order left, so the nearest node is used, then memory in
+        // no node
+        assert_eq!(allocator.allocate_on(1, MAX_ORDER).unwrap(), 0x0 as *const u8);
+        assert_eq!(allocator.allocate_on(1, MAX_ORDER).unwrap(), (2usize << 30) as *const u8);
+        assert_eq!(allocator.allocate_on(1, MAX_ORDER), None);
+    }
+
+    #[test]
+    fn test_reserve_release() {
+        let allocator = PhysicalAllocator::new(iter::once(&(0..(2 << 30) + 1)));
+
+        allocator.reserve((1 << 30) - 0x1000..(1 << 30) + 0x2000);
+        assert_eq!(
+            allocator.allocate_in(0, (1 << 30) - 0x1000..2 << 30).unwrap(),
+            ((1 << 30) + 0x2000) as *const u8,
+        );
+
+        assert_eq!(allocator.release((1 << 30) - 0x1000..(1 << 30) + 0x1000), 0x2000);
+        assert_eq!(
+            allocator.allocate_in(0, (1 << 30) - 0x1000..2 << 30).unwrap(),
+            ((1 << 30) - 0x1000) as *const u8,
+        );
+    }
+
     #[test]
     fn test_init() {
         let allocator = PhysicalAllocator {
-            trees: Once::new(),
+            prelim_trees: Once::new(),
+            rest_trees: Once::new(),
         };
 
         allocator.init_prelim(iter::once(&(0..(9 << 30) + 1)));
 
-        let trees = allocator.trees.wait().unwrap();
+        assert!(allocator.tree(8).is_none());
+        assert!(allocator.tree(7).is_some());
 
-        assert!(trees[8].lock().is_none());
-        assert!(trees[7].lock().is_some());
+        allocator.init_rest(iter::once(&(0..(9 << 30) + 1)));
 
-        allocator.init_rest(
-            9,
-            iter::once(&(0..(9 << 30) + 1)),
-        );
+        assert!(allocator.tree(8).is_some());
+        assert!(allocator.tree(9).is_some());
+        assert!(allocator.tree(10).is_none());
+    }
+
+    #[test]
+    fn test_sparse() {
+        let usable = [0..1 << 30, (300 << 30) + 0x1000..(301 << 30)];
+        let allocator = PhysicalAllocator::new(usable.iter());
+
+        assert!(allocator.tree(1).is_none());
+        assert_eq!(allocator.trees().map(|tree| tree.gib).collect::<Vec<_>>(), vec![0, 300]);
+
+        let address = allocator.allocate_in(0, 300 << 30..301 << 30).unwrap();
+        assert_eq!(address, ((300usize << 30) + 0x1000) as *const u8);
 
-        assert!(trees[8].lock().is_some());
+        allocator.deallocate(address, 0);
+        assert_eq!(allocator.stats().usable_bytes, (2 << 30) - 0x1000);
     }
 }
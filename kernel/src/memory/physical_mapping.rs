@@ -1,15 +1,48 @@
 use core::{mem, ptr::NonNull, ops::Deref};
+use crate::memory::{self, paging::{EntryFlags, PhysicalAddress}};
 use crate::util;
 
 pub unsafe fn map_physical_region<T>(
     physical_address: usize,
     size: usize,
     mutable: bool
+) -> PhysicalMapping<T> {
+    map_region_with_flags(physical_address, size, mutable, EntryFlags::empty())
+}
+
+/// Maps a region of memory mapped IO. The mapping is mutable and is not cached.
+pub unsafe fn map_mmio_region<T>(physical_address: usize, size: usize) -> PhysicalMapping<T> {
+    map_region_with_flags(
+        physical_address,
+        size,
+        true,
+        EntryFlags::NO_CACHE | EntryFlags::WRITE_DIRECT,
+    )
+}
+
+unsafe fn map_region_with_flags<T>(
+    physical_address: usize,
+    size: usize,
+    mutable: bool,
+    flags: EntryFlags,
 ) -> PhysicalMapping<T> {
     let frames = util::round_up_divide(size as u64, 4096) as usize;
     let physical_begin_frame = physical_address / 4096;
 
-    let alloc_ptr = crate::HEAP.alloc_specific(physical_begin_frame, frames) as usize;
+    // Cached RAM can be reached through the direct map without using up heap space
+    let physical_range = physical_begin_frame * 4096..(physical_begin_frame + frames) * 4096;
+    if flags.is_empty() && memory::is_direct_mapped(&physical_range) {
+        let virtual_address = memory::phys_to_virt(PhysicalAddress(physical_address));
+
+        return PhysicalMapping {
+            physical_start: physical_begin_frame * 4096,
+            virtual_start: NonNull::new(virtual_address.0 as *mut T).unwrap(),
+            mapped_length: frames * 4096,
+            mutable,
+        };
+    }
+
+    let alloc_ptr = crate::HEAP.alloc_specific(physical_begin_frame, frames, flags) as usize;
 
     if alloc_ptr == 0 {
         panic!("Ran out of heap memory!");
@@ -39,6 +72,11 @@ pub struct PhysicalMapping<T> {
 }
 
 impl<T> PhysicalMapping<T> {
+    /// The virtual address that the object is mapped at
+    pub fn virtual_address(&self) -> usize {
+        self.virtual_start.as_ptr() as usize
+    }
+
     /// Returns a mutable reference to the data if this mapping is mutable and returns None if not
     /// mutable.
     pub fn deref_mut(&mut self) -> Option<&mut T> {
@@ -54,6 +92,10 @@ impl<T> Drop for PhysicalMapping<T> {
     fn drop(&mut self) {
         let obj_addr = self.virtual_start.as_ptr() as *mut T as usize;
 
+        if memory::in_direct_map(obj_addr) {
+            return;
+        }
+
         // Clear lower page offset bits
         let page_begin = obj_addr & !0xFFF;
 
@@ -0,0 +1,107 @@
+//! Reclaiming memory which is only needed while booting: the bootstrap heap, the `.boot` and
+//! `.boot_bss` sections (the code and page tables used to enter long mode) and ACPI reclaimable
+//! memory.
+
+use core::ops::Range;
+use arrayvec::ArrayVec;
+use spin::Once;
+use super::{KERNEL_MAPPING_BEGIN, MAX_MEMORY_AREAS};
+use super::paging::{PAGE_TABLES, FreeMemory, InvalidateTlb};
+use super::bootstrap_heap::{BootstrapHeap, BOOTSTRAP_HEAP};
+use super::physical_allocator::PHYSICAL_ALLOCATOR;
+use crate::{interrupts, util};
+
+extern "C" {
+    // Defined in the linker script
+    static boot_start: u8;
+    static boot_end: u8;
+    static boot_bss_start: u8;
+    static boot_bss_end: u8;
+}
+
+/// The ACPI reclaimable areas from the memory map, which is not mapped by the time they can be
+/// reclaimed
+static ACPI_RECLAIMABLE: Once<ArrayVec<[Range<usize>; MAX_MEMORY_AREAS]>> = Once::new();
+
+pub(super) fn set_acpi_reclaimable(areas: ArrayVec<[Range<usize>; MAX_MEMORY_AREAS]>) {
+    ACPI_RECLAIMABLE.call_once(|| areas);
+}
+
+/// Hands memory which is only needed while booting back to the physical allocator, logging how
+/// much was recovered. To be called once, after the ACPI tables have been parsed and all CPUs have
+/// booted.
+///
+/// # Unsafety
+///
+/// Unsafe as nothing may use the bootstrap heap, the boot sections or ACPI reclaimable memory
+/// afterwards.
+pub unsafe fn reclaim_boot_memory() {
+    info!("mem: reclaiming boot memory");
+
+    #[allow(unused_variables)] // For when log_level != debug | trace
+    let trees = PHYSICAL_ALLOCATOR.migrate_from_bootstrap_heap();
+    debug!("mem: moved {} pmm trees off the bootstrap heap", trees);
+
+    let mut reclaimed = 0;
+
+    let bootstrap_heap_start = BOOTSTRAP_HEAP.start();
+    let bootstrap_heap_end = bootstrap_heap_start +
+        util::round_up_divide(BootstrapHeap::space_taken() as u64 + 1, 4096) as usize * 4096;
+    reclaimed += reclaim_kernel_area(bootstrap_heap_start..bootstrap_heap_end);
+
+    let boot = &boot_start as *const u8 as usize..&boot_end as *const u8 as usize;
+    let boot_bss = &boot_bss_start as *const u8 as usize..&boot_bss_end as *const u8 as usize;
+    reclaimed += reclaim_kernel_area(boot);
+    reclaimed += reclaim_kernel_area(boot_bss);
+
+    for area in ACPI_RECLAIMABLE.wait().into_iter().flat_map(|areas| areas.iter()) {
+        // Frames given out by the physical allocator must be reachable through the direct map
+        if !super::extend_direct_map(area.clone()) {
+            warn!("mem: too many direct mapped areas, not reclaiming ACPI memory");
+            break;
+        }
+
+        let released = PHYSICAL_ALLOCATOR.release(area.clone());
+        reclaimed += released;
+
+        // Frames outside of the GiBs which the physical allocator manages cannot be handed back
+        if released < area.end - area.start {
+            info!(
+                "mem: only reclaimed {} of {} bytes of ACPI memory at 0x{:x} to 0x{:x}",
+                released,
+                area.end - area.start,
+                area.start,
+                area.end,
+            );
+        }
+    }
+
+    info!("mem: reclaimed {} KiB of boot memory", reclaimed / 1024);
+}
+
+/// Unmaps a page aligned range in the kernel's area and releases its frames, which must be
+/// physically contiguous. Returns the number of bytes released.
+unsafe fn reclaim_kernel_area(range: Range<usize>) -> usize {
+    let physical_start = interrupts::without_interrupts(|| {
+        let mut tables = PAGE_TABLES.lock();
+
+        let physical_start = tables.translate(range.start)
+            .map(|address| address.0)
+            .unwrap_or(range.start - KERNEL_MAPPING_BEGIN);
+
+        tables.unmap_range(range.clone(), FreeMemory::NoFree, InvalidateTlb::Invalidate);
+        physical_start
+    });
+
+    let length = range.end - range.start;
+    let released = PHYSICAL_ALLOCATOR.release(physical_start..physical_start + length);
+
+    trace!(
+        "mem: reclaimed {} bytes of 0x{:x} to 0x{:x}",
+        released,
+        physical_start,
+        physical_start + length,
+    );
+
+    released
+}
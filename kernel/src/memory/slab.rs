@@ -0,0 +1,272 @@
+//! Slab caches for small allocations. Each size class (16 to 2048 bytes) carves objects out of
+//! slabs obtained from a backing allocator (the buddy heap), so small allocations do not pay for
+//! a whole buddy block and never touch the buddy tree or the page tables unless a new slab is
+//! needed.
+//!
+//! Every slab is naturally aligned to its size and begins with a [Slab] header, so the slab of an
+//! object is found by masking its address.
+
+use core::{cmp, mem, ptr};
+use core::alloc::Layout;
+use spin::Mutex;
+
+/// The object sizes of the size classes
+pub const SIZE_CLASSES: [usize; CLASS_COUNT] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+const CLASS_COUNT: usize = 8;
+/// The minimum number of objects in a slab. Larger size classes use slabs of several pages so that
+/// the header does not waste too much of them.
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+/// Where slabs come from
+pub trait SlabSource {
+    /// Allocates a slab of the given power of two size, aligned to its size. Returns null if out
+    /// of memory.
+    unsafe fn alloc_slab(&self, size: usize) -> *mut u8;
+    unsafe fn free_slab(&self, ptr: *mut u8, size: usize);
+}
+
+/// Returns the index of the size class which serves the layout, or `None` if it is too large
+pub fn size_class(layout: &Layout) -> Option<usize> {
+    let size = cmp::max(layout.size(), layout.align());
+    SIZE_CLASSES.iter().position(|&class_size| class_size >= size)
+}
+
+/// The size of the slabs of a size class
+fn slab_size(class: usize) -> usize {
+    cmp::max(4096, (SIZE_CLASSES[class] * MIN_OBJECTS_PER_SLAB).next_power_of_two())
+}
+
+/// A free object, linked into its slab's free list
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Header at the start of every slab
+#[repr(C)]
+struct Slab {
+    /// Links in the list of slabs with free objects
+    prev: *mut Slab,
+    next: *mut Slab,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+struct Cache {
+    class: usize,
+    /// Slabs with at least one free object
+    partial: *mut Slab,
+}
+
+// Only accessed behind the cache mutex
+unsafe impl Send for Cache {}
+
+impl Cache {
+    const fn new(class: usize) -> Self {
+        Cache { class, partial: ptr::null_mut() }
+    }
+
+    unsafe fn alloc<S: SlabSource>(&mut self, source: &S) -> *mut u8 {
+        if self.partial.is_null() {
+            let slab = self.new_slab(source);
+            if slab.is_null() {
+                return ptr::null_mut();
+            }
+
+            self.push_partial(slab);
+        }
+
+        let slab = self.partial;
+        let object = (*slab).free;
+
+        (*slab).free = (*object).next;
+        (*slab).in_use += 1;
+
+        if (*slab).free.is_null() {
+            self.remove_partial(slab);
+        }
+
+        object as *mut u8
+    }
+
+    unsafe fn dealloc<S: SlabSource>(&mut self, ptr: *mut u8, source: &S) {
+        let slab_size = slab_size(self.class);
+        let slab = (ptr as usize & !(slab_size - 1)) as *mut Slab;
+        let object = ptr as *mut FreeObject;
+
+        let was_full = (*slab).free.is_null();
+
+        (*object).next = (*slab).free;
+        (*slab).free = object;
+        (*slab).in_use -= 1;
+
+        if was_full {
+            self.push_partial(slab);
+        }
+
+        // Keep one slab around so that an alloc/free pair does not bounce a slab in and out
+        if (*slab).in_use == 0 && !((*slab).prev.is_null() && (*slab).next.is_null()) {
+            self.remove_partial(slab);
+            source.free_slab(slab as *mut u8, slab_size);
+        }
+    }
+
+    /// Allocates a slab and puts all of its objects on its free list
+    unsafe fn new_slab<S: SlabSource>(&mut self, source: &S) -> *mut Slab {
+        let slab_size = slab_size(self.class);
+        let object_size = SIZE_CLASSES[self.class];
+
+        let slab = source.alloc_slab(slab_size) as *mut Slab;
+        if slab.is_null() {
+            return slab;
+        }
+
+        // Objects are aligned to their size, so the first one starts after the header rounded up
+        let first = cmp::max(mem::size_of::<Slab>(), object_size);
+        let first = (first + object_size - 1) & !(object_size - 1);
+
+        let mut free = ptr::null_mut();
+
+        for offset in (first..slab_size).step_by(object_size).rev() {
+            let object = (slab as usize + offset) as *mut FreeObject;
+            (*object).next = free;
+            free = object;
+        }
+
+        ptr::write(slab, Slab { prev: ptr::null_mut(), next: ptr::null_mut(), free, in_use: 0 });
+        slab
+    }
+
+    unsafe fn push_partial(&mut self, slab: *mut Slab) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.partial;
+
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+
+        self.partial = slab;
+    }
+
+    unsafe fn remove_partial(&mut self, slab: *mut Slab) {
+        if (*slab).prev.is_null() {
+            self.partial = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = ptr::null_mut();
+    }
+}
+
+/// One slab cache per size class, each with its own lock
+pub struct SlabAllocator {
+    caches: [Mutex<Cache>; CLASS_COUNT],
+}
+
+impl SlabAllocator {
+    pub const fn new() -> Self {
+        SlabAllocator {
+            caches: [
+                Mutex::new(Cache::new(0)),
+                Mutex::new(Cache::new(1)),
+                Mutex::new(Cache::new(2)),
+                Mutex::new(Cache::new(3)),
+                Mutex::new(Cache::new(4)),
+                Mutex::new(Cache::new(5)),
+                Mutex::new(Cache::new(6)),
+                Mutex::new(Cache::new(7)),
+            ],
+        }
+    }
+
+    /// Allocates an object of the given size class. Returns null if out of memory.
+    pub unsafe fn alloc<S: SlabSource>(&self, class: usize, source: &S) -> *mut u8 {
+        self.caches[class].lock().alloc(source)
+    }
+
+    /// Frees an object of the given size class.
+    pub unsafe fn dealloc<S: SlabSource>(&self, ptr: *mut u8, class: usize, source: &S) {
+        self.caches[class].lock().dealloc(ptr, source)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::alloc::{alloc, dealloc};
+    use std::cell::Cell;
+    use std::collections::BTreeSet;
+    use super::*;
+
+    /// Slabs from the system allocator, counting how many are live
+    struct TestSource {
+        live: Cell<usize>,
+    }
+
+    impl SlabSource for TestSource {
+        unsafe fn alloc_slab(&self, size: usize) -> *mut u8 {
+            self.live.set(self.live.get() + 1);
+            alloc(Layout::from_size_align(size, size).unwrap())
+        }
+
+        unsafe fn free_slab(&self, ptr: *mut u8, size: usize) {
+            self.live.set(self.live.get() - 1);
+            dealloc(ptr, Layout::from_size_align(size, size).unwrap())
+        }
+    }
+
+    #[test]
+    fn test_size_class() {
+        assert_eq!(size_class(&Layout::from_size_align(1, 1).unwrap()), Some(0));
+        assert_eq!(size_class(&Layout::from_size_align(17, 8).unwrap()), Some(1));
+        assert_eq!(size_class(&Layout::from_size_align(8, 256).unwrap()), Some(4));
+        assert_eq!(size_class(&Layout::from_size_align(2048, 8).unwrap()), Some(7));
+        assert_eq!(size_class(&Layout::from_size_align(2049, 8).unwrap()), None);
+    }
+
+    #[test]
+    fn test_slab_alloc() {
+        let source = TestSource { live: Cell::new(0) };
+        let slabs = SlabAllocator::new();
+
+        for class in 0..CLASS_COUNT {
+            let size = SIZE_CLASSES[class];
+            let live_before = source.live.get();
+            let mut objects = BTreeSet::new();
+
+            // Enough for several slabs
+            for _ in 0..(slab_size(class) / size) * 3 {
+                let object = unsafe { slabs.alloc(class, &source) };
+
+                assert!(!object.is_null());
+                assert_eq!(object as usize % size, 0, "Object is not aligned");
+                assert!(objects.insert(object as usize), "Object allocated twice");
+            }
+
+            for object in objects {
+                unsafe { slabs.dealloc(object as *mut u8, class, &source) };
+            }
+
+            // Each cache keeps one empty slab around
+            assert_eq!(source.live.get(), live_before + 1, "Empty slabs were not freed");
+        }
+    }
+
+    #[test]
+    fn test_slab_reuse() {
+        let source = TestSource { live: Cell::new(0) };
+        let slabs = SlabAllocator::new();
+
+        unsafe {
+            let first = slabs.alloc(2, &source);
+            slabs.dealloc(first, 2, &source);
+            assert_eq!(slabs.alloc(2, &source), first);
+        }
+
+        assert_eq!(source.live.get(), 1);
+    }
+}
@@ -1,10 +1,12 @@
 use crate::memory::paging::Page;
 
-/// A bump allocator for kernel stacks. There is no guard page.
+/// A bump allocator for kernel stacks. Every stack has a guard page below it, which is never
+/// mapped, so that overflowing the stack causes a page fault rather than silently corrupting
+/// whatever lies below it.
 pub struct StackAllocator {
     base: Page,
     capacity: usize,
-    /// Stack size in 4kib pages
+    /// Stack size in 4kib pages, not including the guard page
     stack_size_pages: usize,
     current: usize,
 }
@@ -21,16 +23,31 @@ impl StackAllocator {
         }
     }
 
+    /// Returns the bottom of a new stack, just above its guard page
     pub fn alloc(&mut self) -> Option<*const u8> {
         if self.current >= self.capacity {
             return None;
         }
 
-        let addr = self.base.start_address().unwrap() + (self.current * (self.stack_size_pages << 12));
+        let addr = self.base.start_address().unwrap() + self.current * self.slot_size() + 4096;
         self.current += 1;
 
         Some(addr as *const u8)
     }
+
+    /// Whether the address lies in the guard page of any stack this allocator can hand out
+    pub fn is_guard_page(&self, address: usize) -> bool {
+        let base = self.base.start_address().unwrap();
+
+        address >= base &&
+            address < base + self.capacity * self.slot_size() &&
+            (address - base) % self.slot_size() < 4096
+    }
+
+    /// The size of a stack along with its guard page
+    fn slot_size(&self) -> usize {
+        (self.stack_size_pages + 1) << 12
+    }
 }
 
 #[cfg(test)]
@@ -42,9 +59,9 @@ mod test {
     fn test_stack_alloc() {
         let mut allocator = StackAllocator::new(Page::containing_address(0, PageSize::Kib4), 10, 1);
 
-        assert_eq!(allocator.alloc(), Some(0 as *const u8));
+        assert_eq!(allocator.alloc(), Some((1 << 12) as *const u8));
         assert!(allocator.alloc() != allocator.alloc());
-        assert_eq!(allocator.alloc(), Some((3 << 12) as *const u8));
+        assert_eq!(allocator.alloc(), Some((7 << 12) as *const u8));
     }
 
     #[test]
@@ -57,4 +74,17 @@ mod test {
 
         assert_eq!(allocator.alloc(), None);
     }
+
+    #[test]
+    fn test_is_guard_page() {
+        let allocator = StackAllocator::new(Page::containing_address(0x10000, PageSize::Kib4), 2, 2);
+
+        assert!(allocator.is_guard_page(0x10000));
+        assert!(allocator.is_guard_page(0x10fff));
+        assert!(!allocator.is_guard_page(0x11000));
+        assert!(!allocator.is_guard_page(0x12fff));
+        assert!(allocator.is_guard_page(0x13008));
+        assert!(!allocator.is_guard_page(0x16000));
+        assert!(!allocator.is_guard_page(0xf000));
+    }
 }
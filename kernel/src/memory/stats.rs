@@ -0,0 +1,106 @@
+//! Memory usage statistics, gathered from the counters of the physical allocator, the heap and the
+//! paging module.
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::string::ToString;
+use super::physical_allocator::{self, PHYSICAL_ALLOCATOR};
+use super::{heap, paging};
+
+/// Bytes of RAM reported by the bootloader's memory map
+static TOTAL_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+pub(super) fn set_total_memory(bytes: usize) {
+    TOTAL_MEMORY.store(bytes, Ordering::Relaxed);
+}
+
+/// A snapshot of memory usage. All sizes are in bytes.
+#[derive(Debug, Copy, Clone)]
+pub struct MemoryStats {
+    /// RAM reported by the bootloader
+    pub total: usize,
+    /// RAM managed by the physical allocator, i.e excluding the kernel, modules and boot structures
+    pub usable: usize,
+    /// Number of free 4kib frames
+    pub free_frames: usize,
+    /// The largest block that the physical allocator can hand out
+    pub largest_free_physical: usize,
+    pub heap_used: usize,
+    pub heap_free: usize,
+    /// The largest block that the heap can hand out
+    pub largest_free_heap: usize,
+    /// Number of frames used by page tables allocated since boot
+    pub page_table_frames: usize,
+}
+
+/// Takes a snapshot of memory usage.
+///
+/// # Panicking
+///
+/// Panics if memory is not initialised.
+pub fn memory_stats() -> MemoryStats {
+    MemoryStats::new(PHYSICAL_ALLOCATOR.stats(), crate::HEAP.stats())
+}
+
+/// Like `memory_stats`, but returns `None` rather than waiting if an allocator is locked, e.g by
+/// code which the debugger stopped, or if memory is not initialised
+pub fn try_memory_stats() -> Option<MemoryStats> {
+    Some(MemoryStats::new(PHYSICAL_ALLOCATOR.try_stats()?, crate::HEAP.try_stats()?))
+}
+
+impl MemoryStats {
+    fn new(physical: physical_allocator::TreeStats, heap: heap::TreeStats) -> Self {
+        MemoryStats {
+            total: TOTAL_MEMORY.load(Ordering::Relaxed),
+            usable: physical.usable_bytes,
+            free_frames: physical.free_bytes() / 4096,
+            largest_free_physical: physical.largest_free_block(),
+            heap_used: heap.allocated_bytes,
+            heap_free: heap.free_bytes(),
+            largest_free_heap: heap.largest_free_block(),
+            page_table_frames: paging::page_table_frames(),
+        }
+    }
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} KiB total, {} KiB usable, {} KiB free ({} frames, largest block {} KiB)",
+            self.total / 1024,
+            self.usable / 1024,
+            self.free_frames * 4,
+            self.free_frames,
+            self.largest_free_physical / 1024,
+        )?;
+        writeln!(
+            f,
+            "heap {} KiB used, {} KiB free (largest block {} KiB), {} page table frames",
+            self.heap_used / 1024,
+            self.heap_free / 1024,
+            self.largest_free_heap / 1024,
+            self.page_table_frames,
+        )
+    }
+}
+
+/// Logs a snapshot of memory usage, followed by the counters of every physical allocator tree at
+/// debug level.
+pub fn print_memory_stats() {
+    // Each line of the snapshot is logged on its own, so that it gets a prefix
+    for line in memory_stats().to_string().lines() {
+        info!("mem: {}", line);
+    }
+
+    #[allow(unused_variables)] // For when log_level != debug | trace
+    PHYSICAL_ALLOCATOR.for_each_tree_stats(|gib, tree| {
+        debug!(
+            "mem: GiB {}: {} KiB usable, {} KiB free, allocations by order {:?}",
+            gib,
+            tree.usable_bytes / 1024,
+            tree.free_bytes() / 1024,
+            tree.allocations,
+        );
+    });
+}
@@ -0,0 +1,255 @@
+//! A round-robin, preemptive scheduler for kernel threads.
+//!
+//! Every PIT tick counts down the current thread's time slice. When it runs out, the next IRQ
+//! handler to finish (after sending EOI) switches to the thread at the front of the run queue.
+//!
+//! # Locking
+//!
+//! The scheduler lock is only ever taken with interrupts disabled, so it can never be held by a
+//! thread that has been preempted.
+
+pub mod thread;
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::PhysAddr;
+use crate::interrupts::{self, Irq};
+use crate::smp::percpu;
+use crate::memory::paging::PhysicalAddress;
+use crate::memory::address_space::AddressSpace;
+use crate::util;
+use self::thread::{Thread, ThreadId, ThreadState};
+
+/// Length of a time slice in PIT ticks (milliseconds)
+const TIME_SLICE_MS: usize = 10;
+
+static NEED_RESCHEDULE: AtomicBool = AtomicBool::new(false);
+static SLICE_REMAINING: AtomicUsize = AtomicUsize::new(TIME_SLICE_MS);
+
+lazy_static! {
+    static ref SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+}
+
+extern "C" {
+    /// Defined in `asm/context_switch.asm`
+    fn switch_context(old_stack_pointer: *mut usize, new_stack_pointer: usize);
+}
+
+struct Scheduler {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    /// Runnable threads which are not currently running
+    run_queue: VecDeque<ThreadId>,
+    current: Option<ThreadId>,
+    /// The page map of threads which do not have their own
+    kernel_page_map: PhysicalAddress,
+}
+
+impl Scheduler {
+    fn new() -> Self {
+        Scheduler {
+            threads: BTreeMap::new(),
+            run_queue: VecDeque::new(),
+            current: None,
+            kernel_page_map: PhysicalAddress(0),
+        }
+    }
+
+    fn current(&self) -> &Thread {
+        let id = self.current.expect("Scheduler not initialized!");
+        &self.threads[&id]
+    }
+
+    fn current_mut(&mut self) -> &mut Thread {
+        let id = self.current.expect("Scheduler not initialized!");
+        self.threads.get_mut(&id).unwrap()
+    }
+
+    /// Moves the current thread to the back of the run queue (unless it is dead) and makes the
+    /// thread at the front current. Returns the location to save the current stack pointer to and
+    /// the stack pointer to switch to, or `None` if there is nothing else to run.
+    ///
+    /// Never allocates, as the run queue never grows past the length it had before this call.
+    fn switch_next(&mut self) -> Option<(*mut usize, usize)> {
+        let current_id = self.current?;
+        let next_id = self.run_queue.pop_front()?;
+
+        let current = self.threads.get_mut(&current_id).unwrap();
+        if current.state == ThreadState::Runnable {
+            self.run_queue.push_back(current_id);
+        }
+
+        let old_stack_pointer = &mut current.stack_pointer as *mut usize;
+
+        let next = &self.threads[&next_id];
+        let new_stack_pointer = next.stack_pointer;
+
+        if let Some(top) = next.kernel_stack_top() {
+            percpu::current().set_kernel_stack(top);
+        }
+
+        let page_map = next.address_space.as_ref()
+            .map(AddressSpace::p4_frame)
+            .unwrap_or(self.kernel_page_map);
+        if page_map.0 as u64 != util::cr3() {
+            unsafe { util::cr3_write(PhysAddr::new(page_map.0 as u64)) };
+        }
+
+        self.current = Some(next_id);
+
+        Some((old_stack_pointer, new_stack_pointer))
+    }
+}
+
+/// Initializes the scheduler, turning the current flow of execution into the boot thread, and
+/// starts counting down time slices on the PIT.
+pub fn init() {
+    let boot_thread = Box::new(Thread::boot("kmain"));
+
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.current = Some(boot_thread.id());
+        scheduler.kernel_page_map = PhysicalAddress(util::cr3() as usize);
+        scheduler.threads.insert(boot_thread.id(), boot_thread);
+    });
+
+    interrupts::listen(Irq::Pit, tick);
+    info!("sched: initialized");
+}
+
+/// Spawns a kernel thread which will run `entry` and exit when it returns.
+pub fn spawn<F: FnOnce() + Send + 'static>(name: &'static str, entry: F) -> ThreadId {
+    let thread = Box::new(Thread::new(name, box entry, thread_trampoline));
+    let id = thread.id();
+
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.threads.insert(id, thread);
+        scheduler.run_queue.push_back(id);
+    });
+
+    debug!("sched: spawned thread {} ({})", id, name);
+    id
+}
+
+/// The name of the current thread, without blocking. Returns `None` if the scheduler is not
+/// initialised or is locked (e.g by the code an exception interrupted), or if called on an AP, which
+/// does not run threads.
+pub fn current_thread_name() -> Option<&'static str> {
+    let scheduler = SCHEDULER.try_lock()?;
+    let id = scheduler.current?;
+
+    if percpu::current().index != 0 {
+        return None;
+    }
+
+    scheduler.threads.get(&id).map(|thread| thread.name())
+}
+
+/// Gives up the rest of the current time slice.
+pub fn yield_now() {
+    SLICE_REMAINING.store(TIME_SLICE_MS, Ordering::SeqCst);
+    interrupts::without_interrupts(schedule);
+}
+
+/// Moves the current thread into a user address space, which it keeps running in until it exits.
+pub fn set_address_space(address_space: AddressSpace) {
+    let p4_frame = address_space.p4_frame();
+
+    let old = interrupts::without_interrupts(|| {
+        let old = SCHEDULER.lock().current_mut().address_space.replace(address_space);
+        unsafe { util::cr3_write(PhysAddr::new(p4_frame.0 as u64)) };
+        old
+    });
+
+    // Dropped here, outside of the scheduler lock and once it is no longer active
+    drop(old);
+}
+
+/// Exits the current thread. Its stack and address space are freed later by [reap].
+pub fn exit() -> ! {
+    interrupts::disable();
+
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let thread = scheduler.current_mut();
+        debug!("sched: thread {} ({}) exited", thread.id(), thread.name());
+        thread.state = ThreadState::Dead;
+    }
+
+    schedule();
+    unreachable!("Dead thread was scheduled again!");
+}
+
+/// Frees all threads which have exited. Must not be called from an interrupt handler, as it unmaps
+/// their stacks.
+pub fn reap() {
+    let dead: Vec<Box<Thread>> = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+
+        let ids: Vec<ThreadId> = scheduler.threads.values()
+            .filter(|thread| thread.state == ThreadState::Dead && Some(thread.id()) != current)
+            .map(|thread| thread.id())
+            .collect();
+
+        ids.iter()
+            .filter_map(|id| scheduler.threads.remove(id))
+            .collect()
+    });
+
+    // Dropped here, outside of the scheduler lock
+    drop(dead);
+}
+
+/// Runs the idle loop on the current thread, reaping dead threads whenever it is woken up.
+pub fn idle() -> ! {
+    loop {
+        reap();
+        unsafe { asm!("sti; hlt" :::: "volatile"); }
+    }
+}
+
+/// Switches to the next thread if the current time slice has run out. Called at the end of IRQ
+/// handlers once the interrupt has been acknowledged.
+pub fn preempt() {
+    if NEED_RESCHEDULE.swap(false, Ordering::SeqCst) {
+        schedule();
+    }
+}
+
+/// Counts down the current time slice. Registered as a PIT listener.
+fn tick() {
+    if SLICE_REMAINING.fetch_sub(1, Ordering::SeqCst) <= 1 {
+        SLICE_REMAINING.store(TIME_SLICE_MS, Ordering::SeqCst);
+        NEED_RESCHEDULE.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Switches to the next runnable thread. Must be called with interrupts disabled.
+fn schedule() {
+    let switch = match SCHEDULER.try_lock() {
+        Some(mut scheduler) => scheduler.switch_next(),
+        None => {
+            // Try again on the next IRQ
+            NEED_RESCHEDULE.store(true, Ordering::SeqCst);
+            return;
+        }
+    };
+
+    if let Some((old_stack_pointer, new_stack_pointer)) = switch {
+        unsafe { switch_context(old_stack_pointer, new_stack_pointer) };
+    }
+}
+
+/// The first code to run on a new thread's stack. `switch_context` returns into this.
+extern "C" fn thread_trampoline() -> ! {
+    let entry = SCHEDULER.lock().current_mut().entry.take().expect("Thread has no entry point!");
+
+    // We arrive here from `schedule`, which runs with interrupts disabled
+    interrupts::enable();
+    entry();
+    exit()
+}
@@ -0,0 +1,223 @@
+//! Kernel threads and their stacks
+
+use core::{fmt, ptr};
+use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::interrupts;
+use crate::memory::stack_allocator::StackAllocator;
+use crate::memory::paging::{PAGE_TABLES, Page, PageSize, EntryFlags, FreeMemory, InvalidateTlb};
+use crate::memory::address_space::AddressSpace;
+
+/// The base of the area that kernel thread stacks are allocated in.
+pub const THREAD_STACKS_START: usize = 0xffffffff00000000;
+/// Size of each kernel thread's stack in 4kib pages, not including its guard page
+const STACK_SIZE_PAGES: usize = 16;
+/// The maximum amount of stacks (and their guard pages) that fit in the 1GiB kernel thread stack
+/// area
+const MAX_STACKS: usize = (1 << 30) / ((STACK_SIZE_PAGES + 1) * 4096);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref STACK_ALLOCATOR: Mutex<ThreadStackAllocator> = Mutex::new(
+        ThreadStackAllocator::new()
+    );
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct ThreadId(pub u64);
+
+impl ThreadId {
+    fn next() -> ThreadId {
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::SeqCst))
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ThreadState {
+    /// The thread is running or waiting in the run queue
+    Runnable,
+    /// The thread has exited and is waiting to be reaped
+    Dead,
+}
+
+pub struct Thread {
+    pub(super) id: ThreadId,
+    pub(super) name: &'static str,
+    pub(super) state: ThreadState,
+    /// Saved stack pointer of the thread while it is not running. All other registers are saved
+    /// on the stack itself by `switch_context`.
+    pub(super) stack_pointer: usize,
+    /// The closure the thread runs. Taken when the thread first starts.
+    pub(super) entry: Option<Box<dyn FnOnce() + Send>>,
+    /// The user address space the thread runs in. `None` for threads which only use the kernel
+    /// page map.
+    pub(super) address_space: Option<AddressSpace>,
+    /// The stack of the thread. `None` for the boot thread, which runs on the boot stack.
+    stack: Option<Stack>,
+}
+
+impl Thread {
+    /// Creates a thread for the currently running flow of execution (i.e `kmain`)
+    pub(super) fn boot(name: &'static str) -> Thread {
+        Thread {
+            id: ThreadId::next(),
+            name,
+            state: ThreadState::Runnable,
+            stack_pointer: 0,
+            entry: None,
+            address_space: None,
+            stack: None,
+        }
+    }
+
+    /// Creates a new thread which will begin execution in `trampoline` when first switched to.
+    pub(super) fn new(
+        name: &'static str,
+        entry: Box<dyn FnOnce() + Send>,
+        trampoline: extern "C" fn() -> !,
+    ) -> Thread {
+        let stack = STACK_ALLOCATOR.lock().alloc().expect("Ran out of kernel thread stacks!");
+
+        // Set up the stack as `switch_context` expects to find it: return address followed by
+        // the callee-saved registers. The topmost slot is a fake return address for the
+        // trampoline so that the stack is correctly aligned on entry.
+        let mut stack_pointer = stack.top();
+        let mut push = |value: usize| unsafe {
+            stack_pointer -= 8;
+            ptr::write(stack_pointer as *mut usize, value);
+        };
+
+        push(0);
+        push(trampoline as usize);
+        for _ in 0..6 { // rbp, rbx, r12, r13, r14, r15
+            push(0);
+        }
+
+        Thread {
+            id: ThreadId::next(),
+            name,
+            state: ThreadState::Runnable,
+            stack_pointer,
+            entry: Some(entry),
+            address_space: None,
+            stack: Some(stack),
+        }
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The top of the thread's kernel stack, which the CPU switches to when the thread enters the
+    /// kernel from user mode. `None` for the boot thread, which never runs in user mode.
+    pub fn kernel_stack_top(&self) -> Option<usize> {
+        self.stack.as_ref().map(Stack::top)
+    }
+}
+
+/// Allocates a stack from the kernel thread stack area, for things which run outside of a thread
+/// (e.g an AP before it joins the scheduler)
+pub fn alloc_stack() -> Stack {
+    STACK_ALLOCATOR.lock().alloc().expect("Ran out of kernel thread stacks!")
+}
+
+/// Whether the address lies in the guard page below any kernel thread stack
+pub fn is_stack_guard_page(address: usize) -> bool {
+    // The layout of the stack area never changes, so this does not need to take the allocator's
+    // lock, which the faulting code may hold
+    stack_area().is_guard_page(address)
+}
+
+/// A mapped kernel thread stack. Unmaps its pages and returns itself to the allocator on drop.
+pub struct Stack {
+    bottom: usize,
+}
+
+impl Stack {
+    pub fn top(&self) -> usize {
+        self.bottom + STACK_SIZE_PAGES * 4096
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        // Page tables are locked with interrupts disabled by the heap, so they must never be held
+        // by a preempted thread
+        interrupts::without_interrupts(|| {
+            let mut page_tables = PAGE_TABLES.lock();
+
+            for page in 0..STACK_SIZE_PAGES {
+                unsafe {
+                    page_tables.unmap(
+                        Page::containing_address(self.bottom + page * 4096, PageSize::Kib4),
+                        FreeMemory::Free,
+                        InvalidateTlb::Invalidate,
+                    );
+                }
+            }
+        });
+
+        STACK_ALLOCATOR.lock().free.push(self.bottom);
+    }
+}
+
+/// Hands out kernel thread stacks from the kernel thread stack area. Stacks of exited threads are
+/// reused before bumping the underlying [StackAllocator].
+struct ThreadStackAllocator {
+    allocator: StackAllocator,
+    free: Vec<usize>,
+}
+
+impl ThreadStackAllocator {
+    fn new() -> Self {
+        ThreadStackAllocator {
+            allocator: stack_area(),
+            free: Vec::new(),
+        }
+    }
+
+    fn alloc(&mut self) -> Option<Stack> {
+        let bottom = match self.free.pop() {
+            Some(bottom) => bottom,
+            None => self.allocator.alloc()? as usize,
+        };
+
+        interrupts::without_interrupts(|| {
+            let mut page_tables = PAGE_TABLES.lock();
+
+            for page in 0..STACK_SIZE_PAGES {
+                unsafe {
+                    page_tables.map(
+                        Page::containing_address(bottom + page * 4096, PageSize::Kib4),
+                        EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+                        InvalidateTlb::Invalidate,
+                    );
+                }
+            }
+        });
+
+        Some(Stack { bottom })
+    }
+}
+
+/// A fresh allocator over the whole kernel thread stack area
+fn stack_area() -> StackAllocator {
+    StackAllocator::new(
+        Page::containing_address(THREAD_STACKS_START, PageSize::Kib4),
+        MAX_STACKS,
+        STACK_SIZE_PAGES,
+    )
+}
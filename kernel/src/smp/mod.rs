@@ -0,0 +1,188 @@
+//! Symmetric multiprocessing: bringing up the application processors (APs) listed in the ACPI MADT.
+//!
+//! Each AP is started with an INIT-SIPI-SIPI sequence into `asm/ap_trampoline.asm`, which takes it
+//! through protected mode into long mode on the kernel's page tables and calls [ap_main]. APs are
+//! started one at a time, since they share the trampoline.
+
+pub mod percpu;
+
+use core::{mem, ptr};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use acpi::{Acpi, ProcessorState};
+use crate::interrupts::{self, apic::{self, Apic}};
+use crate::memory::paging::{PAGE_TABLES, Page, PageSize, PhysicalAddress, EntryFlags, FreeMemory,
+                            InvalidateTlb};
+use crate::drivers::pit;
+use crate::sched::thread;
+use crate::{cpuid, syscall, util};
+use self::percpu::PerCpu;
+
+/// The maximum number of CPUs which can be brought online
+pub const MAX_CPUS: usize = 64;
+
+/// The physical address the AP trampoline is copied to. Must be page aligned and below 1MiB. This
+/// is never handed out by the physical allocator, as it lies below the kernel.
+const AP_TRAMPOLINE_ADDR: usize = 0x8000;
+/// How long to wait for an AP to start after each startup IPI
+const AP_STARTUP_TIMEOUT_MS: usize = 100;
+
+static CPUS_ONLINE: AtomicUsize = AtomicUsize::new(0);
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    // Defined in `asm/ap_trampoline.asm`
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_data: u8;
+}
+
+/// Layout of `ap_trampoline_data`
+#[repr(C)]
+struct TrampolineData {
+    cr3: u64,
+    stack_top: u64,
+    entry: u64,
+    argument: u64,
+}
+
+/// Sets up the per-CPU data block, GDT and TSS of the BSP
+pub fn init_bsp() {
+    let percpu = PerCpu::new(0, cpuid::initial_apic_id());
+    unsafe { percpu.install(); }
+
+    CPUS_ONLINE.store(1, Ordering::SeqCst);
+}
+
+/// Boots every usable AP in the ACPI MADT. Requires the APIC to have been set up.
+pub fn boot_aps(acpi: Option<&Acpi>) {
+    let (apic, acpi) = match (apic::APIC.wait(), acpi) {
+        (Some(apic), Some(acpi)) => (apic, acpi),
+        _ => {
+            info!("smp: no apic available, only the bsp is online");
+            return;
+        }
+    };
+
+    unsafe { install_trampoline() };
+
+    for processor in acpi.application_processors.iter() {
+        if let ProcessorState::Disabled = processor.state {
+            continue;
+        }
+
+        let index = CPUS_ONLINE.load(Ordering::SeqCst);
+        if index >= MAX_CPUS {
+            warn!("smp: more than {} cpus present, ignoring the rest", MAX_CPUS);
+            break;
+        }
+
+        if !boot_ap(apic, index, processor.local_apic_id) {
+            warn!("smp: cpu with apic id {} did not start", processor.local_apic_id);
+        }
+    }
+
+    unsafe { remove_trampoline() };
+
+    info!("smp: {} cpus online", CPUS_ONLINE.load(Ordering::SeqCst));
+}
+
+/// Identity maps the trampoline page and copies the trampoline there
+unsafe fn install_trampoline() {
+    let start = &ap_trampoline_start as *const u8;
+    let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+    assert!(len <= 4096, "AP trampoline must fit in one page!");
+
+    // Executable, since the AP runs from here until it reaches long mode
+    interrupts::without_interrupts(|| {
+        PAGE_TABLES.lock().map_to(
+            Page::containing_address(AP_TRAMPOLINE_ADDR, PageSize::Kib4),
+            PhysicalAddress(AP_TRAMPOLINE_ADDR),
+            EntryFlags::WRITABLE,
+            InvalidateTlb::Invalidate,
+        );
+    });
+
+    ptr::copy_nonoverlapping(start, AP_TRAMPOLINE_ADDR as *mut u8, len);
+}
+
+unsafe fn remove_trampoline() {
+    interrupts::without_interrupts(|| {
+        PAGE_TABLES.lock().unmap(
+            Page::containing_address(AP_TRAMPOLINE_ADDR, PageSize::Kib4),
+            FreeMemory::NoFree,
+            InvalidateTlb::Invalidate,
+        );
+    });
+}
+
+/// Starts one AP and waits for it to come online, returning whether it did
+fn boot_ap(apic: &Apic, index: usize, apic_id: u8) -> bool {
+    let percpu = PerCpu::new(index, apic_id);
+    let stack = thread::alloc_stack();
+
+    let cr3 = util::cr3();
+    assert!(cr3 < (1 << 32), "P4 table must be below 4GiB for the AP trampoline to load it!");
+
+    let data_offset = unsafe {
+        &ap_trampoline_data as *const u8 as usize - &ap_trampoline_start as *const u8 as usize
+    };
+
+    unsafe {
+        ptr::write_volatile(
+            (AP_TRAMPOLINE_ADDR + data_offset) as *mut TrampolineData,
+            TrampolineData {
+                cr3,
+                stack_top: stack.top() as u64,
+                entry: ap_main as usize as u64,
+                argument: percpu as *const PerCpu as u64,
+            },
+        );
+    }
+
+    AP_STARTED.store(false, Ordering::SeqCst);
+
+    apic.local.send_init_ipi(apic_id);
+    pit::sleep(10);
+
+    // The second startup IPI is only sent if the first did not work
+    for _ in 0..2 {
+        apic.local.send_startup_ipi(apic_id, (AP_TRAMPOLINE_ADDR >> 12) as u8);
+
+        let timeout = pit::time_ms() + AP_STARTUP_TIMEOUT_MS;
+        while pit::time_ms() < timeout {
+            if AP_STARTED.load(Ordering::SeqCst) {
+                // The AP runs on this stack forever
+                mem::forget(stack);
+                return true;
+            }
+
+            unsafe { asm!("pause" :::: "volatile"); }
+        }
+    }
+
+    // Put the AP back into its wait for a startup IPI, so that it cannot start late and use the
+    // data block and stacks once they are freed
+    apic.local.send_init_ipi(apic_id);
+
+    unsafe { PerCpu::free(percpu); }
+    false
+}
+
+/// The entry point of APs, called by the trampoline once in long mode
+extern "C" fn ap_main(percpu: &'static PerCpu) -> ! {
+    unsafe { percpu.install(); }
+    interrupts::load_idt();
+    syscall::init();
+
+    if let Some(apic) = apic::APIC.wait() {
+        unsafe { apic.local.enable(); }
+    }
+
+    CPUS_ONLINE.fetch_add(1, Ordering::SeqCst);
+    AP_STARTED.store(true, Ordering::SeqCst);
+
+    // IRQs are only routed to the BSP and the scheduler only runs there for now, so APs just idle
+    loop {
+        unsafe { asm!("sti; hlt" :::: "volatile"); }
+    }
+}
@@ -0,0 +1,119 @@
+//! Per-CPU data blocks. Each CPU's GS base points at its own block while it runs the kernel.
+
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::boxed::Box;
+use x86_64::VirtAddr;
+use x86_64::structures::tss::TaskStateSegment;
+use crate::gdt::{self, Gdt};
+use crate::{memory, util};
+
+const IA32_GS_BASE_MSR: u32 = 0xC0000101;
+/// The GS base which `swapgs` exchanges with the current one
+const IA32_KERNEL_GS_BASE_MSR: u32 = 0xC0000102;
+
+/// Per-CPU data. The GS base points at the current CPU's block while in the kernel. Every entry
+/// from user mode (`syscall`, interrupts and exceptions) runs `swapgs` before touching GS, and
+/// every return to user mode runs it again, so that the pointer sits in IA32_KERNEL_GS_BASE
+/// meanwhile and user code can neither read nor replace it.
+#[repr(C)]
+pub struct PerCpu {
+    /// Pointer to this block. Must be the first field so that the block can be found with `gs:0`.
+    self_pointer: *const PerCpu,
+    /// The kernel stack `syscall` switches to. Must be at `gs:8` for `asm/syscall.asm`.
+    syscall_stack: AtomicUsize,
+    /// Where `syscall` saves the user stack pointer. Must be at `gs:16` for `asm/syscall.asm`.
+    syscall_user_stack: AtomicUsize,
+    /// Index of the CPU in the order it was brought online. The BSP is always 0.
+    pub index: usize,
+    pub apic_id: u8,
+    tss: *mut TaskStateSegment,
+    pub gdt: &'static Gdt,
+}
+
+// Only the TSS is mutated after creation, and only by the CPU the block belongs to
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    /// Allocates the data block for a CPU, along with its own GDT, TSS and IST stacks. These live
+    /// forever once the CPU is online, and are freed with [PerCpu::free] if it fails to start.
+    pub fn new(index: usize, apic_id: u8) -> &'static PerCpu {
+        let tss: *mut TaskStateSegment = Box::leak(box gdt::new_tss());
+        let gdt: &'static Gdt = Box::leak(box Gdt::new(unsafe { &*tss }));
+
+        let percpu = Box::leak(box PerCpu {
+            self_pointer: ptr::null(),
+            syscall_stack: AtomicUsize::new(0),
+            syscall_user_stack: AtomicUsize::new(0),
+            index,
+            apic_id,
+            tss,
+            gdt,
+        });
+
+        percpu.self_pointer = percpu as *const PerCpu;
+        percpu
+    }
+
+    /// Frees the data block of a CPU which never came online, along with its GDT, TSS and IST
+    /// stacks.
+    ///
+    /// # Unsafety
+    ///
+    /// Unsafe as the block must not have been installed on any CPU, and must not be used
+    /// afterwards.
+    pub unsafe fn free(percpu: &'static PerCpu) {
+        let percpu = Box::from_raw(percpu as *const PerCpu as *mut PerCpu);
+        let tss = Box::from_raw(percpu.tss);
+
+        // Packed struct; cannot safely borrow fields
+        let ist = tss.interrupt_stack_table;
+        let mut stacks = [0; 7];
+        for (stack, top) in stacks.iter_mut().zip(ist.iter()) {
+            *stack = top.as_u64() as usize;
+        }
+
+        memory::free_ist_stacks(&stacks);
+        drop(Box::from_raw(percpu.gdt as *const Gdt as *mut Gdt));
+    }
+
+    /// Loads this CPU's GDT and TSS and points GS at this block. User mode starts with a GS base of
+    /// 0.
+    ///
+    /// # Unsafety
+    ///
+    /// Unsafe as it must be called on the CPU that this block belongs to.
+    pub unsafe fn install(&'static self) {
+        // Loading the GDT reloads GS, clearing its base, so the base must be set afterwards
+        self.gdt.load();
+        util::wrmsr(IA32_GS_BASE_MSR, self as *const PerCpu as u64);
+        util::wrmsr(IA32_KERNEL_GS_BASE_MSR, 0);
+    }
+
+    /// The top of one of this CPU's IST stacks, by its index in the IST (see `gdt`)
+    pub fn ist_top(&self, index: u16) -> usize {
+        // Packed struct; cannot safely borrow fields
+        let ist = unsafe { (*self.tss).interrupt_stack_table };
+        ist[index as usize].as_u64() as usize
+    }
+
+    /// Sets the stack that the CPU switches to when entering the kernel from user mode, whether
+    /// through an interrupt or `syscall`. Must be called on the CPU that this block belongs to.
+    pub fn set_kernel_stack(&self, top: usize) {
+        unsafe {
+            // Packed struct; cannot safely borrow fields
+            (*self.tss).privilege_stack_table[0] = VirtAddr::new(top as u64);
+        }
+
+        self.syscall_stack.store(top, Ordering::SeqCst);
+    }
+}
+
+/// Returns the data block of the current CPU. Must only be called once it has been installed.
+pub fn current() -> &'static PerCpu {
+    let block: *const PerCpu;
+    unsafe {
+        asm!("mov %gs:0, $0" : "=r" (block));
+        &*block
+    }
+}
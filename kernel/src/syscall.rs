@@ -0,0 +1,131 @@
+//! System calls from ring 3, made with the `syscall` instruction. See `asm/syscall.asm` for the
+//! calling convention.
+
+use core::{slice, str};
+use crate::memory::paging::{PAGE_TABLES, Page, PageSize, EntryFlags};
+use crate::drivers::pit;
+use crate::smp::percpu;
+use crate::{interrupts, sched, util};
+
+const IA32_STAR_MSR: u32 = 0xC0000081;
+const IA32_LSTAR_MSR: u32 = 0xC0000082;
+const IA32_FMASK_MSR: u32 = 0xC0000084;
+const EFER_SYSCALL_ENABLE: u64 = 1;
+
+/// RFLAGS bits cleared on `syscall`: trap, interrupt enable and direction
+const SYSCALL_RFLAGS_MASK: u64 = (1 << 8) | (1 << 9) | (1 << 10);
+
+/// The first address above the lower half, where user space ends
+pub const USER_SPACE_END: usize = 0x0000_8000_0000_0000;
+
+extern "C" {
+    /// Defined in `asm/syscall.asm`
+    fn syscall_entry();
+}
+
+type SyscallHandler = fn(u64, u64, u64, u64, u64) -> Result<u64, SyscallError>;
+
+/// The system call table, indexed by system call number
+static SYSCALLS: [SyscallHandler; 4] = [
+    sys_write, // 0
+    sys_exit,  // 1
+    sys_yield, // 2
+    sys_sleep, // 3
+];
+
+/// Errors returned to user mode. Encoded as the negated discriminant in `rax`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u64)]
+enum SyscallError {
+    InvalidSyscall = 1,
+    InvalidPointer = 2,
+    InvalidUtf8 = 3,
+}
+
+/// Enables `syscall`/`sysret` on the current CPU. Must be called after the CPU's GDT is loaded.
+pub fn init() {
+    let star = percpu::current().gdt.syscall_star();
+
+    unsafe {
+        util::wrmsr(util::IA32_EFER_MSR, util::rdmsr(util::IA32_EFER_MSR) | EFER_SYSCALL_ENABLE);
+        util::wrmsr(IA32_STAR_MSR, star);
+        util::wrmsr(IA32_LSTAR_MSR, syscall_entry as usize as u64);
+        util::wrmsr(IA32_FMASK_MSR, SYSCALL_RFLAGS_MASK);
+    }
+}
+
+/// Called by `syscall_entry` on the current thread's kernel stack, with interrupts enabled
+#[no_mangle]
+pub extern "C" fn syscall_dispatch(number: u64, a0: u64, a1: u64, a2: u64, a3: u64, a4: u64) -> u64 {
+    let result = match SYSCALLS.get(number as usize) {
+        Some(handler) => handler(a0, a1, a2, a3, a4),
+        None => Err(SyscallError::InvalidSyscall),
+    };
+
+    match result {
+        Ok(value) => value,
+        Err(error) => {
+            trace!("syscall: {} failed with {:?}", number, error);
+            (-(error as i64)) as u64
+        },
+    }
+}
+
+/// Checks that the given range lies in user space and is mapped accessible to user mode, and
+/// returns it as a slice.
+fn user_slice(address: u64, len: u64) -> Result<&'static [u8], SyscallError> {
+    let (address, len) = (address as usize, len as usize);
+    let end = address.checked_add(len).ok_or(SyscallError::InvalidPointer)?;
+
+    if end > USER_SPACE_END {
+        return Err(SyscallError::InvalidPointer);
+    }
+
+    let accessible = interrupts::without_interrupts(|| {
+        let tables = PAGE_TABLES.lock();
+        let first = Page::containing_address(address, PageSize::Kib4);
+        let pages = util::round_up_divide(end as u64, 4096) as usize - first.number();
+
+        (0..pages).all(|i| match tables.walk_page_table(first + i) {
+            Some((entry, _)) => entry.flags().contains(
+                EntryFlags::PRESENT | EntryFlags::USER_ACCESSIBLE
+            ),
+            None => false,
+        })
+    });
+
+    if accessible {
+        Ok(unsafe { slice::from_raw_parts(address as *const u8, len) })
+    } else {
+        Err(SyscallError::InvalidPointer)
+    }
+}
+
+/// Writes a UTF-8 string to the terminal and serial port. Args: pointer, length.
+fn sys_write(address: u64, len: u64, _: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    let bytes = user_slice(address, len)?;
+    let string = str::from_utf8(bytes).map_err(|_| SyscallError::InvalidUtf8)?;
+
+    print!("{}", string);
+    serial_print!("{}", string);
+
+    Ok(len)
+}
+
+/// Exits the calling thread. Args: exit code.
+fn sys_exit(code: u64, _: u64, _: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    debug!("syscall: user thread exited with code {}", code as i64);
+    sched::exit()
+}
+
+/// Gives up the rest of the time slice.
+fn sys_yield(_: u64, _: u64, _: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    sched::yield_now();
+    Ok(0)
+}
+
+/// Sleeps for at least the given number of milliseconds. Args: milliseconds.
+fn sys_sleep(ms: u64, _: u64, _: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    pit::sleep(ms as usize);
+    Ok(0)
+}
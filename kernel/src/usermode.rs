@@ -0,0 +1,88 @@
+//! Entering ring 3. User threads are ordinary kernel threads which drop into user mode and come
+//! back into the kernel on their own kernel stack through system calls and interrupts.
+
+use crate::smp::percpu;
+use crate::interrupts;
+
+/// RFLAGS for user mode: interrupts enabled, plus the always-set reserved bit
+const USER_RFLAGS: u64 = 0x202;
+
+/// Drops the current thread into ring 3 at `entry` with the given stack. The current kernel stack
+/// is reused from the top whenever the thread enters the kernel again, so anything still on it is
+/// lost.
+///
+/// # Unsafety
+///
+/// Unsafe as the entry point and stack must be mapped accessible to user mode.
+pub unsafe fn enter(entry: usize, stack_top: usize) -> ! {
+    let gdt = percpu::current().gdt;
+    let code_selector = gdt.user_code_selector().0 as u64;
+    let data_selector = gdt.user_data_selector().0 as u64;
+
+    interrupts::disable();
+
+    // Build an interrupt stack frame and return into it, with the user's GS base (see
+    // `smp::percpu::PerCpu`)
+    asm!("pushq $0
+          pushq $1
+          pushq $2
+          pushq $3
+          pushq $4
+          swapgs
+          iretq"
+         :: "r" (data_selector), "r" (stack_top as u64), "r" (USER_RFLAGS), "r" (code_selector),
+            "r" (entry as u64)
+         : "memory" : "volatile");
+
+    unreachable!("Returned from user mode!");
+}
+
+/// A tiny built-in ring 3 program, which writes a message through a system call and exits. Only
+/// run with the `user_test` feature.
+#[cfg(feature = "user_test")]
+pub mod test_program {
+    use core::slice;
+    use crate::memory::paging::EntryFlags;
+    use crate::memory::address_space::AddressSpace;
+    use crate::sched;
+
+    /// Where the test program's code is loaded
+    const CODE_ADDR: usize = 0x400000;
+    /// The top of the test program's one page stack
+    const STACK_TOP: usize = 0x800000;
+
+    extern "C" {
+        // Defined in `asm/user_test.asm`
+        static user_test_start: u8;
+        static user_test_end: u8;
+    }
+
+    /// Spawns a thread running the test program
+    pub fn spawn() {
+        sched::spawn("user_test", run);
+    }
+
+    fn run() {
+        let code = unsafe {
+            let start = &user_test_start as *const u8;
+            let len = &user_test_end as *const u8 as usize - start as usize;
+            slice::from_raw_parts(start, len)
+        };
+
+        assert!(code.len() <= 4096, "User test program must fit in one page!");
+
+        let mut address_space = AddressSpace::new()
+            .expect("Error creating user test address space");
+        address_space.map_anonymous(CODE_ADDR, 4096, EntryFlags::empty())
+            .and_then(|_| address_space.write(CODE_ADDR, code))
+            .and_then(|_| address_space.map_anonymous(
+                STACK_TOP - 4096,
+                4096,
+                EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+            ))
+            .expect("Error mapping user test program");
+
+        sched::set_address_space(address_space);
+        unsafe { super::enter(CODE_ADDR, STACK_TOP) }
+    }
+}
@@ -1,5 +1,8 @@
 //! Various utilities
 
+/// The extended feature enable register, with the long mode, `syscall` and no-execute enable bits
+pub const IA32_EFER_MSR: u32 = 0xC0000080;
+
 /// A macro to implement [FromDiscriminator] on an enum with explicit discriminators.
 /// Doesn't support generics or comments, but does support attributes, etc
 macro_rules! from_discriminator {
@@ -93,4 +96,85 @@ pub fn cr3() -> u64 {
     }
 
     value
-}
\ No newline at end of file
+}
+
+/// The control register with the protected mode, paging and write protect enable bits
+pub fn cr0() -> u64 {
+    let value: u64;
+    unsafe {
+        asm!("mov %cr0, $0" : "=r" (value));
+    }
+
+    value
+}
+
+/// Sets CR0, see [cr0]
+pub unsafe fn cr0_write(value: u64) {
+    asm!("mov $0, %cr0" :: "r" (value) : "memory" : "volatile");
+}
+
+/// The address which caused the last page fault
+pub fn cr2() -> u64 {
+    let value: u64;
+    unsafe {
+        asm!("mov %cr2, $0" : "=r" (value));
+    }
+
+    value
+}
+
+/// The control register with the enable bits of extensions such as PAE and global pages
+pub fn cr4() -> u64 {
+    let value: u64;
+    unsafe {
+        asm!("mov %cr4, $0" : "=r" (value));
+    }
+
+    value
+}
+
+/// The debug status register, which tells what caused a debug exception
+pub fn dr6() -> u64 {
+    let value: u64;
+    unsafe {
+        asm!("mov %dr6, $0" : "=r" (value));
+    }
+
+    value
+}
+
+/// Sets DR6, see [dr6]. The processor never clears it itself.
+pub unsafe fn dr6_write(value: u64) {
+    asm!("mov $0, %dr6" :: "r" (value) :: "volatile");
+}
+
+/// Switches to the stack with the given top and calls `f(argument)` on it. The current stack is
+/// left as it is, never to be returned to.
+///
+/// # Unsafety
+///
+/// Unsafe as the stack must be mapped, 16 byte aligned and not in use.
+pub unsafe fn call_on_stack(top: usize, f: extern "C" fn(usize) -> !, argument: usize) -> ! {
+    asm!("mov $0, %rsp
+          xor %rbp, %rbp
+          call *$1"
+         :: "r" (top), "r" (f as usize), "{rdi}" (argument)
+         : "memory" : "volatile");
+
+    unreachable!("Returned from a function on another stack!");
+}
+
+/// Reads a model specific register
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let (high, low): (u32, u32);
+    asm!("rdmsr" : "={eax}" (low), "={edx}" (high) : "{ecx}" (msr) : "memory" : "volatile");
+
+    ((high as u64) << 32) | (low as u64)
+}
+
+/// Writes a model specific register
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    let low = value as u32;
+    let high = (value >> 32) as u32;
+    asm!("wrmsr" :: "{ecx}" (msr), "{eax}" (low), "{edx}" (high) : "memory" : "volatile");
+}